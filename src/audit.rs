@@ -0,0 +1,122 @@
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{AmsiResult, AmsiSession, ScanError, Verdict};
+use super::sha256::{sha256, to_hex};
+
+/// A record of a single scan, as written to an `AuditStore`.
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    timestamp: SystemTime,
+    content_name: String,
+    sha256: [u8; 32],
+    size: usize,
+    result_code: u32,
+    correlation_id: Option<u64>,
+    verdict: Verdict,
+}
+
+impl AuditRecord {
+    /// Creates the record of a scan that just completed.
+    pub(crate) fn new(content_name: &str, data: &[u8], result: &AmsiResult, verdict: Verdict) -> AuditRecord {
+        AuditRecord{
+            timestamp: SystemTime::now(),
+            content_name: content_name.to_owned(),
+            sha256: sha256(data),
+            size: data.len(),
+            result_code: result.get_code(),
+            correlation_id: result.correlation_id(),
+            verdict,
+        }
+    }
+
+    /// Returns when the scan completed.
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// Returns the content name the payload was scanned under.
+    pub fn content_name(&self) -> &str {
+        &self.content_name
+    }
+
+    /// Returns the SHA-256 digest of the payload.
+    pub fn sha256(&self) -> &[u8; 32] {
+        &self.sha256
+    }
+
+    /// Returns the size of the payload, in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the raw result code of the scan.
+    pub fn result_code(&self) -> u32 {
+        self.result_code
+    }
+
+    /// Returns the correlation ID of the scan, see `AmsiResult::correlation_id`.
+    pub fn correlation_id(&self) -> Option<u64> {
+        self.correlation_id
+    }
+
+    /// Returns the verdict of the context's policy for the scan.
+    pub fn verdict(&self) -> Verdict {
+        self.verdict
+    }
+}
+
+/// A destination for audit records, see `AmsiContext::set_audit_store`.
+pub trait AuditStore: Send + Sync {
+    /// Persists a record. The scan that produced the record fails if this returns an error.
+    fn record(&self, record: &AuditRecord) -> std::io::Result<()>;
+}
+
+/// An `AuditStore` that appends one tab-separated line per scan to a writer (e.g. a log file).
+///
+/// Each line holds the timestamp (seconds since the UNIX epoch), the SHA-256 digest in hex, the size, the result code,
+/// the verdict, the correlation ID (`-` if the scan has none) and the content name, in that order.
+#[derive(Debug)]
+pub struct LogAuditStore<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> LogAuditStore<W> {
+    /// Creates a store that writes to `writer`.
+    pub fn new(writer: W) -> LogAuditStore<W> {
+        LogAuditStore{
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<W: Write + Send> AuditStore for LogAuditStore<W> {
+    fn record(&self, record: &AuditRecord) -> std::io::Result<()> {
+        let timestamp = record.timestamp.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let correlation_id = record.correlation_id.map_or_else(|| "-".to_owned(), |id| id.to_string());
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writeln!(writer, "{}\t{}\t{}\t{:#x}\t{:?}\t{}\t{}", timestamp, to_hex(&record.sha256), record.size, record.result_code, record.verdict, correlation_id, record.content_name)?;
+        writer.flush()
+    }
+}
+
+impl<'a> AmsiSession<'a> {
+    /// Scans a buffer, and records the outcome in the audit store of the context.
+    ///
+    /// The scan only succeeds once the record was persisted, so every verdict the caller acts upon is accounted for.
+    /// If the context has no audit store, this is the same as `scan_buffer`.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **data** - payload that should be scanned.
+    pub fn scan_buffer_audited(&self, content_name: &str, data: &[u8]) -> Result<AmsiResult, ScanError> {
+        let result = self.scan_buffer(content_name, data)?;
+
+        if let Some(ref store) = self.ctx.audit_store {
+            store.record(&AuditRecord::new(content_name, data, &result, self.ctx.verdict(&result)))?;
+        }
+
+        Ok(result)
+    }
+}
@@ -0,0 +1,83 @@
+use super::{AmsiContext, ProviderInfo, WinError, providers};
+use super::sys::{FreeLibrary, GetProcAddress, LOAD_LIBRARY_SEARCH_SYSTEM32, LoadLibraryExW, amsi_dll};
+
+/// What `is_available` found out about AMSI on this machine.
+#[derive(Debug)]
+pub struct Availability {
+    load_error: Option<WinError>,
+    initialize_error: Option<WinError>,
+    providers: Vec<ProviderInfo>,
+}
+
+impl Availability {
+    /// Returns `true` if scans can be expected to reach a provider: `amsi.dll` loads, a context can be created and
+    /// at least one provider is registered.
+    pub fn is_usable(&self) -> bool {
+        self.load_error.is_none() && self.initialize_error.is_none() && !self.providers.is_empty()
+    }
+
+    /// Returns `true` if `amsi.dll` is present in the system directory and exports the AMSI functions.
+    pub fn is_dll_loadable(&self) -> bool {
+        self.load_error.is_none()
+    }
+
+    /// Returns why `amsi.dll` couldn't be loaded, `ERROR_MOD_NOT_FOUND` on Windows versions without AMSI.
+    pub fn load_error(&self) -> Option<&WinError> {
+        self.load_error.as_ref()
+    }
+
+    /// Returns why `AmsiInitialize` failed. Also set when `amsi.dll` couldn't be loaded, since AMSI wasn't
+    /// initialized then.
+    pub fn initialize_error(&self) -> Option<&WinError> {
+        self.initialize_error.as_ref()
+    }
+
+    /// Returns the registered antimalware providers, see `providers`. Empty if none are registered or they couldn't
+    /// be listed.
+    pub fn providers(&self) -> &[ProviderInfo] {
+        &self.providers
+    }
+}
+
+/// Loads `amsi.dll` from the system directory and checks that it exports `AmsiScanBuffer`.
+fn probe_dll() -> Result<(), WinError> {
+    let file_name = amsi_dll();
+    unsafe {
+        let module = LoadLibraryExW(file_name.as_ptr(), std::ptr::null(), LOAD_LIBRARY_SEARCH_SYSTEM32);
+        if module.is_null() {
+            return Err(WinError::new().during("LoadLibraryExW"));
+        }
+        let proc_addr = GetProcAddress(module, b"AmsiScanBuffer\0".as_ptr());
+        let result = if proc_addr.is_null() {
+            Err(WinError::new().during("GetProcAddress"))
+        } else {
+            Ok(())
+        };
+        FreeLibrary(module);
+        result
+    }
+}
+
+/// Checks whether AMSI can be used on this machine.
+///
+/// This checks, in order, that `amsi.dll` can be loaded from the system directory, that `AmsiInitialize` succeeds
+/// and that at least one antimalware provider is registered. Applications deployed to a mix of Windows versions can
+/// use it to decide whether to offer scanning at all, instead of failing on the first scan.
+///
+/// Without the `dynamic` feature, a process on a Windows version without AMSI fails to start before this could be
+/// called, so the check is only meaningful for the DLL together with `dynamic`.
+///
+/// Nothing is cached, every call probes again.
+pub fn is_available() -> Availability {
+    let load_error = probe_dll().err();
+    let initialize_error = match load_error {
+        Some(ref err) => Some(WinError::from_hresult(err.hresult()).during("AmsiInitialize")),
+        None => AmsiContext::new("amsi-availability-probe").err(),
+    };
+
+    Availability{
+        load_error,
+        initialize_error,
+        providers: providers().unwrap_or_default(),
+    }
+}
@@ -0,0 +1,87 @@
+//! Scanning many payloads at once.
+//!
+//! Scans spend most of their time waiting for the antimalware service, so a batch of payloads is scanned much faster
+//! by several threads than one after the other.
+//!
+//! ```no_run
+//! extern crate amsi;
+//!
+//! let ctx = amsi::AmsiContext::new("mailscanner-1.0.0").unwrap();
+//! let attachments = vec![("invoice.pdf", vec![0u8; 1024]), ("notes.txt", b"hello".to_vec())];
+//! for (name, result) in amsi::batch::scan_all(&ctx, attachments).unwrap() {
+//!     println!("{}: {:?}", name, result);
+//! }
+//! ```
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{AmsiContext, AmsiResult, CancellationToken, IntoContentName, ScanError, WinError};
+
+/// The result of every payload of a batch, in the order of the payloads.
+pub type BatchResults<N> = Vec<(N, Result<AmsiResult, ScanError>)>;
+
+/// Scans a batch of payloads with as many threads as the machine can run in parallel, see `scan_all_with`.
+pub fn scan_all<N, D, I>(ctx: &AmsiContext, items: I) -> Result<BatchResults<N>, WinError>
+    where I: IntoIterator<Item = (N, D)>, N: IntoContentName + Sync, D: AsRef<[u8]> + Sync
+{
+    let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    scan_all_with(ctx, items, threads)
+}
+
+/// Scans a batch of payloads with up to `threads` threads, each of which scans in a session of its own.
+///
+/// Payloads are handed to the threads one at a time, so a few large payloads don't hold up the rest of the batch.
+/// Since every thread has its own session, the provider may correlate payloads that were scanned by the same thread,
+/// but not across threads.
+///
+/// Fails if the sessions can't be opened, in which case nothing is scanned. Otherwise the results of all payloads are
+/// returned along with their names, in the order of `items`.
+///
+/// ## Parameters
+/// * **ctx** - context to scan with.
+/// * **items** - pairs of content name and payload.
+/// * **threads** - maximum amount of threads, `0` scans on the calling thread only.
+pub fn scan_all_with<N, D, I>(ctx: &AmsiContext, items: I, threads: usize) -> Result<BatchResults<N>, WinError>
+    where I: IntoIterator<Item = (N, D)>, N: IntoContentName + Sync, D: AsRef<[u8]> + Sync
+{
+    scan_all_cancellable(ctx, items, threads, &CancellationToken::new())
+}
+
+/// Scans a batch of payloads like `scan_all_with`, until `token` is cancelled.
+///
+/// Payloads that weren't handed to the provider yet when the token is cancelled fail with `ScanError::Cancelled`.
+pub fn scan_all_cancellable<N, D, I>(ctx: &AmsiContext, items: I, threads: usize, token: &CancellationToken) -> Result<BatchResults<N>, WinError>
+    where I: IntoIterator<Item = (N, D)>, N: IntoContentName + Sync, D: AsRef<[u8]> + Sync
+{
+    let items: Vec<(N, D)> = items.into_iter().collect();
+    let threads = std::cmp::max(std::cmp::min(threads, items.len()), 1);
+    let mut sessions = (0..threads).map(|_| ctx.create_session()).collect::<Result<Vec<_>, WinError>>()?;
+    for session in &mut sessions {
+        session.set_cancellation_token(token.clone());
+    }
+
+    let next = AtomicUsize::new(0);
+    let scan = |session: &super::AmsiSession| {
+        let mut results = Vec::new();
+        loop {
+            let index = next.fetch_add(1, Ordering::Relaxed);
+            let (name, data) = match items.get(index) {
+                Some((name, data)) => (name, data),
+                None => return results,
+            };
+            results.push((index, session.scan_buffer_in(name, data.as_ref())));
+        }
+    };
+
+    let mut results: Vec<(usize, Result<AmsiResult, ScanError>)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = sessions[1..].iter().map(|session| scope.spawn(move || scan(session))).collect();
+        let mut results = scan(&sessions[0]);
+        for worker in workers {
+            results.extend(worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)));
+        }
+        results
+    });
+    results.sort_by_key(|&(index, _)| index);
+
+    Ok(items.into_iter().zip(results).map(|((name, _), (_, result))| (name, result)).collect())
+}
@@ -0,0 +1,101 @@
+use super::{AmsiContext, AppName, E_INVALIDARG, Guid, RetryPolicy, WinError};
+
+/// The default of `AmsiContextBuilder::chunk_size`, in bytes.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Creates an `AmsiContext` with non-default settings. Created by `AmsiContext::builder`.
+///
+/// ```no_run
+/// extern crate amsi;
+///
+/// let ctx = amsi::AmsiContext::builder()
+///     .app_name("emailscanner-1.0.0")
+///     .max_payload_size(32 * 1024 * 1024)
+///     .default_session(true)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct AmsiContextBuilder {
+    app_name: Option<AppName>,
+    chunk_size: usize,
+    max_payload_size: Option<usize>,
+    retry_policy: RetryPolicy,
+    default_session: bool,
+    recovery: bool,
+}
+
+impl AmsiContextBuilder {
+    pub(crate) fn new() -> AmsiContextBuilder {
+        AmsiContextBuilder{
+            app_name: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_payload_size: None,
+            retry_policy: RetryPolicy::none(),
+            default_session: false,
+            recovery: false,
+        }
+    }
+
+    /// Sets the name and version of the application, as a string or an `AppName`, reported to providers. Either this
+    /// or `app_guid` is required.
+    pub fn app_name<A: Into<AppName>>(&mut self, app_name: A) -> &mut Self {
+        self.app_name = Some(app_name.into());
+        self
+    }
+
+    /// Identifies the application by a GUID instead of a name, passed to AMSI in registry format. Replaces the name
+    /// set with `app_name`, and the other way around.
+    pub fn app_guid(&mut self, app_guid: &Guid) -> &mut Self {
+        self.app_name = Some(AppName::new(&app_guid.to_string()));
+        self
+    }
+
+    /// Sets the chunk size that chunked scans should use, see `AmsiContext::chunk_size`. Defaults to
+    /// `DEFAULT_CHUNK_SIZE`.
+    pub fn chunk_size(&mut self, chunk_size: usize) -> &mut Self {
+        self.chunk_size = std::cmp::max(chunk_size, 1);
+        self
+    }
+
+    /// Rejects payloads larger than `max_payload_size` bytes with an `InvalidInput` I/O error, without scanning them.
+    /// By default, every payload AMSI accepts is scanned (up to 4 GiB).
+    pub fn max_payload_size(&mut self, max_payload_size: usize) -> &mut Self {
+        self.max_payload_size = Some(max_payload_size);
+        self
+    }
+
+    /// Sets how scans are retried after transient failures. By default they aren't.
+    pub fn retry_policy(&mut self, retry_policy: RetryPolicy) -> &mut Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Opens a session along with the context, which `AmsiContext::scan_string` and `AmsiContext::scan_buffer` use
+    /// instead of no session. The provider then correlates all scans that go through the context itself.
+    pub fn default_session(&mut self, default_session: bool) -> &mut Self {
+        self.default_session = default_session;
+        self
+    }
+
+    /// Makes the context reinitialize itself after fatal failures, see `AmsiContext::set_recovery`. Off by default.
+    pub fn recovery(&mut self, recovery: bool) -> &mut Self {
+        self.recovery = recovery;
+        self
+    }
+
+    /// Initializes the context. Fails with `E_INVALIDARG` if no application name (or GUID) was set.
+    pub fn build(&self) -> Result<AmsiContext, WinError> {
+        let app_name = match self.app_name {
+            Some(ref app_name) => app_name.clone(),
+            None => return Err(WinError::from_hresult(E_INVALIDARG).during("AmsiContextBuilder::build")),
+        };
+
+        let mut ctx = AmsiContext::with_name(app_name, self.default_session)?;
+        ctx.chunk_size = self.chunk_size;
+        ctx.max_payload_size = self.max_payload_size;
+        ctx.retry_policy = self.retry_policy;
+        ctx.recovery = self.recovery;
+        Ok(ctx)
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::{AmsiContext, AmsiResult, AmsiSession, IntoContentName, ScanConfidence, ScanError, SkipReason, WinError};
+use super::sha256::Sha256;
+
+/// The default of `CachedSession::set_capacity`.
+const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+/// Distinguishes the keys of strings from those of buffers with the same bytes, since providers may treat them
+/// differently.
+#[derive(Clone, Copy)]
+enum ContentKind {
+    String = 1,
+    Buffer = 2,
+}
+
+#[derive(Debug)]
+struct Entries {
+    fingerprint: u64,
+    results: HashMap<[u8; 32], AmsiResult>,
+}
+
+/// A scan session that remembers the results of the payloads it scanned, created by
+/// `AmsiContext::create_cached_session`.
+///
+/// Payloads are identified by their SHA-256 hash: scanning a payload that was scanned before returns the previous
+/// result, without calling the provider. Only the content counts, not the content name. Results that were decided
+/// without calling the provider (e.g. by a filter) and failed scans aren't cached.
+///
+/// Entries are keyed by the `config_fingerprint` of the context as well, which is taken when the session is created
+/// and again by `refresh`. Call `refresh` once in a while (e.g. after `AmsiContext::definitions_changed_since`
+/// returns `true`), so that results are re-scanned with new definitions, or `invalidate` to forget all results.
+#[derive(Debug)]
+pub struct CachedSession<'a> {
+    session: AmsiSession<'a>,
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl<'a> CachedSession<'a> {
+    /// Returns the session that scans payloads which aren't cached.
+    pub fn session(&self) -> &AmsiSession<'a> {
+        &self.session
+    }
+
+    /// Sets how many results the cache holds, it is emptied when it is full. Defaults to 1024.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
+    /// Returns the number of cached results.
+    pub fn len(&self) -> usize {
+        self.lock().results.len()
+    }
+
+    /// Returns `true` if no results are cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets all cached results.
+    pub fn invalidate(&self) {
+        self.lock().results.clear();
+    }
+
+    /// Forgets the cached results of a payload, whether it was scanned as a string or as a buffer. Returns `true` if
+    /// there were any.
+    pub fn invalidate_payload(&self, data: &[u8]) -> bool {
+        let mut entries = self.lock();
+        let fingerprint = entries.fingerprint;
+        let string = entries.results.remove(&key(fingerprint, ContentKind::String, data)).is_some();
+        let buffer = entries.results.remove(&key(fingerprint, ContentKind::Buffer, data)).is_some();
+        string || buffer
+    }
+
+    /// Takes the `config_fingerprint` of the context again, forgetting all cached results if it changed.
+    ///
+    /// Returns `true` if the results were forgotten.
+    pub fn refresh(&self) -> bool {
+        let fingerprint = self.session.ctx.config_fingerprint();
+        let mut entries = self.lock();
+        if entries.fingerprint == fingerprint {
+            return false;
+        }
+        entries.fingerprint = fingerprint;
+        entries.results.clear();
+        true
+    }
+
+    /// Scans a string like `AmsiSession::scan_string`, unless the same string was scanned before.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID
+    /// * **data** - Content that should be scanned.
+    pub fn scan_string<N: IntoContentName>(&self, content_name: N, data: &str) -> Result<AmsiResult, ScanError> {
+        self.cached(ContentKind::String, data.as_bytes(), || self.session.scan_string(content_name, data))
+    }
+
+    /// Scans a buffer like `AmsiSession::scan_buffer`, unless the same buffer was scanned before.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **data** - payload that should be scanned.
+    pub fn scan_buffer<N: IntoContentName>(&self, content_name: N, data: &[u8]) -> Result<AmsiResult, ScanError> {
+        self.cached(ContentKind::Buffer, data, || self.session.scan_buffer(content_name, data))
+    }
+
+    /// Scans a buffer like `AmsiSession::scan_confident`, reporting a cached result as `SkipReason::CacheHit`.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **data** - payload that should be scanned.
+    pub fn scan_confident(&self, content_name: &str, data: &[u8]) -> Result<ScanConfidence, ScanError> {
+        let key = match self.lookup(ContentKind::Buffer, data) {
+            Ok(result) => return Ok(ScanConfidence::Skipped(SkipReason::CacheHit(result))),
+            Err(key) => key,
+        };
+
+        let confidence = self.session.scan_confident(content_name, data)?;
+        if let ScanConfidence::Scanned(result) = confidence {
+            self.store(key, result);
+        }
+        Ok(confidence)
+    }
+
+    fn cached<F>(&self, kind: ContentKind, data: &[u8], scan: F) -> Result<AmsiResult, ScanError>
+        where F: FnOnce() -> Result<AmsiResult, ScanError>
+    {
+        let key = match self.lookup(kind, data) {
+            Ok(result) => return Ok(result),
+            Err(key) => key,
+        };
+
+        // the lock isn't held while scanning, so that scans of other payloads don't wait for this one.
+        let result = scan()?;
+        self.store(key, result);
+        Ok(result)
+    }
+
+    /// Returns the cached result of a payload, or its key if there is none.
+    fn lookup(&self, kind: ContentKind, data: &[u8]) -> Result<AmsiResult, [u8; 32]> {
+        let entries = self.lock();
+        let key = key(entries.fingerprint, kind, data);
+        entries.results.get(&key).copied().ok_or(key)
+    }
+
+    /// Caches a result, unless it was decided without calling the provider.
+    fn store(&self, key: [u8; 32], result: AmsiResult) {
+        if result.correlation_id().is_some() {
+            let mut entries = self.lock();
+            if entries.results.len() >= self.capacity {
+                entries.results.clear();
+            }
+            entries.results.insert(key, result);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Hashes a payload along with what its result depends on.
+fn key(fingerprint: u64, kind: ContentKind, data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(&fingerprint.to_le_bytes());
+    hasher.update(&[kind as u8]);
+    hasher.update(data);
+    hasher.finish()
+}
+
+impl AmsiContext {
+    /// Creates a scan session that caches results by the hash of the scanned content, see `CachedSession`.
+    pub fn create_cached_session(&self) -> Result<CachedSession<'_>, WinError> {
+        Ok(CachedSession{
+            session: self.create_session()?,
+            capacity: DEFAULT_CACHE_CAPACITY,
+            entries: Mutex::new(Entries{
+                fingerprint: self.config_fingerprint(),
+                results: HashMap::new(),
+            }),
+        })
+    }
+}
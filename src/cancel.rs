@@ -0,0 +1,59 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::{AmsiSession, ScanError};
+
+/// Cancels scans that haven't been handed to the provider yet, e.g. when the upload they belong to was aborted.
+///
+/// Clones of a token share its state, so a token can be cancelled from another thread than the one that scans. Once
+/// cancelled, a token stays cancelled.
+///
+/// Cancellation is cooperative: a scan that the provider is working on runs to completion, but chunked scans (such as
+/// `AmsiSession::scan_reader`) stop before their next chunk, and later scans fail right away with
+/// `ScanError::Cancelled`.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Creates a token that isn't cancelled.
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Cancels the scans that use this token, or any of its clones.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    /// Returns `true` if the token was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Fails with `ScanError::Cancelled` if the token was cancelled.
+    pub(crate) fn check(&self) -> Result<(), ScanError> {
+        if self.is_cancelled() {
+            Err(ScanError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<'a> AmsiSession<'a> {
+    /// Makes the scans of this session fail with `ScanError::Cancelled` once `token` is cancelled.
+    ///
+    /// The token is checked before every call into the provider, including every chunk of a chunked scan.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation = Some(token);
+    }
+
+    pub(crate) fn check_cancelled(&self) -> Result<(), ScanError> {
+        match self.cancellation {
+            Some(ref token) => token.check(),
+            None => Ok(()),
+        }
+    }
+}
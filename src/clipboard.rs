@@ -0,0 +1,160 @@
+use std::os::raw::c_void;
+use std::path::PathBuf;
+
+use super::{AmsiResult, AmsiSession, ScanError, WinError};
+use super::file::from_wide;
+use super::sys::{BOOL, HANDLE};
+
+type UINT = u32;
+
+const CF_UNICODETEXT: UINT = 13;
+const CF_HDROP: UINT = 15;
+
+#[link(name="user32")]
+extern "system" {
+    fn OpenClipboard(new_owner: HANDLE) -> BOOL;
+    fn CloseClipboard() -> BOOL;
+    fn IsClipboardFormatAvailable(format: UINT) -> BOOL;
+    fn GetClipboardData(format: UINT) -> HANDLE;
+}
+
+#[link(name="kernel32")]
+extern "system" {
+    fn GlobalLock(mem: HANDLE) -> *mut c_void;
+    fn GlobalUnlock(mem: HANDLE) -> BOOL;
+    fn GlobalSize(mem: HANDLE) -> usize;
+}
+
+#[link(name="shell32")]
+extern "system" {
+    fn DragQueryFileW(drop: HANDLE, file: UINT, name: *mut u16, name_len: UINT) -> UINT;
+}
+
+/// The contents of the clipboard, copied out while it was open.
+enum ClipboardContent {
+    Text(String),
+    Files(Vec<PathBuf>),
+}
+
+/// The outcome of `AmsiSession::scan_clipboard`.
+#[derive(Debug)]
+pub enum ClipboardScan {
+    /// The clipboard held text, with the result of its scan.
+    Text(AmsiResult),
+    /// The clipboard held copied files, with the result of each file in the order they were copied.
+    Files(Vec<(PathBuf, Result<AmsiResult, ScanError>)>),
+}
+
+impl ClipboardScan {
+    /// Returns the most severe result, ignoring files that couldn't be scanned. `None` if no file could be scanned.
+    pub fn worst(&self) -> Option<AmsiResult> {
+        match *self {
+            ClipboardScan::Text(result) => Some(result),
+            ClipboardScan::Files(ref files) => files.iter()
+                .filter_map(|(_, result)| result.as_ref().ok().copied())
+                .reduce(AmsiResult::most_severe),
+        }
+    }
+}
+
+/// Keeps the clipboard open, closing it on drop.
+struct OpenedClipboard;
+
+impl OpenedClipboard {
+    fn open() -> Result<OpenedClipboard, WinError> {
+        if unsafe { OpenClipboard(std::ptr::null_mut()) } == 0 {
+            return Err(WinError::new());
+        }
+        Ok(OpenedClipboard)
+    }
+
+    unsafe fn text(&self) -> Option<String> {
+        let mem = GetClipboardData(CF_UNICODETEXT);
+        if mem.is_null() {
+            return None;
+        }
+        let ptr = GlobalLock(mem) as *const u16;
+        if ptr.is_null() {
+            return None;
+        }
+
+        let max_len = GlobalSize(mem) / 2;
+        let chars = std::slice::from_raw_parts(ptr, max_len);
+        let len = chars.iter().position(|&c| c == 0).unwrap_or(max_len);
+        let text = String::from_utf16_lossy(&chars[..len]);
+
+        GlobalUnlock(mem);
+        Some(text)
+    }
+
+    unsafe fn files(&self) -> Option<Vec<PathBuf>> {
+        let drop = GetClipboardData(CF_HDROP);
+        if drop.is_null() {
+            return None;
+        }
+
+        let count = DragQueryFileW(drop, !0, std::ptr::null_mut(), 0);
+        let files = (0..count)
+            .map(|idx| {
+                let len = DragQueryFileW(drop, idx, std::ptr::null_mut(), 0);
+                let mut name = vec![0u16; len as usize + 1];
+                let len = DragQueryFileW(drop, idx, name.as_mut_ptr(), name.len() as UINT);
+                PathBuf::from(from_wide(&name[..len as usize]))
+            })
+            .collect();
+        Some(files)
+    }
+}
+
+impl Drop for OpenedClipboard {
+    fn drop(&mut self) {
+        unsafe {
+            CloseClipboard();
+        }
+    }
+}
+
+fn read_clipboard() -> Result<Option<ClipboardContent>, WinError> {
+    let clipboard = OpenedClipboard::open()?;
+
+    unsafe {
+        if IsClipboardFormatAvailable(CF_HDROP) != 0 {
+            if let Some(files) = clipboard.files() {
+                return Ok(Some(ClipboardContent::Files(files)));
+            }
+        }
+        if IsClipboardFormatAvailable(CF_UNICODETEXT) != 0 {
+            if let Some(text) = clipboard.text() {
+                return Ok(Some(ClipboardContent::Text(text)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+impl<'a> AmsiSession<'a> {
+    /// Scans the current content of the clipboard.
+    ///
+    /// Files that were copied (`CF_HDROP`, e.g. from Explorer) are scanned like with `scan_file`, each under its path,
+    /// and their results are returned along with their paths. A file that can't be read or scanned only fails its own
+    /// entry. Otherwise, copied text (`CF_UNICODETEXT`) is scanned as a string. `None` is returned when the clipboard
+    /// holds neither.
+    ///
+    /// The clipboard is only kept open while its content is copied out, not during the scan.
+    pub fn scan_clipboard(&self) -> Result<Option<ClipboardScan>, ScanError> {
+        match read_clipboard()? {
+            Some(ClipboardContent::Text(text)) => Ok(Some(ClipboardScan::Text(self.scan_string("clipboard", &text)?))),
+            Some(ClipboardContent::Files(files)) => Ok(Some(self.scan_copied_files(files))),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn scan_copied_files(&self, files: Vec<PathBuf>) -> ClipboardScan {
+        ClipboardScan::Files(files.into_iter()
+            .map(|path| {
+                let result = self.scan_file(&path);
+                (path, result)
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// A source of time.
+///
+/// Everything in this crate that measures or waits for time does so through a `Clock`, which allows tests to replace
+/// the system clock with a `MockClock`. See `AmsiContext::set_clock`.
+pub trait Clock: Send + Sync {
+    /// Returns the current instant.
+    fn now(&self) -> Instant;
+
+    /// Blocks the current thread for the specified duration.
+    fn sleep(&self, dur: Duration) {
+        std::thread::sleep(dur);
+    }
+}
+
+/// The clock used by default, backed by `Instant::now()`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves forward when told to.
+///
+/// Sleeping on a `MockClock` doesn't block, it advances the clock by the requested duration instead.
+#[derive(Debug)]
+pub struct MockClock {
+    base: Instant,
+    offset: AtomicU64,
+}
+
+impl MockClock {
+    /// Creates a new `MockClock`, starting at the current instant.
+    pub fn new() -> MockClock {
+        MockClock{
+            base: Instant::now(),
+            offset: AtomicU64::new(0),
+        }
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, dur: Duration) {
+        self.offset.fetch_add(dur.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    /// Returns how much time has passed since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.offset.load(Ordering::SeqCst))
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.base + self.elapsed()
+    }
+
+    fn sleep(&self, dur: Duration) {
+        self.advance(dur);
+    }
+}
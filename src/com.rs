@@ -0,0 +1,350 @@
+use std::io::{Read, Seek};
+use std::marker::PhantomData;
+use std::os::raw::c_void;
+
+use super::{AmsiResult, ScanError, WinError, buffer_length, wow64};
+use super::stream::{self, AmsiStream, BufferSource, ReaderSource, ScanAttributes, Spilled};
+use super::sys::{AMSI_RESULT, DWORD, HRESULT, ULONG};
+
+/// A COM interface or class ID, laid out like the native `GUID`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+impl Guid {
+    /// Creates a GUID from its big-endian representation, e.g.
+    /// `Guid::from_u128(0x2781761e_28e0_4109_99fe_b9d127c57afe)` for `{2781761E-28E0-4109-99FE-B9D127C57AFE}`.
+    pub const fn from_u128(value: u128) -> Guid {
+        let bytes = value.to_be_bytes();
+        Guid{
+            data1: (value >> 96) as u32,
+            data2: (value >> 80) as u16,
+            data3: (value >> 64) as u16,
+            data4: [bytes[8], bytes[9], bytes[10], bytes[11], bytes[12], bytes[13], bytes[14], bytes[15]],
+        }
+    }
+}
+
+/// Formats the GUID in registry format, e.g. `{2781761E-28E0-4109-99FE-B9D127C57AFE}`.
+impl std::fmt::Display for Guid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let d = &self.data4;
+        write!(f, "{{{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}}}",
+            self.data1, self.data2, self.data3, d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7])
+    }
+}
+
+pub(crate) const S_OK: HRESULT = 0;
+pub(crate) const S_FALSE: HRESULT = 1;
+pub(crate) const E_NOTIMPL: HRESULT = 0x8000_4001;
+pub(crate) const E_NOINTERFACE: HRESULT = 0x8000_4002;
+pub(crate) const E_POINTER: HRESULT = 0x8000_4003;
+pub(crate) const E_FAIL: HRESULT = 0x8000_4005;
+pub(crate) const E_NOT_SUFFICIENT_BUFFER: HRESULT = 0x8007_007a;
+
+pub(crate) const IID_IUNKNOWN: Guid = Guid{ data1: 0x0000_0000, data2: 0x0000, data3: 0x0000, data4: [0xc0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x46] };
+pub(crate) const IID_IAMSISTREAM: Guid = Guid{ data1: 0x3e47_f2e5, data2: 0x81d4, data3: 0x4d3b, data4: [0x89, 0x7f, 0x54, 0x50, 0x96, 0x77, 0x03, 0x73] };
+const IID_IANTIMALWARE: Guid = Guid{ data1: 0x82d2_9c2e, data2: 0xf062, data3: 0x44e6, data4: [0xb5, 0xc9, 0x3d, 0x9a, 0x2f, 0x24, 0xa2, 0xdf] };
+const CLSID_ANTIMALWARE: Guid = Guid{ data1: 0xfdb0_0e52, data2: 0xa214, data3: 0x4aa1, data4: [0x8f, 0xba, 0x43, 0x57, 0xbb, 0x00, 0x72, 0xec] };
+
+const CLSCTX_INPROC_SERVER: DWORD = 0x1;
+const COINIT_MULTITHREADED: DWORD = 0x0;
+const COINIT_APARTMENTTHREADED: DWORD = 0x2;
+
+#[link(name="ole32")]
+extern "system" {
+    fn CoInitializeEx(reserved: *mut c_void, co_init: DWORD) -> HRESULT;
+    fn CoUninitialize();
+    fn CoCreateInstance(clsid: *const Guid, outer: *mut c_void, cls_context: DWORD, iid: *const Guid, object: *mut *mut c_void) -> HRESULT;
+    pub(crate) fn CoTaskMemAlloc(size: usize) -> *mut c_void;
+    pub(crate) fn CoTaskMemFree(ptr: *mut c_void);
+}
+
+#[repr(C)]
+pub(crate) struct IUnknownVtbl {
+    pub(crate) query_interface: unsafe extern "system" fn(this: *mut c_void, iid: *const Guid, object: *mut *mut c_void) -> HRESULT,
+    pub(crate) add_ref: unsafe extern "system" fn(this: *mut c_void) -> ULONG,
+    pub(crate) release: unsafe extern "system" fn(this: *mut c_void) -> ULONG,
+}
+
+#[repr(C)]
+pub(crate) struct IAntimalwareProviderVtbl {
+    pub(crate) unknown: IUnknownVtbl,
+    pub(crate) scan: unsafe extern "system" fn(this: *mut c_void, stream: *mut c_void, result: *mut AMSI_RESULT) -> HRESULT,
+    pub(crate) close_session: unsafe extern "system" fn(this: *mut c_void, session: u64),
+    pub(crate) display_name: unsafe extern "system" fn(this: *mut c_void, display_name: *mut *mut u16) -> HRESULT,
+}
+
+#[repr(C)]
+struct IAntimalwareVtbl {
+    unknown: IUnknownVtbl,
+    scan: unsafe extern "system" fn(this: *mut c_void, stream: *mut c_void, result: *mut AMSI_RESULT, provider: *mut *mut c_void) -> HRESULT,
+    close_session: unsafe extern "system" fn(this: *mut c_void, session: u64),
+}
+
+/// An owned reference to a COM object, released on drop.
+pub(crate) struct ComPtr<V> {
+    ptr: *mut c_void,
+    _vtbl: PhantomData<*const V>,
+}
+
+impl<V> ComPtr<V> {
+    /// Takes ownership of a reference. `ptr` must point to an object whose vtable starts like `V`.
+    pub(crate) unsafe fn from_raw(ptr: *mut c_void) -> Option<ComPtr<V>> {
+        if ptr.is_null() {
+            None
+        } else {
+            Some(ComPtr{
+                ptr,
+                _vtbl: PhantomData,
+            })
+        }
+    }
+
+    pub(crate) fn as_raw(&self) -> *mut c_void {
+        self.ptr
+    }
+
+    pub(crate) fn vtbl(&self) -> &V {
+        unsafe {
+            &**(self.ptr as *const *const V)
+        }
+    }
+}
+
+impl<V> Drop for ComPtr<V> {
+    fn drop(&mut self) {
+        unsafe {
+            let vtbl = &**(self.ptr as *const *const IUnknownVtbl);
+            (vtbl.release)(self.ptr);
+        }
+    }
+}
+
+/// Takes a string that was allocated with `CoTaskMemAlloc`, freeing it.
+pub(crate) unsafe fn take_co_task_string(ptr: *mut u16) -> String {
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    let s = String::from_utf16_lossy(std::slice::from_raw_parts(ptr, len));
+    CoTaskMemFree(ptr as *mut c_void);
+    s
+}
+
+/// Initializes COM for the current thread, and uninitializes it on drop.
+///
+/// `Antimalware` requires COM to be initialized on the threads that use it. Applications that already initialize COM
+/// themselves don't need this.
+#[derive(Debug)]
+pub struct ComApartment {
+    // COM is initialized per thread, so the guard has to be dropped on the thread that created it.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl ComApartment {
+    /// Joins the multithreaded apartment.
+    pub fn multithreaded() -> Result<ComApartment, WinError> {
+        Self::initialize(COINIT_MULTITHREADED)
+    }
+
+    /// Initializes a single-threaded apartment for the current thread.
+    pub fn single_threaded() -> Result<ComApartment, WinError> {
+        Self::initialize(COINIT_APARTMENTTHREADED)
+    }
+
+    fn initialize(co_init: DWORD) -> Result<ComApartment, WinError> {
+        // S_FALSE means COM was already initialized on this thread, which still has to be balanced.
+        match unsafe { CoInitializeEx(std::ptr::null_mut(), co_init) } {
+            S_OK | S_FALSE => Ok(ComApartment{
+                _not_send: PhantomData,
+            }),
+            hres => Err(WinError::from_hresult(hres).during("CoInitializeEx")),
+        }
+    }
+}
+
+impl Drop for ComApartment {
+    fn drop(&mut self) {
+        unsafe {
+            CoUninitialize();
+        }
+    }
+}
+
+/// The outcome of a scan through `Antimalware`.
+#[derive(Debug)]
+pub struct ComScan {
+    result: AmsiResult,
+    provider: Option<String>,
+}
+
+impl ComScan {
+    /// Returns the result of the scan.
+    pub fn result(&self) -> &AmsiResult {
+        &self.result
+    }
+
+    /// Returns the display name of the provider that returned the result, if AMSI reported one.
+    pub fn provider(&self) -> Option<&str> {
+        self.provider.as_deref()
+    }
+
+    /// Returns the result of the scan, discarding the provider.
+    pub fn into_result(self) -> AmsiResult {
+        self.result
+    }
+}
+
+/// A client of the `IAntimalware` COM interface, an alternative to `AmsiContext`.
+///
+/// `amsi.dll` implements the flat API (`AmsiScanBuffer` and friends) on top of `IAntimalware`. Using it directly
+/// reports which provider returned a verdict, and leaves the COM apartment and threading model to the caller: COM has
+/// to be initialized on every thread that uses an `Antimalware`, e.g. with `ComApartment`, and objects created in a
+/// single-threaded apartment must only be used on that thread.
+///
+/// The filter chain, rate limit and policy features of `AmsiContext` aren't available here.
+pub struct Antimalware {
+    antimalware: ComPtr<IAntimalwareVtbl>,
+    app_name: Vec<u16>,
+}
+
+impl std::fmt::Debug for Antimalware {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Antimalware")
+            .field("antimalware", &self.antimalware.as_raw())
+            .field("app_name", &String::from_utf16_lossy(&self.app_name[..self.app_name.len() - 1]))
+            .finish()
+    }
+}
+
+impl Antimalware {
+    /// Instantiates `IAntimalware` with `CoCreateInstance(CLSID_Antimalware)`.
+    ///
+    /// ## Parameters
+    /// * **app_name** - name, version or GUID of the application, reported to providers like with `AmsiContext::new`.
+    pub fn new(app_name: &str) -> Result<Antimalware, WinError> {
+        let mut object = std::ptr::null_mut();
+        let hres = unsafe {
+            CoCreateInstance(&CLSID_ANTIMALWARE, std::ptr::null_mut(), CLSCTX_INPROC_SERVER, &IID_IANTIMALWARE, &mut object)
+        };
+        if hres != S_OK {
+            return Err(WinError::from_hresult(hres).during("CoCreateInstance"));
+        }
+
+        Ok(Antimalware{
+            antimalware: unsafe { ComPtr::from_raw(object) }.ok_or_else(|| WinError::from_hresult(E_POINTER))?,
+            app_name: app_name.encode_utf16().chain(std::iter::once(0)).collect(),
+        })
+    }
+
+    /// Scans a buffer.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **data** - payload that should be scanned.
+    pub fn scan_buffer(&self, content_name: &str, data: &[u8]) -> Result<ComScan, ScanError> {
+        self.scan_buffer_with(content_name, data, &ScanAttributes::default())
+    }
+
+    /// Scans a buffer, passing additional attributes to the provider.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **data** - payload that should be scanned.
+    /// * **attributes** - context about the payload, see `ScanAttributes`.
+    pub fn scan_buffer_with(&self, content_name: &str, data: &[u8], attributes: &ScanAttributes) -> Result<ComScan, ScanError> {
+        buffer_length(data)?;
+        self.scan_amsi_stream(content_name, AmsiStream::new(&self.app_name, content_name, attributes, Box::new(BufferSource::new(data))))
+    }
+
+    /// Scans content from a reader, without loading all of it into memory.
+    ///
+    /// The reader is exposed to providers as an `IAmsiStream`, and providers read the parts of the content they are
+    /// interested in, in any order. This makes it possible to scan files of hundreds of megabytes, which would have
+    /// to be buffered completely for `AmsiSession::scan_buffer`. The content reaches from the current position of the
+    /// reader up to its end.
+    ///
+    /// Reads may happen on another thread than the one that calls this method, but never concurrently. A failing read
+    /// fails the provider's read with `E_FAIL`; what a provider makes of that is up to the provider.
+    ///
+    /// Providers that only scan content in memory fail stream scans with `E_NOTIMPL`. The content is then read into
+    /// memory and scanned again like with `scan_buffer`, so for such providers this costs as much memory as the size
+    /// of the content, and fails for content of 4 GiB or more.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **reader** - source of the content that should be scanned.
+    pub fn scan_stream<R: Read + Seek + Send>(&self, content_name: &str, reader: R) -> Result<ComScan, ScanError> {
+        self.scan_stream_with(content_name, reader, &ScanAttributes::default())
+    }
+
+    /// Scans content from a reader, passing additional attributes to the provider. See `scan_stream`.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **reader** - source of the content that should be scanned.
+    /// * **attributes** - context about the content, see `ScanAttributes`.
+    pub fn scan_stream_with<R: Read + Seek + Send>(&self, content_name: &str, mut reader: R, attributes: &ScanAttributes) -> Result<ComScan, ScanError> {
+        let start = reader.stream_position()?;
+        let source = ReaderSource::new(&mut reader)?;
+        match self.scan_amsi_stream(content_name, AmsiStream::new(&self.app_name, content_name, attributes, Box::new(source))) {
+            Err(ref err) if stream::is_stream_unsupported(err) => {
+                let data = stream::read_all(&mut reader, start)?;
+                self.scan_buffer_with(content_name, &data, attributes)
+            },
+            result => result,
+        }
+    }
+
+    /// Scans content from a reader that can't seek, e.g. a socket or a decompression stream, as a whole.
+    ///
+    /// Content of up to `memory_limit` bytes is read into memory and scanned like with `scan_buffer`. Larger content
+    /// is spilled to a temporary file, which is scanned like with `scan_stream` and deleted afterwards, also when
+    /// reading or scanning fails. This way content of any size can be scanned without running out of memory, as long
+    /// as the provider supports streams (see `scan_stream`) and the disk has room for it.
+    ///
+    /// Unlike `AmsiSession::scan_reader`, which scans the content in chunks, the provider sees all of the content.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **reader** - source of the content that should be scanned.
+    /// * **memory_limit** - largest content, in bytes, that is scanned from memory.
+    pub fn scan_reader<R: Read>(&self, content_name: &str, reader: R, memory_limit: usize) -> Result<ComScan, ScanError> {
+        match stream::spill(reader, memory_limit)? {
+            Spilled::Memory(data) => self.scan_buffer(content_name, &data),
+            Spilled::File(mut spilled) => self.scan_stream(content_name, &mut spilled.file),
+        }
+    }
+
+    fn scan_amsi_stream(&self, content_name: &str, stream: AmsiStream) -> Result<ComScan, ScanError> {
+        let mut result = 0;
+        let mut provider = std::ptr::null_mut();
+
+        let hres = unsafe {
+            (self.antimalware.vtbl().scan)(self.antimalware.as_raw(), stream.as_raw(), &mut result, &mut provider)
+        };
+        let provider = unsafe { ComPtr::<IAntimalwareProviderVtbl>::from_raw(provider) };
+
+        if hres != S_OK {
+            return Err(wow64::scan_error(WinError::from_hresult(hres).during("IAntimalware::Scan").for_content(content_name)));
+        }
+
+        Ok(ComScan{
+            result: AmsiResult::scanned(result),
+            provider: provider.and_then(|provider| unsafe {
+                let mut name = std::ptr::null_mut();
+                if (provider.vtbl().display_name)(provider.as_raw(), &mut name) == S_OK && !name.is_null() {
+                    Some(take_co_task_string(name))
+                } else {
+                    None
+                }
+            }),
+        })
+    }
+}
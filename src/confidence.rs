@@ -0,0 +1,81 @@
+use super::{AMSI_RESULT_CLEAN, AMSI_RESULT_DETECTED, AmsiResult, AmsiSession, FilterDecision, ScanError, ULONG};
+use super::sys::AMSI_RESULT_NOT_DETECTED;
+
+/// Why a payload was not handed to the provider.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SkipReason {
+    /// A filter of the context's `FilterChain` decided the outcome, either `FilterDecision::Allow` or
+    /// `FilterDecision::Block`.
+    Filtered(FilterDecision),
+    /// The payload has no content.
+    Empty,
+    /// The payload is larger than the `max_payload_size` of the context, or 4 GiB and larger, which can't be scanned
+    /// as a buffer.
+    TooLarge,
+    /// The payload was scanned before, and the result of that scan was taken from the cache of a `CachedSession`.
+    CacheHit(AmsiResult),
+}
+
+impl SkipReason {
+    /// Returns the result that scan functions report for a skipped payload.
+    ///
+    /// Regular scan functions fail for payloads that are too large, which are reported as "not detected" here.
+    pub fn result(&self) -> AmsiResult {
+        match *self {
+            SkipReason::Filtered(FilterDecision::Block) => AmsiResult::new(AMSI_RESULT_DETECTED),
+            SkipReason::Filtered(_) | SkipReason::Empty => AmsiResult::new(AMSI_RESULT_CLEAN),
+            SkipReason::TooLarge => AmsiResult::new(AMSI_RESULT_NOT_DETECTED),
+            SkipReason::CacheHit(result) => result,
+        }
+    }
+}
+
+/// The outcome of `AmsiSession::scan_confident`, telling apart provider verdicts from shortcuts taken by the crate.
+///
+/// Regular scan functions report a skipped payload as a plain `AmsiResult`, so an `Ok(clean)` can mean that the
+/// provider found nothing, or that the provider never saw the content. Security reports should only claim the former
+/// for `ScanConfidence::Scanned`.
+#[derive(Debug)]
+pub enum ScanConfidence {
+    /// The provider scanned the payload and returned this result.
+    Scanned(AmsiResult),
+    /// The payload was not scanned.
+    Skipped(SkipReason),
+}
+
+impl ScanConfidence {
+    /// Returns the result regular scan functions report for this outcome.
+    pub fn result(self) -> AmsiResult {
+        match self {
+            ScanConfidence::Scanned(result) => result,
+            ScanConfidence::Skipped(reason) => reason.result(),
+        }
+    }
+
+    /// Returns `true` if the provider actually scanned the payload.
+    pub fn is_scanned(&self) -> bool {
+        match *self {
+            ScanConfidence::Scanned(_) => true,
+            ScanConfidence::Skipped(_) => false,
+        }
+    }
+}
+
+impl<'a> AmsiSession<'a> {
+    /// Scans a buffer, reporting whether the provider was actually involved in the result.
+    ///
+    /// Unlike `scan_buffer`, payloads that are too large to be scanned don't fail, but are reported as
+    /// `SkipReason::TooLarge`.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **data** - payload that should be scanned.
+    pub fn scan_confident(&self, content_name: &str, data: &[u8]) -> Result<ScanConfidence, ScanError> {
+        self.check_cancelled()?;
+        if data.len() > ULONG::MAX as usize || self.ctx.max_payload_size.is_some_and(|max| data.len() > max) {
+            return Ok(ScanConfidence::Skipped(SkipReason::TooLarge));
+        }
+        self.ctx.scan_buffer_confident_in(self.target(), &content_name, data)
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::SystemTime;
+
+use super::WinError;
+use super::definitions::SIGNATURE_UPDATES_KEY;
+use super::etw::filetime_to_system_time;
+use super::registry::{HKEY_LOCAL_MACHINE, REG_BINARY, RegKey};
+
+/// The versions of the Windows Defender engine and definitions installed on the machine.
+///
+/// Recording them along with scan results tells later whether a "not detected" result is still meaningful, e.g. in a
+/// report that says a file was clean as of definitions `1.401.1234.0`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefenderVersions {
+    engine: Option<String>,
+    antivirus_signatures: Option<String>,
+    antispyware_signatures: Option<String>,
+    signatures_updated: Option<SystemTime>,
+}
+
+impl DefenderVersions {
+    /// Returns the version of the antimalware engine, e.g. `"1.1.24030.4"`.
+    pub fn engine(&self) -> Option<&str> {
+        self.engine.as_deref()
+    }
+
+    /// Returns the version of the antivirus definitions, e.g. `"1.409.185.0"`. This is the version
+    /// `DefinitionsToken` tracks.
+    pub fn antivirus_signatures(&self) -> Option<&str> {
+        self.antivirus_signatures.as_deref()
+    }
+
+    /// Returns the version of the antispyware definitions, which are updated together with the antivirus ones.
+    pub fn antispyware_signatures(&self) -> Option<&str> {
+        self.antispyware_signatures.as_deref()
+    }
+
+    /// Returns when the definitions were last updated.
+    pub fn signatures_updated(&self) -> Option<SystemTime> {
+        self.signatures_updated
+    }
+}
+
+/// Reads a string value, `None` if it is missing or empty.
+fn version(key: &RegKey, name: &str) -> Option<String> {
+    key.string_value(name)
+        .ok()
+        .filter(|version| !version.is_empty())
+}
+
+/// Returns the versions of the Windows Defender engine and definitions.
+///
+/// The versions are read from `HKLM\SOFTWARE\Microsoft\Windows Defender\Signature Updates`, which is readable
+/// without elevation. Versions that aren't set are `None`. Fails with `ERROR_FILE_NOT_FOUND` if Windows Defender
+/// isn't installed. Other antimalware products don't publish their versions in a common place, so their versions
+/// can't be queried.
+pub fn defender_versions() -> Result<DefenderVersions, WinError> {
+    let key = RegKey::open(HKEY_LOCAL_MACHINE, SIGNATURE_UPDATES_KEY)?;
+
+    // a `FILETIME`, stored as binary.
+    let signatures_updated = match key.value("SignaturesLastUpdated") {
+        Ok((REG_BINARY, ref data)) if data.len() == 8 => {
+            let mut filetime = [0; 8];
+            filetime.copy_from_slice(data);
+            Some(filetime_to_system_time(i64::from_le_bytes(filetime)))
+        },
+        _ => None,
+    };
+
+    Ok(DefenderVersions{
+        engine: version(&key, "EngineVersion"),
+        antivirus_signatures: version(&key, "AVSignatureVersion"),
+        antispyware_signatures: version(&key, "ASSignatureVersion"),
+        signatures_updated,
+    })
+}
@@ -0,0 +1,108 @@
+use super::{AmsiContext, AmsiResult, AmsiSession, ScanError, providers};
+use super::registry::{HKEY_LOCAL_MACHINE, RegKey};
+use super::sha256::Sha256;
+
+pub(crate) const SIGNATURE_UPDATES_KEY: &str = r"SOFTWARE\Microsoft\Windows Defender\Signature Updates";
+
+/// Identifies the antimalware definitions that were installed at some point in time.
+///
+/// Tokens are obtained from `AmsiContext::definitions_token` (or along with a scan, from
+/// `AmsiSession::scan_buffer_with_definitions`), and compared with `AmsiContext::definitions_changed_since`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DefinitionsToken {
+    version: Option<String>,
+}
+
+impl DefinitionsToken {
+    fn current() -> DefinitionsToken {
+        let version = RegKey::open(HKEY_LOCAL_MACHINE, SIGNATURE_UPDATES_KEY)
+            .and_then(|key| key.string_value("AVSignatureVersion"))
+            .ok()
+            .filter(|version| !version.is_empty());
+
+        DefinitionsToken{
+            version,
+        }
+    }
+
+    /// Returns the definition version this token stands for, or `None` if it couldn't be discovered.
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+}
+
+impl AmsiContext {
+    /// Returns a token for the currently installed antimalware definitions.
+    ///
+    /// The version is discovered through the registry, which is only possible for Windows Defender. For other
+    /// providers the token carries no version, see `definitions_changed_since`.
+    pub fn definitions_token(&self) -> DefinitionsToken {
+        DefinitionsToken::current()
+    }
+
+    /// Returns `true` if the antimalware definitions were updated since `token` was obtained.
+    ///
+    /// Content that scanned as "not detected" should be re-scanned once this returns `true`, instead of re-scanning
+    /// it periodically. When the definition version can't be discovered, this always returns `false`, so callers
+    /// that depend on re-scans should fall back to a schedule when `DefinitionsToken::version()` is `None`.
+    pub fn definitions_changed_since(&self, token: &DefinitionsToken) -> bool {
+        let current = DefinitionsToken::current();
+        match (&token.version, &current.version) {
+            (Some(old), Some(new)) => old != new,
+            _ => false,
+        }
+    }
+
+    /// Returns a key identifying the configuration results of this context depend on.
+    ///
+    /// The key covers the application name, the registered providers, the installed definition version and the
+    /// verdict policy. Mixing it into the keys of a result cache makes cached results go stale as soon as any of
+    /// them changes. The filter chain isn't covered, since filters can't be compared.
+    ///
+    /// The key is derived from SHA-256, so it is stable across processes and builds of this crate.
+    pub fn config_fingerprint(&self) -> u64 {
+        let mut hasher = Sha256::new();
+
+        for c in self.app_name.as_wide() {
+            hasher.update(&c.to_le_bytes());
+        }
+
+        let mut clsids: Vec<String> = providers()
+            .map(|providers| providers.iter().map(|p| p.clsid().to_ascii_uppercase()).collect())
+            .unwrap_or_default();
+        clsids.sort();
+        for clsid in &clsids {
+            hasher.update(clsid.as_bytes());
+            hasher.update(&[0]);
+        }
+        hasher.update(&[0]);
+
+        if let Some(version) = DefinitionsToken::current().version() {
+            hasher.update(version.as_bytes());
+        }
+        hasher.update(&[0]);
+
+        let policy = &self.policy;
+        for verdict in &[policy.clean, policy.not_detected, policy.blocked_by_admin, policy.detected, policy.unknown] {
+            hasher.update(&[*verdict as u8]);
+        }
+
+        let digest = hasher.finish();
+        let mut key = [0; 8];
+        key.copy_from_slice(&digest[..8]);
+        u64::from_le_bytes(key)
+    }
+}
+
+impl<'a> AmsiSession<'a> {
+    /// Scans a buffer, and returns the result along with a token for the definitions that were used.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **data** - payload that should be scanned.
+    pub fn scan_buffer_with_definitions(&self, content_name: &str, data: &[u8]) -> Result<(AmsiResult, DefinitionsToken), ScanError> {
+        let token = DefinitionsToken::current();
+        let result = self.scan_buffer(content_name, data)?;
+        Ok((result, token))
+    }
+}
@@ -0,0 +1,123 @@
+#![allow(non_snake_case)]
+
+use std::sync::OnceLock;
+
+use super::sys::{AMSI_RESULT, DWORD, HAMSICONTEXT, HAMSISESSION, HMODULE, HRESULT, LOAD_LIBRARY_SEARCH_SYSTEM32, LPCWSTR, ULONG};
+use super::sys::{GetLastError, GetProcAddress, LoadLibraryExW, amsi_dll, hresult_from_win32};
+
+type AmsiInitializeFn = unsafe extern "system" fn(name: LPCWSTR, context: &mut HAMSICONTEXT) -> HRESULT;
+type AmsiUninitializeFn = unsafe extern "system" fn(context: HAMSICONTEXT);
+type AmsiScanStringFn = unsafe extern "system" fn(context: HAMSICONTEXT, string: LPCWSTR, content_name: LPCWSTR, session: HAMSISESSION, result: &mut AMSI_RESULT) -> HRESULT;
+type AmsiScanBufferFn = unsafe extern "system" fn(context: HAMSICONTEXT, buffer: *const u8, length: ULONG, content_name: LPCWSTR, session: HAMSISESSION, result: &mut AMSI_RESULT) -> HRESULT;
+type AmsiOpenSessionFn = unsafe extern "system" fn(context: HAMSICONTEXT, session: &mut HAMSISESSION) -> HRESULT;
+type AmsiCloseSessionFn = unsafe extern "system" fn(context: HAMSICONTEXT, session: HAMSISESSION);
+
+/// The functions of `amsi.dll`, resolved at runtime.
+struct Api {
+    initialize: AmsiInitializeFn,
+    uninitialize: AmsiUninitializeFn,
+    scan_string: AmsiScanStringFn,
+    scan_buffer: AmsiScanBufferFn,
+    open_session: AmsiOpenSessionFn,
+    close_session: AmsiCloseSessionFn,
+}
+
+unsafe fn resolve(module: HMODULE, name: &[u8]) -> Result<*const u8, DWORD> {
+    let proc_addr = GetProcAddress(module, name.as_ptr());
+    if proc_addr.is_null() {
+        Err(GetLastError())
+    } else {
+        Ok(proc_addr)
+    }
+}
+
+/// Loads `amsi.dll` from the system directory on first use, returning the Windows error code if that fails.
+///
+/// The module is never unloaded.
+fn api() -> Result<&'static Api, DWORD> {
+    static API: OnceLock<Result<Api, DWORD>> = OnceLock::new();
+
+    API.get_or_init(|| unsafe {
+        let file_name = amsi_dll();
+        let module = LoadLibraryExW(file_name.as_ptr(), std::ptr::null(), LOAD_LIBRARY_SEARCH_SYSTEM32);
+        if module.is_null() {
+            return Err(GetLastError());
+        }
+
+        Ok(Api{
+            initialize: std::mem::transmute::<*const u8, AmsiInitializeFn>(resolve(module, b"AmsiInitialize\0")?),
+            uninitialize: std::mem::transmute::<*const u8, AmsiUninitializeFn>(resolve(module, b"AmsiUninitialize\0")?),
+            scan_string: std::mem::transmute::<*const u8, AmsiScanStringFn>(resolve(module, b"AmsiScanString\0")?),
+            scan_buffer: std::mem::transmute::<*const u8, AmsiScanBufferFn>(resolve(module, b"AmsiScanBuffer\0")?),
+            open_session: std::mem::transmute::<*const u8, AmsiOpenSessionFn>(resolve(module, b"AmsiOpenSession\0")?),
+            close_session: std::mem::transmute::<*const u8, AmsiCloseSessionFn>(resolve(module, b"AmsiCloseSession\0")?),
+        })
+    }).as_ref().map_err(|&code| code)
+}
+
+// The wrappers below mirror the native declarations. `AmsiInitialize` fails with the error of loading the DLL
+// (`ERROR_MOD_NOT_FOUND` on Windows versions without AMSI), the others can't be reached without a context.
+
+/// Calls `AmsiInitialize` of `amsi.dll`.
+///
+/// # Safety
+/// The arguments have to meet the requirements of the native function.
+pub unsafe fn AmsiInitialize(name: LPCWSTR, context: &mut HAMSICONTEXT) -> HRESULT {
+    match api() {
+        Ok(api) => (api.initialize)(name, context),
+        Err(code) => hresult_from_win32(code),
+    }
+}
+
+/// Calls `AmsiUninitialize` of `amsi.dll`.
+///
+/// # Safety
+/// The arguments have to meet the requirements of the native function.
+pub unsafe fn AmsiUninitialize(context: HAMSICONTEXT) {
+    if let Ok(api) = api() {
+        (api.uninitialize)(context)
+    }
+}
+
+/// Calls `AmsiScanString` of `amsi.dll`.
+///
+/// # Safety
+/// The arguments have to meet the requirements of the native function.
+pub unsafe fn AmsiScanString(context: HAMSICONTEXT, string: LPCWSTR, content_name: LPCWSTR, session: HAMSISESSION, result: &mut AMSI_RESULT) -> HRESULT {
+    match api() {
+        Ok(api) => (api.scan_string)(context, string, content_name, session, result),
+        Err(code) => hresult_from_win32(code),
+    }
+}
+
+/// Calls `AmsiScanBuffer` of `amsi.dll`.
+///
+/// # Safety
+/// The arguments have to meet the requirements of the native function.
+pub unsafe fn AmsiScanBuffer(context: HAMSICONTEXT, buffer: *const u8, length: ULONG, content_name: LPCWSTR, session: HAMSISESSION, result: &mut AMSI_RESULT) -> HRESULT {
+    match api() {
+        Ok(api) => (api.scan_buffer)(context, buffer, length, content_name, session, result),
+        Err(code) => hresult_from_win32(code),
+    }
+}
+
+/// Calls `AmsiOpenSession` of `amsi.dll`.
+///
+/// # Safety
+/// The arguments have to meet the requirements of the native function.
+pub unsafe fn AmsiOpenSession(context: HAMSICONTEXT, session: &mut HAMSISESSION) -> HRESULT {
+    match api() {
+        Ok(api) => (api.open_session)(context, session),
+        Err(code) => hresult_from_win32(code),
+    }
+}
+
+/// Calls `AmsiCloseSession` of `amsi.dll`.
+///
+/// # Safety
+/// The arguments have to meet the requirements of the native function.
+pub unsafe fn AmsiCloseSession(context: HAMSICONTEXT, session: HAMSISESSION) {
+    if let Ok(api) = api() {
+        (api.close_session)(context, session)
+    }
+}
@@ -0,0 +1,606 @@
+//! Tracing the scans of all applications on the machine.
+//!
+//! AMSI reports every scan to Event Tracing for Windows, on the `Microsoft-Antimalware-Scan-Interface` provider,
+//! along with the scanned content and the process that requested the scan. `AmsiTrace` starts a real-time trace
+//! session for that provider and parses its events, which shows what reached the provider and why it was flagged:
+//!
+//! ```no_run
+//! extern crate amsi;
+//!
+//! let trace = amsi::etw::AmsiTrace::start("amsi-rs-trace").unwrap();
+//! for event in trace.into_events() {
+//!     if let Some(scan) = event.scan() {
+//!         println!("{} scanned {:?} for process {}: {}", scan.app_name(), scan.content_name(), event.process_id(), scan.result());
+//!     }
+//! }
+//! ```
+//!
+//! Starting a trace session requires administrator rights (or membership in the "Performance Log Users" group). The
+//! number of trace sessions is limited system-wide, so sessions should be stopped when they are no longer needed,
+//! which `AmsiTrace` does on drop.
+
+use std::any::Any;
+use std::os::raw::c_void;
+use std::panic::{AssertUnwindSafe, catch_unwind, resume_unwind};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, channel};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{AmsiResult, WinError};
+use super::com::Guid;
+use super::registry::to_wide;
+use super::sys::{LPCWSTR, ULONG};
+
+type TRACEHANDLE = u64;
+
+/// `{2A576B87-09A7-520E-C21A-4942F0271D67}`, the ETW provider of AMSI.
+pub const AMSI_PROVIDER: Guid = Guid::from_u128(0x2a576b87_09a7_520e_c21a_4942f0271d67);
+
+/// The ID of the event AMSI writes for every `AmsiScanBuffer` and `AmsiScanString` call.
+pub const SCAN_EVENT_ID: u16 = 1101;
+
+const WNODE_FLAG_TRACED_GUID: ULONG = 0x0002_0000;
+const EVENT_TRACE_REAL_TIME_MODE: ULONG = 0x0000_0100;
+/// Timestamps in system time, i.e. as `FILETIME`s.
+const CLOCK_SYSTEM_TIME: ULONG = 2;
+const EVENT_TRACE_CONTROL_STOP: ULONG = 1;
+const EVENT_CONTROL_CODE_ENABLE_PROVIDER: ULONG = 1;
+const TRACE_LEVEL_VERBOSE: u8 = 5;
+const PROCESS_TRACE_MODE_REAL_TIME: ULONG = 0x0000_0100;
+const PROCESS_TRACE_MODE_EVENT_RECORD: ULONG = 0x1000_0000;
+const EVENT_HEADER_FLAG_32_BIT_HEADER: u16 = 0x0020;
+const ERROR_SUCCESS: ULONG = 0;
+const ERROR_CANCELLED: ULONG = 1223;
+#[cfg(target_pointer_width = "64")]
+const INVALID_PROCESSTRACE_HANDLE: TRACEHANDLE = 0xffff_ffff_ffff_ffff;
+#[cfg(not(target_pointer_width = "64"))]
+const INVALID_PROCESSTRACE_HANDLE: TRACEHANDLE = 0x0000_0000_ffff_ffff;
+/// The difference between the `FILETIME` epoch (1601) and the Unix epoch, in 100 nanosecond intervals.
+const FILETIME_UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+
+#[repr(C)]
+struct WNODE_HEADER {
+    buffer_size: ULONG,
+    provider_id: ULONG,
+    historical_context: u64,
+    time_stamp: i64,
+    guid: Guid,
+    client_context: ULONG,
+    flags: ULONG,
+}
+
+#[repr(C)]
+struct EVENT_TRACE_PROPERTIES {
+    wnode: WNODE_HEADER,
+    buffer_size: ULONG,
+    minimum_buffers: ULONG,
+    maximum_buffers: ULONG,
+    maximum_file_size: ULONG,
+    log_file_mode: ULONG,
+    flush_timer: ULONG,
+    enable_flags: ULONG,
+    age_limit: i32,
+    number_of_buffers: ULONG,
+    free_buffers: ULONG,
+    events_lost: ULONG,
+    buffers_written: ULONG,
+    log_buffers_lost: ULONG,
+    real_time_buffers_lost: ULONG,
+    logger_thread_id: *mut c_void,
+    log_file_name_offset: ULONG,
+    logger_name_offset: ULONG,
+}
+
+/// `EVENT_TRACE`, which is only used by the legacy callback.
+#[repr(C)]
+struct EVENT_TRACE {
+    header: [u64; 6],
+    instance_id: ULONG,
+    parent_instance_id: ULONG,
+    parent_guid: Guid,
+    mof_data: *mut c_void,
+    mof_length: ULONG,
+    client_context: ULONG,
+}
+
+#[repr(C)]
+struct TRACE_LOGFILE_HEADER {
+    buffer_size: ULONG,
+    version: ULONG,
+    provider_version: ULONG,
+    number_of_processors: ULONG,
+    end_time: i64,
+    timer_resolution: ULONG,
+    maximum_file_size: ULONG,
+    log_file_mode: ULONG,
+    buffers_written: ULONG,
+    log_instance_guid: Guid,
+    logger_name: *mut u16,
+    log_file_name: *mut u16,
+    /// `TIME_ZONE_INFORMATION`.
+    time_zone: [u32; 43],
+    boot_time: i64,
+    perf_freq: i64,
+    start_time: i64,
+    reserved_flags: ULONG,
+    buffers_lost: ULONG,
+}
+
+#[repr(C)]
+struct EVENT_TRACE_LOGFILEW {
+    log_file_name: *mut u16,
+    logger_name: *mut u16,
+    current_time: i64,
+    buffers_read: ULONG,
+    process_trace_mode: ULONG,
+    current_event: EVENT_TRACE,
+    logfile_header: TRACE_LOGFILE_HEADER,
+    buffer_callback: *mut c_void,
+    buffer_size: ULONG,
+    filled: ULONG,
+    events_lost: ULONG,
+    event_record_callback: Option<unsafe extern "system" fn(*mut EVENT_RECORD)>,
+    is_kernel_trace: ULONG,
+    context: *mut c_void,
+}
+
+#[repr(C)]
+pub(crate) struct EVENT_DESCRIPTOR {
+    pub(crate) id: u16,
+    pub(crate) version: u8,
+    pub(crate) channel: u8,
+    pub(crate) level: u8,
+    pub(crate) opcode: u8,
+    pub(crate) task: u16,
+    pub(crate) keyword: u64,
+}
+
+#[repr(C)]
+struct EVENT_HEADER {
+    size: u16,
+    header_type: u16,
+    flags: u16,
+    event_property: u16,
+    thread_id: ULONG,
+    process_id: ULONG,
+    time_stamp: i64,
+    provider_id: Guid,
+    event_descriptor: EVENT_DESCRIPTOR,
+    processor_time: u64,
+    activity_id: Guid,
+}
+
+#[repr(C)]
+struct EVENT_RECORD {
+    event_header: EVENT_HEADER,
+    /// `ETW_BUFFER_CONTEXT`.
+    buffer_context: u32,
+    extended_data_count: u16,
+    user_data_length: u16,
+    extended_data: *mut c_void,
+    user_data: *const u8,
+    user_context: *mut c_void,
+}
+
+#[link(name="advapi32")]
+extern "system" {
+    fn StartTraceW(handle: *mut TRACEHANDLE, instance_name: LPCWSTR, properties: *mut EVENT_TRACE_PROPERTIES) -> ULONG;
+    fn ControlTraceW(handle: TRACEHANDLE, instance_name: LPCWSTR, properties: *mut EVENT_TRACE_PROPERTIES, control_code: ULONG) -> ULONG;
+    fn EnableTraceEx2(handle: TRACEHANDLE, provider_id: *const Guid, control_code: ULONG, level: u8, match_any_keyword: u64, match_all_keyword: u64, timeout: ULONG, enable_parameters: *mut c_void) -> ULONG;
+    fn OpenTraceW(logfile: *mut EVENT_TRACE_LOGFILEW) -> TRACEHANDLE;
+    fn ProcessTrace(handles: *const TRACEHANDLE, handle_count: ULONG, start_time: *const c_void, end_time: *const c_void) -> ULONG;
+    fn CloseTrace(handle: TRACEHANDLE) -> ULONG;
+}
+
+fn check(status: ULONG, operation: &'static str) -> Result<(), WinError> {
+    if status == ERROR_SUCCESS {
+        Ok(())
+    } else {
+        Err(WinError::from_code(status).during(operation))
+    }
+}
+
+/// A buffer holding `EVENT_TRACE_PROPERTIES`, followed by the name of the session.
+struct Properties {
+    // `u64`s, so the buffer is aligned like the structure.
+    buffer: Vec<u64>,
+}
+
+impl Properties {
+    fn new(name: &[u16]) -> Properties {
+        let size = std::mem::size_of::<EVENT_TRACE_PROPERTIES>() + name.len() * 2;
+        let mut properties = Properties{
+            buffer: vec![0; size.div_ceil(8)],
+        };
+        let header = properties.header();
+        header.wnode.buffer_size = size as ULONG;
+        header.wnode.flags = WNODE_FLAG_TRACED_GUID;
+        header.wnode.client_context = CLOCK_SYSTEM_TIME;
+        header.log_file_mode = EVENT_TRACE_REAL_TIME_MODE;
+        header.logger_name_offset = std::mem::size_of::<EVENT_TRACE_PROPERTIES>() as ULONG;
+        properties
+    }
+
+    fn header(&mut self) -> &mut EVENT_TRACE_PROPERTIES {
+        unsafe { &mut *(self.buffer.as_mut_ptr() as *mut EVENT_TRACE_PROPERTIES) }
+    }
+}
+
+/// A real-time trace session that receives the events of AMSI, stopped on drop.
+#[derive(Debug)]
+pub struct AmsiTrace {
+    handle: TRACEHANDLE,
+    name: Vec<u16>,
+    stopped: AtomicBool,
+}
+
+impl AmsiTrace {
+    /// Starts a trace session named `session_name`, and enables the AMSI provider for it.
+    ///
+    /// Fails with `ERROR_ALREADY_EXISTS` if a session with the same name is running, e.g. because a previous run of
+    /// the application didn't stop it, and with `ERROR_ACCESS_DENIED` without the required rights (see the module
+    /// documentation).
+    pub fn start(session_name: &str) -> Result<AmsiTrace, WinError> {
+        let name = to_wide(session_name);
+        let mut properties = Properties::new(&name);
+        let mut handle = 0;
+        check(unsafe {
+            StartTraceW(&mut handle, name.as_ptr(), properties.header())
+        }, "StartTraceW")?;
+
+        // from here on, dropping the trace stops the session.
+        let trace = AmsiTrace{
+            handle,
+            name,
+            stopped: AtomicBool::new(false),
+        };
+        check(unsafe {
+            EnableTraceEx2(handle, &AMSI_PROVIDER, EVENT_CONTROL_CODE_ENABLE_PROVIDER, TRACE_LEVEL_VERBOSE, 0, 0, 0, std::ptr::null_mut())
+        }, "EnableTraceEx2")?;
+        Ok(trace)
+    }
+
+    /// Returns the name of the trace session.
+    pub fn session_name(&self) -> String {
+        String::from_utf16_lossy(&self.name[..self.name.len() - 1])
+    }
+
+    /// Calls `handler` for every event of the session, until the session is stopped.
+    ///
+    /// This blocks the calling thread; `stop` (from another thread, or from `handler`) makes it return. Events are
+    /// delivered in the order in which they were written. A panic in `handler` stops the session, and is resumed
+    /// once the events of the session are no longer processed.
+    pub fn process<F: FnMut(TraceEvent)>(&self, mut handler: F) -> Result<(), WinError> {
+        let mut consumer = Consumer{
+            handler: &mut handler,
+            trace: self,
+            panic: None,
+        };
+
+        let mut logfile: EVENT_TRACE_LOGFILEW = unsafe { std::mem::zeroed() };
+        logfile.logger_name = self.name.as_ptr() as *mut u16;
+        logfile.process_trace_mode = PROCESS_TRACE_MODE_REAL_TIME | PROCESS_TRACE_MODE_EVENT_RECORD;
+        logfile.event_record_callback = Some(on_event);
+        logfile.context = &mut consumer as *mut Consumer as *mut c_void;
+
+        let handle = unsafe { OpenTraceW(&mut logfile) };
+        if handle == INVALID_PROCESSTRACE_HANDLE {
+            return Err(WinError::new().during("OpenTraceW"));
+        }
+        let status = unsafe { ProcessTrace(&handle, 1, std::ptr::null(), std::ptr::null()) };
+        unsafe {
+            CloseTrace(handle);
+        }
+
+        if let Some(panic) = consumer.panic.take() {
+            resume_unwind(panic);
+        }
+        match status {
+            // the session was stopped while the consumer was waiting for events.
+            ERROR_CANCELLED => Ok(()),
+            status => check(status, "ProcessTrace"),
+        }
+    }
+
+    /// Returns the events of the session as an iterator, processing them on a thread of its own.
+    ///
+    /// The iterator ends when the session is stopped, which happens when the iterator is dropped.
+    pub fn into_events(self) -> TraceEvents {
+        let trace = Arc::new(self);
+        let (sender, receiver) = channel();
+
+        let thread_trace = trace.clone();
+        let _ = std::thread::Builder::new().name("amsi-etw-consumer".into()).spawn(move || {
+            let _ = thread_trace.process(|event| {
+                if sender.send(event).is_err() {
+                    let _ = thread_trace.stop();
+                }
+            });
+        });
+
+        TraceEvents{
+            trace,
+            receiver,
+        }
+    }
+
+    /// Stops the trace session, which ends `process` and the iterator returned by `into_events`.
+    ///
+    /// Stopping a session that was stopped already does nothing.
+    pub fn stop(&self) -> Result<(), WinError> {
+        if self.stopped.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let mut properties = Properties::new(&self.name);
+        check(unsafe {
+            ControlTraceW(self.handle, std::ptr::null(), properties.header(), EVENT_TRACE_CONTROL_STOP)
+        }, "ControlTraceW")
+    }
+}
+
+impl Drop for AmsiTrace {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// The events of a trace session, returned by `AmsiTrace::into_events`.
+#[derive(Debug)]
+pub struct TraceEvents {
+    trace: Arc<AmsiTrace>,
+    receiver: Receiver<TraceEvent>,
+}
+
+impl TraceEvents {
+    /// Returns the trace session the events come from, e.g. to stop it from another thread.
+    pub fn trace(&self) -> &Arc<AmsiTrace> {
+        &self.trace
+    }
+}
+
+impl Iterator for TraceEvents {
+    type Item = TraceEvent;
+
+    fn next(&mut self) -> Option<TraceEvent> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl Drop for TraceEvents {
+    fn drop(&mut self) {
+        let _ = self.trace.stop();
+    }
+}
+
+/// The state of `AmsiTrace::process`, passed to `on_event`.
+struct Consumer<'a> {
+    handler: &'a mut dyn FnMut(TraceEvent),
+    trace: &'a AmsiTrace,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+unsafe extern "system" fn on_event(record: *mut EVENT_RECORD) {
+    let record = &*record;
+    let consumer = &mut *(record.user_context as *mut Consumer);
+    if consumer.panic.is_some() || record.event_header.provider_id != AMSI_PROVIDER {
+        return;
+    }
+
+    let data = if record.user_data.is_null() {
+        Vec::new()
+    } else {
+        std::slice::from_raw_parts(record.user_data, record.user_data_length as usize).to_vec()
+    };
+    let pointer_size = if record.event_header.flags & EVENT_HEADER_FLAG_32_BIT_HEADER != 0 { 4 } else { 8 };
+    let event = TraceEvent::new(&record.event_header, data, pointer_size);
+
+    // unwinding out of the callback would cross `ProcessTrace`.
+    let handler = &mut consumer.handler;
+    if let Err(panic) = catch_unwind(AssertUnwindSafe(|| handler(event))) {
+        consumer.panic = Some(panic);
+        let _ = consumer.trace.stop();
+    }
+}
+
+/// An event of the AMSI provider.
+#[derive(Debug, Clone)]
+pub struct TraceEvent {
+    id: u16,
+    process_id: u32,
+    thread_id: u32,
+    timestamp: SystemTime,
+    data: Vec<u8>,
+    scan: Option<TraceScan>,
+}
+
+impl TraceEvent {
+    fn new(header: &EVENT_HEADER, data: Vec<u8>, pointer_size: usize) -> TraceEvent {
+        let id = header.event_descriptor.id;
+        TraceEvent{
+            id,
+            process_id: header.process_id,
+            thread_id: header.thread_id,
+            timestamp: filetime_to_system_time(header.time_stamp),
+            scan: if id == SCAN_EVENT_ID { TraceScan::parse(&data, pointer_size) } else { None },
+            data,
+        }
+    }
+
+    /// Returns the ID of the event, see `SCAN_EVENT_ID`.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Returns the ID of the process that requested the scan.
+    pub fn process_id(&self) -> u32 {
+        self.process_id
+    }
+
+    /// Returns the ID of the thread that requested the scan.
+    pub fn thread_id(&self) -> u32 {
+        self.thread_id
+    }
+
+    /// Returns when the event was written.
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// Returns the payload of the event, as written by AMSI.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the scan the event reports, `None` for other events and malformed scan events.
+    pub fn scan(&self) -> Option<&TraceScan> {
+        self.scan.as_ref()
+    }
+}
+
+pub(crate) fn filetime_to_system_time(filetime: i64) -> SystemTime {
+    let intervals = (filetime as u64).saturating_sub(FILETIME_UNIX_EPOCH);
+    UNIX_EPOCH + Duration::from_secs(intervals / 10_000_000) + Duration::from_nanos(intervals % 10_000_000 * 100)
+}
+
+/// A scan reported by AMSI, the payload of a `SCAN_EVENT_ID` event.
+#[derive(Debug, Clone)]
+pub struct TraceScan {
+    session: u64,
+    scan_status: u8,
+    result: AmsiResult,
+    app_name: String,
+    content_name: String,
+    content_size: u32,
+    original_size: u32,
+    content: Vec<u8>,
+    hash: Vec<u8>,
+    content_filtered: bool,
+}
+
+impl TraceScan {
+    /// Parses the payload of a scan event, whose pointers are `pointer_size` bytes.
+    pub(crate) fn parse(data: &[u8], pointer_size: usize) -> Option<TraceScan> {
+        let mut reader = EventReader{
+            data,
+        };
+        let session = if pointer_size == 4 { reader.u32()? as u64 } else { reader.u64()? };
+        let scan_status = reader.u8()?;
+        let result = AmsiResult::new(reader.u32()?);
+        let app_name = reader.string()?;
+        let content_name = reader.string()?;
+        let content_size = reader.u32()?;
+        let original_size = reader.u32()?;
+        let content = reader.bytes(content_size as usize)?.to_vec();
+        let hash = reader.bytes(32)?.to_vec();
+        let content_filtered = reader.u32()? != 0;
+
+        Some(TraceScan{
+            session,
+            scan_status,
+            result,
+            app_name,
+            content_name,
+            content_size,
+            original_size,
+            content,
+            hash,
+            content_filtered,
+        })
+    }
+
+    /// Returns the session the content was scanned in, as an opaque value, `0` for scans without a session.
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    /// Returns `true` if the scan was handed to a provider, which it isn't e.g. when no provider is registered.
+    pub fn scanned(&self) -> bool {
+        self.scan_status != 0
+    }
+
+    /// Returns the result of the scan.
+    pub fn result(&self) -> AmsiResult {
+        self.result
+    }
+
+    /// Returns the name of the application that requested the scan, as passed to `AmsiInitialize`.
+    pub fn app_name(&self) -> &str {
+        &self.app_name
+    }
+
+    /// Returns the content name of the scan.
+    pub fn content_name(&self) -> &str {
+        &self.content_name
+    }
+
+    /// Returns the number of bytes of content included in the event, see `content`.
+    pub fn content_size(&self) -> u32 {
+        self.content_size
+    }
+
+    /// Returns the size of the scanned content, in bytes.
+    pub fn original_size(&self) -> u32 {
+        self.original_size
+    }
+
+    /// Returns the scanned content, which AMSI truncates for large payloads (see `content_filtered`). Strings are
+    /// included as UTF-16LE.
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+
+    /// Returns the SHA-256 digest of the scanned content.
+    pub fn hash(&self) -> &[u8] {
+        &self.hash
+    }
+
+    /// Returns `true` if AMSI left out the content, or part of it.
+    pub fn content_filtered(&self) -> bool {
+        self.content_filtered
+    }
+}
+
+/// Reads the fields of an event payload, which are packed without padding.
+struct EventReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> EventReader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes(8).map(|b| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(b);
+            u64::from_le_bytes(bytes)
+        })
+    }
+
+    /// Reads a nul-terminated UTF-16LE string.
+    fn string(&mut self) -> Option<String> {
+        let mut units = Vec::new();
+        loop {
+            let unit = self.bytes(2).map(|b| u16::from_le_bytes([b[0], b[1]]))?;
+            if unit == 0 {
+                return Some(String::from_utf16_lossy(&units));
+            }
+            units.push(unit);
+        }
+    }
+}
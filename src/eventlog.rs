@@ -0,0 +1,101 @@
+use std::os::raw::c_void;
+
+use super::{AmsiResult, AuditRecord, AuditStore, DWORD, LPCWSTR, WinError};
+use super::registry::{self, HKEY_LOCAL_MACHINE, RegKey, to_wide};
+use super::sha256::to_hex;
+use super::sys::HANDLE;
+
+const EVENTLOG_WARNING_TYPE: u16 = 0x0002;
+const EVENTLOG_TYPES_SUPPORTED: DWORD = 0x0007;
+/// The ID of the events written for detections. `EventCreate.exe` has a message for IDs 1 - 1000 that shows the
+/// text of the event as it is.
+const DETECTION_EVENT_ID: DWORD = 1;
+const EVENT_MESSAGE_FILE: &str = r"%SystemRoot%\System32\EventCreate.exe";
+const APPLICATION_LOG_KEY: &str = r"SYSTEM\CurrentControlSet\Services\EventLog\Application";
+
+#[link(name="advapi32")]
+extern "system" {
+    fn RegisterEventSourceW(server_name: LPCWSTR, source_name: LPCWSTR) -> HANDLE;
+    fn ReportEventW(event_log: HANDLE, event_type: u16, category: u16, event_id: DWORD, user_sid: *mut c_void, num_strings: u16, data_size: DWORD, strings: *const LPCWSTR, raw_data: *const c_void) -> i32;
+    fn DeregisterEventSource(event_log: HANDLE) -> i32;
+}
+
+/// An `AuditStore` that writes detections to the Application event log, where SOC tooling usually collects them.
+///
+/// Every audited scan whose result `is_malware()` is written as a warning with event ID 1, which names the content,
+/// its SHA-256 digest, the result code and the verdict. Other scans aren't written. Set as the detection store of a
+/// context (see `AmsiContext::set_detection_store`), the store sees every scan of the context. As its audit store (see
+/// `AmsiContext::set_audit_store`), it only sees the scans of `AmsiSession::scan_buffer_audited`, which fail if the
+/// event can't be written.
+///
+/// The event source should be registered once (e.g. by the installer of the application) with `register_source`,
+/// otherwise Event Viewer complains that the description of the event can't be found.
+#[derive(Debug)]
+pub struct EventLogAuditStore {
+    handle: HANDLE,
+}
+
+// event source handles can be used from any thread, and `ReportEventW` synchronizes on its own.
+unsafe impl Send for EventLogAuditStore {}
+unsafe impl Sync for EventLogAuditStore {}
+
+impl EventLogAuditStore {
+    /// Opens the event source `source`, e.g. the name of the application.
+    pub fn new(source: &str) -> Result<EventLogAuditStore, WinError> {
+        let source = to_wide(source);
+        let handle = unsafe { RegisterEventSourceW(std::ptr::null(), source.as_ptr()) };
+        if handle.is_null() {
+            return Err(WinError::new().during("RegisterEventSourceW"));
+        }
+        Ok(EventLogAuditStore{
+            handle,
+        })
+    }
+
+    /// Registers `source` as an event source of the Application log, with the messages of `EventCreate.exe`.
+    ///
+    /// This requires administrator rights. Registering a source that exists already updates its registration.
+    pub fn register_source(source: &str) -> Result<(), WinError> {
+        let key = RegKey::create(HKEY_LOCAL_MACHINE, &format!("{}\\{}", APPLICATION_LOG_KEY, source))?;
+        key.set_expand_string_value("EventMessageFile", EVENT_MESSAGE_FILE)?;
+        key.set_dword_value("TypesSupported", EVENTLOG_TYPES_SUPPORTED)?;
+        key.set_dword_value("CustomSource", 1)
+    }
+
+    /// Removes the registration of `source` made by `register_source`. This requires administrator rights.
+    pub fn unregister_source(source: &str) -> Result<(), WinError> {
+        registry::delete_tree(HKEY_LOCAL_MACHINE, &format!("{}\\{}", APPLICATION_LOG_KEY, source))
+    }
+}
+
+/// Returns the text of the event written for a detection.
+pub(crate) fn detection_message(record: &AuditRecord) -> String {
+    format!("AMSI detected malicious content in {:?}.\r\n\r\nSHA-256: {}\r\nSize: {} bytes\r\nResult code: {:#x}\r\nVerdict: {:?}",
+        record.content_name(), to_hex(record.sha256()), record.size(), record.result_code(), record.verdict())
+}
+
+impl AuditStore for EventLogAuditStore {
+    fn record(&self, record: &AuditRecord) -> std::io::Result<()> {
+        if !AmsiResult::new(record.result_code()).is_malware() {
+            return Ok(());
+        }
+
+        let message = to_wide(&detection_message(record));
+        let strings = [message.as_ptr()];
+        let ok = unsafe {
+            ReportEventW(self.handle, EVENTLOG_WARNING_TYPE, 0, DETECTION_EVENT_ID, std::ptr::null_mut(), strings.len() as u16, 0, strings.as_ptr(), std::ptr::null())
+        };
+        if ok == 0 {
+            return Err(WinError::new().during("ReportEventW").into());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EventLogAuditStore {
+    fn drop(&mut self) {
+        unsafe {
+            DeregisterEventSource(self.handle);
+        }
+    }
+}
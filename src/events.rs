@@ -0,0 +1,71 @@
+use super::{AmsiResult, AmsiSession, ScanError};
+
+/// An event of a scan, see `AmsiSession::scan_events`.
+#[derive(Debug)]
+pub enum ScanEvent {
+    /// The scan is about to start.
+    Started,
+    /// The given number of bytes (in total) were handed to the provider.
+    Progress(usize),
+    /// The scan finished with a result. This is the last event.
+    Completed(AmsiResult),
+    /// The scan failed. This is the last event.
+    Failed(ScanError),
+}
+
+enum State {
+    Start,
+    Scan,
+    Finish(AmsiResult),
+    Done,
+}
+
+/// Iterator over the events of `AmsiSession::scan_events`.
+pub struct ScanEvents<'s, 'a: 's, 'd> {
+    session: &'s AmsiSession<'a>,
+    content_name: String,
+    data: &'d [u8],
+    state: State,
+}
+
+impl<'s, 'a, 'd> Iterator for ScanEvents<'s, 'a, 'd> {
+    type Item = ScanEvent;
+
+    fn next(&mut self) -> Option<ScanEvent> {
+        match std::mem::replace(&mut self.state, State::Done) {
+            State::Start => {
+                self.state = State::Scan;
+                Some(ScanEvent::Started)
+            },
+            State::Scan => match self.session.scan_buffer(&self.content_name, self.data) {
+                Ok(result) => {
+                    self.state = State::Finish(result);
+                    Some(ScanEvent::Progress(self.data.len()))
+                },
+                Err(err) => Some(ScanEvent::Failed(err)),
+            },
+            State::Finish(result) => Some(ScanEvent::Completed(result)),
+            State::Done => None,
+        }
+    }
+}
+
+impl<'a> AmsiSession<'a> {
+    /// Scans a buffer, reporting the scan as a sequence of events.
+    ///
+    /// The iterator yields `Started`, then `Progress` once the buffer was handed to the provider, and finally
+    /// `Completed` with the result. A failed scan yields `Failed` instead of `Progress` and `Completed`. The scan
+    /// happens while the iterator is advanced, not when this method is called.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **data** - payload that should be scanned.
+    pub fn scan_events<'s, 'd>(&'s self, content_name: &str, data: &'d [u8]) -> ScanEvents<'s, 'a, 'd> {
+        ScanEvents{
+            session: self,
+            content_name: content_name.to_owned(),
+            data,
+            state: State::Start,
+        }
+    }
+}
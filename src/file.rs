@@ -0,0 +1,242 @@
+use std::io::Read;
+use std::os::raw::c_void;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use super::{AmsiResult, AmsiSession, DWORD, FILE_SIZE_LIMIT, IntoContentName, LPCWSTR, ScanError, WinError};
+use super::sys::{BOOL, CloseHandle, GetFileSizeEx, HANDLE, INVALID_HANDLE_VALUE, hresult_from_win32};
+
+const ERROR_FILE_NOT_FOUND: DWORD = 2;
+const ERROR_PATH_NOT_FOUND: DWORD = 3;
+const ERROR_HANDLE_EOF: DWORD = 38;
+const FIND_STREAM_INFO_STANDARD: u32 = 0;
+const MAX_PATH: usize = 260;
+const GENERIC_READ: DWORD = 0x8000_0000;
+const FILE_SHARE_READ: DWORD = 0x1;
+const FILE_SHARE_WRITE: DWORD = 0x2;
+const FILE_SHARE_DELETE: DWORD = 0x4;
+
+#[repr(C)]
+struct WIN32_FIND_STREAM_DATA {
+    stream_size: i64,
+    stream_name: [u16; MAX_PATH + 36],
+}
+
+#[link(name="kernel32")]
+extern "system" {
+    fn FindFirstStreamW(file_name: LPCWSTR, info_level: u32, find_stream_data: *mut WIN32_FIND_STREAM_DATA, flags: DWORD) -> HANDLE;
+    fn FindNextStreamW(find_stream: HANDLE, find_stream_data: *mut WIN32_FIND_STREAM_DATA) -> BOOL;
+    fn FindClose(find_file: HANDLE) -> BOOL;
+    fn ReOpenFile(original_file: HANDLE, desired_access: DWORD, share_mode: DWORD, flags_and_attributes: DWORD) -> HANDLE;
+    fn ReadFile(file: HANDLE, buffer: *mut u8, bytes_to_read: DWORD, bytes_read: *mut DWORD, overlapped: *mut c_void) -> BOOL;
+}
+
+/// Reads the whole content of a file handle, failing with an `InvalidData` I/O error if it is larger than `max_size`
+/// bytes.
+///
+/// The file is read through a handle of its own, opened with `ReOpenFile`, so the file pointer of `handle` isn't moved.
+pub(crate) unsafe fn read_handle(handle: HANDLE, max_size: u64) -> std::io::Result<Vec<u8>> {
+    let handle = ReOpenFile(handle, GENERIC_READ, FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE, 0);
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(WinError::new().during("ReOpenFile").into());
+    }
+    let res = read_to_limit(handle, max_size);
+    CloseHandle(handle);
+    res
+}
+
+unsafe fn read_to_limit(handle: HANDLE, max_size: u64) -> std::io::Result<Vec<u8>> {
+    let too_large = || std::io::Error::new(std::io::ErrorKind::InvalidData, format!("file is larger than {} bytes", max_size));
+    let mut size = 0;
+    if GetFileSizeEx(handle, &mut size) == 0 {
+        return Err(WinError::new().during("GetFileSizeEx").into());
+    }
+    if size as u64 > max_size {
+        return Err(too_large());
+    }
+
+    let mut data: Vec<u8> = Vec::with_capacity(size as usize);
+    loop {
+        if data.len() == data.capacity() {
+            // the file might still be growing.
+            data.reserve(64 * 1024);
+        }
+
+        // reads one byte past the limit at most, to tell whether the file grew beyond it.
+        let spare = (data.capacity() - data.len()) as u64;
+        let to_read = spare.min(max_size.saturating_add(1) - data.len() as u64).min(DWORD::MAX as u64) as DWORD;
+        let mut read = 0;
+        if ReadFile(handle, data.as_mut_ptr().add(data.len()), to_read, &mut read, std::ptr::null_mut()) == 0 {
+            return Err(WinError::new().during("ReadFile").into());
+        }
+        if read == 0 {
+            return Ok(data);
+        }
+        data.set_len(data.len() + read as usize);
+        if data.len() as u64 > max_size {
+            return Err(too_large());
+        }
+    }
+}
+
+/// Reads a file into memory, failing with an `InvalidData` I/O error if it is larger than `max_size` bytes.
+pub(crate) fn read_file(path: &Path, max_size: u64) -> std::io::Result<Vec<u8>> {
+    let file = std::fs::File::open(path)?;
+    let too_large = || std::io::Error::new(std::io::ErrorKind::InvalidData, format!("file is larger than {} bytes", max_size));
+    if file.metadata()?.len() > max_size {
+        return Err(too_large());
+    }
+
+    // the file might grow while it is read.
+    let mut data = Vec::new();
+    file.take(max_size.saturating_add(1)).read_to_end(&mut data)?;
+    if data.len() as u64 > max_size {
+        return Err(too_large());
+    }
+    Ok(data)
+}
+
+/// Converts UTF-16 from the system to an `OsString`, keeping invalid sequences on Windows.
+pub(crate) fn from_wide(wide: &[u16]) -> OsString {
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStringExt;
+
+        OsString::from_wide(wide)
+    }
+    #[cfg(not(windows))]
+    {
+        OsString::from(String::from_utf16_lossy(wide))
+    }
+}
+
+/// Lists the names of the data streams of a file, as reported by `FindFirstStreamW` without the leading colon and the
+/// `":$DATA"` type, so the main stream has an empty name.
+fn list_streams(path: &Path) -> Result<Vec<OsString>, WinError> {
+    let path_utf16 = path.to_wide();
+    let mut data = WIN32_FIND_STREAM_DATA{
+        stream_size: 0,
+        stream_name: [0; MAX_PATH + 36],
+    };
+
+    let handle = unsafe {
+        FindFirstStreamW(path_utf16.as_ptr(), FIND_STREAM_INFO_STANDARD, &mut data, 0)
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(WinError::new());
+    }
+
+    let data_suffix: Vec<u16> = ":$DATA".encode_utf16().collect();
+    let mut streams = Vec::new();
+    let res = loop {
+        let len = data.stream_name.iter().position(|&c| c == 0).unwrap_or(data.stream_name.len());
+        // stream names look like ":name:$DATA".
+        let mut name = &data.stream_name[..len];
+        name = name.strip_suffix(&data_suffix[..]).unwrap_or(name);
+        name = name.strip_prefix(&[u16::from(b':')]).unwrap_or(name);
+        streams.push(from_wide(name));
+
+        if unsafe { FindNextStreamW(handle, &mut data) } == 0 {
+            let err = WinError::new();
+            break if err.hresult() == hresult_from_win32(ERROR_HANDLE_EOF) { Ok(streams) } else { Err(err) };
+        }
+    };
+
+    unsafe {
+        FindClose(handle);
+    }
+    res
+}
+
+/// Falls back to the main stream alone when the streams of an existing file can't be enumerated, e.g. on FAT or
+/// network volumes. Errors for missing files are kept.
+pub(crate) fn streams_or_main(streams: Result<Vec<OsString>, WinError>) -> Result<Vec<OsString>, WinError> {
+    match streams {
+        Err(err) if err.hresult() != hresult_from_win32(ERROR_FILE_NOT_FOUND)
+            && err.hresult() != hresult_from_win32(ERROR_PATH_NOT_FOUND) => Ok(vec![OsString::new()]),
+        streams => streams,
+    }
+}
+
+impl<'a> AmsiSession<'a> {
+    /// Scans a file, using its path as the content name.
+    ///
+    /// The file is read into memory, files larger than `FILE_SIZE_LIMIT` fail with an `InvalidData` I/O error
+    /// without being scanned. See `scan_file_with_limit` for another limit.
+    ///
+    /// ## Parameters
+    /// * **path** - path to the file that should be scanned.
+    pub fn scan_file<P: AsRef<Path>>(&self, path: P) -> Result<AmsiResult, ScanError> {
+        self.scan_file_with_limit(path, FILE_SIZE_LIMIT)
+    }
+
+    /// Scans a file, failing with an `InvalidData` I/O error if it is larger than `max_size` bytes. See `scan_file`.
+    ///
+    /// ## Parameters
+    /// * **path** - path to the file that should be scanned.
+    /// * **max_size** - size limit, in bytes.
+    pub fn scan_file_with_limit<P: AsRef<Path>>(&self, path: P, max_size: u64) -> Result<AmsiResult, ScanError> {
+        let path = path.as_ref();
+        let data = read_file(path, max_size)?;
+        self.scan_buffer(path, &data)
+    }
+
+    /// Scans the content of an open file handle, such as a file that is still being written by a downloader.
+    ///
+    /// The whole file is read from the beginning, through a handle of its own that `ReOpenFile` opens for the same
+    /// file. The file pointer of `handle` isn't moved, so its owner can keep using it, even while the file is read.
+    /// Like with `scan_file`, files larger than `FILE_SIZE_LIMIT` fail with an `InvalidData` I/O error without being
+    /// scanned.
+    ///
+    /// Reopening the file fails with `ERROR_SHARING_VIOLATION` if `handle` was opened without sharing read access
+    /// (`FILE_SHARE_READ`).
+    ///
+    /// ## Safety
+    /// * `handle` must be a valid file handle, as long as this function runs.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **handle** - handle to the file that should be scanned, e.g. from `AsRawHandle::as_raw_handle()`.
+    pub unsafe fn scan_file_handle(&self, content_name: &str, handle: *mut c_void) -> Result<AmsiResult, ScanError> {
+        let data = read_handle(handle, FILE_SIZE_LIMIT)?;
+        self.scan_buffer(content_name, &data)
+    }
+
+    /// Scans every data stream of a file, including NTFS alternate data streams.
+    ///
+    /// Content hidden in an alternate data stream is missed when only the file itself is read. This function
+    /// enumerates the streams of the file and scans each of them separately: the main stream is scanned under the
+    /// path of the file, alternate streams under `"<path>:<stream name>"`, which is also what the returned paths are.
+    /// Like with `scan_file`, streams larger than `FILE_SIZE_LIMIT` fail with an `InvalidData` I/O error without being
+    /// scanned.
+    ///
+    /// If the streams can't be enumerated (e.g. the volume isn't NTFS), only the main stream is scanned. If the file
+    /// doesn't exist, a single entry with the path of the file and the error is returned.
+    ///
+    /// ## Parameters
+    /// * **path** - path to the file that should be scanned.
+    pub fn scan_file_with_ads<P: AsRef<Path>>(&self, path: P) -> Vec<(PathBuf, Result<AmsiResult, ScanError>)> {
+        let path = path.as_ref();
+
+        let streams = match streams_or_main(list_streams(path)) {
+            Ok(streams) => streams,
+            Err(err) => return vec![(path.to_path_buf(), Err(err.into()))],
+        };
+
+        streams.into_iter()
+            .map(|stream| {
+                let mut name = path.as_os_str().to_owned();
+                if !stream.is_empty() {
+                    name.push(":");
+                    name.push(stream);
+                }
+                let name = PathBuf::from(name);
+
+                let res = read_file(&name, FILE_SIZE_LIMIT)
+                    .map_err(ScanError::from)
+                    .and_then(|data| self.scan_buffer(&name, &data));
+                (name, res)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,92 @@
+use std::fmt;
+
+/// A payload that is about to be scanned, as seen by the filters of a `FilterChain`.
+#[derive(Debug, Clone, Copy)]
+pub struct ScanRequest<'r> {
+    content_name: &'r str,
+    data: &'r [u8],
+}
+
+impl<'r> ScanRequest<'r> {
+    pub(crate) fn new(content_name: &'r str, data: &'r [u8]) -> ScanRequest<'r> {
+        ScanRequest{
+            content_name,
+            data,
+        }
+    }
+
+    /// Returns the content name the payload is going to be scanned under.
+    pub fn content_name(&self) -> &'r str {
+        self.content_name
+    }
+
+    /// Returns the payload. Strings are presented as their UTF-8 bytes.
+    pub fn data(&self) -> &'r [u8] {
+        self.data
+    }
+}
+
+/// The outcome of a single filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterDecision {
+    /// Report the payload as malware, without scanning it.
+    Block,
+    /// Report the payload as clean, without scanning it.
+    Allow,
+    /// Let the next filter decide, or scan the payload if this was the last filter.
+    Continue,
+}
+
+type Filter = Box<dyn Fn(&ScanRequest) -> FilterDecision + Send + Sync>;
+
+/// An ordered list of cheap, local checks that run before a payload is handed to AMSI.
+///
+/// Filters run in the order they were added, the first filter that doesn't return `FilterDecision::Continue`
+/// decides the outcome. See `AmsiContext::set_filter_chain`.
+#[derive(Default)]
+pub struct FilterChain {
+    filters: Vec<Filter>,
+}
+
+impl FilterChain {
+    /// Creates an empty chain, which lets every payload through to AMSI.
+    pub fn new() -> FilterChain {
+        FilterChain{
+            filters: Vec::new(),
+        }
+    }
+
+    /// Appends a filter to the end of the chain.
+    pub fn push<F>(&mut self, filter: F) -> &mut FilterChain
+        where F: Fn(&ScanRequest) -> FilterDecision + Send + Sync + 'static
+    {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    /// Runs the filters against a request.
+    pub fn evaluate(&self, request: &ScanRequest) -> FilterDecision {
+        self.filters.iter()
+            .map(|filter| filter(request))
+            .find(|decision| *decision != FilterDecision::Continue)
+            .unwrap_or(FilterDecision::Continue)
+    }
+
+    /// Returns `true` if the chain has no filters.
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Returns the amount of filters in the chain.
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+}
+
+impl fmt::Debug for FilterChain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FilterChain")
+            .field("filters", &self.filters.len())
+            .finish()
+    }
+}
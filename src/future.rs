@@ -0,0 +1,117 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use super::{AmsiContext, AmsiResult, ContentName, FILE_SIZE_LIMIT, IntoContentName, ScanError};
+use super::file::read_file;
+
+#[derive(Default)]
+struct Shared {
+    result: Option<Result<AmsiResult, ScanError>>,
+    waker: Option<Waker>,
+}
+
+/// A scan that runs on a thread of its own, created by `AmsiContext::scan_string_async` and friends.
+///
+/// The future resolves to the result of the scan. It doesn't depend on any particular runtime, so it can be awaited on
+/// any executor without blocking its threads. Dropping the future doesn't stop the scan, only its result is discarded.
+#[must_use = "the result of the scan is only available through the future"]
+pub struct ScanFuture {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl std::fmt::Debug for ScanFuture {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let done = self.shared.lock().map(|shared| shared.result.is_some()).unwrap_or(true);
+        f.debug_struct("ScanFuture")
+            .field("done", &done)
+            .finish()
+    }
+}
+
+impl ScanFuture {
+    /// Runs `scan` on a new thread.
+    fn spawn<F>(scan: F) -> ScanFuture
+        where F: FnOnce() -> Result<AmsiResult, ScanError> + Send + 'static
+    {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+
+        let thread_shared = shared.clone();
+        let spawned = std::thread::Builder::new().name("amsi-async-scan".into()).spawn(move || {
+            let result = scan();
+            let mut shared = thread_shared.lock().unwrap_or_else(|e| e.into_inner());
+            shared.result = Some(result);
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+        });
+        if let Err(err) = spawned {
+            shared.lock().unwrap_or_else(|e| e.into_inner()).result = Some(Err(err.into()));
+        }
+
+        ScanFuture{
+            shared,
+        }
+    }
+}
+
+impl Future for ScanFuture {
+    type Output = Result<AmsiResult, ScanError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock().unwrap_or_else(|e| e.into_inner());
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+impl AmsiContext {
+    /// Scans a string on a background thread, see `scan_string`.
+    ///
+    /// Scans block until the antimalware service responds, which can take tens of milliseconds. The returned future
+    /// can be awaited from async code instead, without stalling the executor.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID
+    /// * **data** - Content that should be scanned.
+    pub fn scan_string_async<N: IntoContentName, S: Into<String>>(&self, content_name: N, data: S) -> ScanFuture {
+        let ctx = self.clone();
+        let content_name = ContentName::new(content_name);
+        let data = data.into();
+        ScanFuture::spawn(move || ctx.scan_string(&content_name, &data))
+    }
+
+    /// Scans a buffer on a background thread, see `scan_buffer` and `scan_string_async`.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **data** - payload that should be scanned.
+    pub fn scan_buffer_async<N: IntoContentName, D: Into<Vec<u8>>>(&self, content_name: N, data: D) -> ScanFuture {
+        let ctx = self.clone();
+        let content_name = ContentName::new(content_name);
+        let data = data.into();
+        ScanFuture::spawn(move || ctx.scan_buffer(&content_name, &data))
+    }
+
+    /// Reads and scans a file on a background thread, using its path as the content name, see `scan_string_async`.
+    ///
+    /// Like `AmsiSession::scan_file`, files larger than `FILE_SIZE_LIMIT` fail with an `InvalidData` I/O error.
+    ///
+    /// ## Parameters
+    /// * **path** - path to the file that should be scanned.
+    pub fn scan_file_async<P: AsRef<Path>>(&self, path: P) -> ScanFuture {
+        let ctx = self.clone();
+        let path: PathBuf = path.as_ref().to_owned();
+        ScanFuture::spawn(move || {
+            let data = read_file(&path, FILE_SIZE_LIMIT)?;
+            ctx.scan_buffer(&path, &data)
+        })
+    }
+}
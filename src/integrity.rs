@@ -0,0 +1,329 @@
+//! Detecting tampering with `amsi.dll` in the current process.
+//!
+//! The usual way to bypass AMSI from inside a process is to patch the first instructions of `AmsiScanBuffer` so it
+//! returns a clean result (or an error) without asking the provider, or to load a different `amsi.dll` altogether.
+//! `check` compares the `amsi.dll` mapped into the process with its file on disk and reports what doesn't match, so
+//! that a host can refuse to run scripts when their scans can't be trusted:
+//!
+//! ```no_run
+//! extern crate amsi;
+//!
+//! let report = amsi::integrity::check().unwrap();
+//! if report.is_tampered() {
+//!     panic!("AMSI was tampered with: {:?}", report.findings());
+//! }
+//! ```
+//!
+//! The check runs inside the process it inspects, so code that already controls the process can also fool it. It
+//! catches the common in-memory bypasses, it is not a defense against an attacker who expects it.
+
+use std::path::{Path, PathBuf};
+
+use super::{DWORD, is_wow64};
+use super::sys::{GetModuleHandleW, GetProcAddress, HMODULE, amsi_dll};
+
+/// The functions whose exports and prologues are checked.
+const FUNCTIONS: [&str; 5] = ["AmsiInitialize", "AmsiOpenSession", "AmsiScanBuffer", "AmsiScanString", "AmsiCloseSession"];
+/// The number of bytes of each function compared with the file, enough to cover a patched jump or early return.
+const PROLOGUE_LEN: usize = 16;
+/// The longest path Windows supports, in UTF-16 units.
+const PATH_LEN: usize = 32 * 1024;
+
+const IMAGE_DIRECTORY_ENTRY_EXPORT: usize = 0;
+const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+const IMAGE_REL_BASED_HIGHLOW: u16 = 3;
+const IMAGE_REL_BASED_DIR64: u16 = 10;
+
+#[link(name="kernel32")]
+extern "system" {
+    fn GetModuleFileNameW(module: HMODULE, file_name: *mut u16, size: DWORD) -> DWORD;
+    fn GetSystemDirectoryW(buffer: *mut u16, size: DWORD) -> DWORD;
+    fn GetSystemWow64DirectoryW(buffer: *mut u16, size: DWORD) -> DWORD;
+}
+
+/// A sign of tampering found by `check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    /// `amsi.dll` was loaded from somewhere other than the system directory.
+    UnexpectedPath(PathBuf),
+    /// The function isn't exported by the loaded module.
+    MissingExport(&'static str),
+    /// The address of the function lies outside of the mapped module, its export was redirected to other code.
+    ExportOutsideImage(&'static str),
+    /// The address of the function differs from the export table of the file on disk.
+    ExportModified(&'static str),
+    /// The first bytes of the function differ from the file on disk, it was patched in memory.
+    PrologueModified(&'static str),
+}
+
+/// The result of `check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntegrityReport {
+    module_path: Option<PathBuf>,
+    findings: Vec<Finding>,
+}
+
+impl IntegrityReport {
+    /// Returns `true` if `amsi.dll` is loaded in the process. Nothing was checked if it isn't.
+    ///
+    /// Without the `dynamic` feature, `amsi.dll` is loaded along with the process. With it, `amsi.dll` is loaded
+    /// when the first context is created.
+    pub fn is_loaded(&self) -> bool {
+        self.module_path.is_some()
+    }
+
+    /// Returns the path `amsi.dll` was loaded from.
+    pub fn module_path(&self) -> Option<&Path> {
+        self.module_path.as_deref()
+    }
+
+    /// Returns the signs of tampering that were found, empty if none were.
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    /// Returns `true` if any sign of tampering was found.
+    pub fn is_tampered(&self) -> bool {
+        !self.findings.is_empty()
+    }
+}
+
+/// A PE image, either the contents of its file or the module mapped by the loader.
+struct PeImage<'a> {
+    data: &'a [u8],
+    /// Whether `data` is laid out like in memory (RVAs are offsets) or like on disk.
+    mapped: bool,
+    size_of_image: u32,
+    directories: usize,
+    directory_count: u32,
+    sections: usize,
+    section_count: u16,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+impl<'a> PeImage<'a> {
+    fn parse(data: &'a [u8], mapped: bool) -> Option<PeImage<'a>> {
+        let nt = u32_at(data, 0x3c)? as usize;
+        if data.get(nt..nt + 4)? != b"PE\0\0" {
+            return None;
+        }
+        let section_count = u16_at(data, nt + 6)?;
+        let optional = nt + 24;
+        let optional_size = u16_at(data, nt + 20)? as usize;
+
+        let directories = match u16_at(data, optional)? {
+            // PE32
+            0x10b => optional + 96,
+            // PE32+
+            0x20b => optional + 112,
+            _ => return None,
+        };
+
+        Some(PeImage{
+            data,
+            mapped,
+            size_of_image: u32_at(data, optional + 56)?,
+            directories,
+            directory_count: u32_at(data, directories - 4)?,
+            sections: optional + optional_size,
+            section_count,
+        })
+    }
+
+    /// Returns the RVA and size of a data directory.
+    fn directory(&self, index: usize) -> Option<(u32, u32)> {
+        if index as u32 >= self.directory_count {
+            return None;
+        }
+        let entry = self.directories + index * 8;
+        Some((u32_at(self.data, entry)?, u32_at(self.data, entry + 4)?))
+    }
+
+    /// Converts an RVA to an offset into `data`.
+    fn offset(&self, rva: u32) -> Option<usize> {
+        if self.mapped {
+            return Some(rva as usize);
+        }
+        (0..self.section_count as usize)
+            .map(|i| self.sections + i * 40)
+            .find_map(|section| {
+                let virtual_address = u32_at(self.data, section + 12)?;
+                let raw_size = u32_at(self.data, section + 16)?;
+                let raw_offset = u32_at(self.data, section + 20)?;
+                if rva >= virtual_address && rva - virtual_address < raw_size {
+                    Some((raw_offset + rva - virtual_address) as usize)
+                } else {
+                    None
+                }
+            })
+    }
+
+    fn bytes(&self, rva: u32, len: usize) -> Option<&'a [u8]> {
+        let offset = self.offset(rva)?;
+        self.data.get(offset..offset + len)
+    }
+
+    /// Returns the RVA of an exported function, from the export table.
+    fn export(&self, name: &str) -> Option<u32> {
+        let (rva, _) = self.directory(IMAGE_DIRECTORY_ENTRY_EXPORT)?;
+        let dir = self.offset(rva)?;
+        let name_count = u32_at(self.data, dir + 24)?;
+        let functions = self.offset(u32_at(self.data, dir + 28)?)?;
+        let names = self.offset(u32_at(self.data, dir + 32)?)?;
+        let ordinals = self.offset(u32_at(self.data, dir + 36)?)?;
+
+        (0..name_count as usize).find_map(|i| {
+            let name_offset = self.offset(u32_at(self.data, names + i * 4)?)?;
+            let export_name = self.data.get(name_offset..name_offset + name.len() + 1)?;
+            if &export_name[..name.len()] != name.as_bytes() || export_name[name.len()] != 0 {
+                return None;
+            }
+            let ordinal = u16_at(self.data, ordinals + i * 2)? as usize;
+            u32_at(self.data, functions + ordinal * 4)
+        })
+    }
+
+    /// Returns the RVAs and sizes of the addresses the loader relocates.
+    fn relocations(&self) -> Vec<(u32, usize)> {
+        let mut relocations = Vec::new();
+        let (rva, size) = match self.directory(IMAGE_DIRECTORY_ENTRY_BASERELOC) {
+            Some(dir) => dir,
+            None => return relocations,
+        };
+        let table = match self.bytes(rva, size as usize) {
+            Some(table) => table,
+            None => return relocations,
+        };
+
+        let mut block = 0;
+        while let (Some(page), Some(block_size)) = (u32_at(table, block), u32_at(table, block + 4)) {
+            if block_size < 8 {
+                break;
+            }
+            for entry in (block + 8..block + block_size as usize).step_by(2) {
+                let entry = match u16_at(table, entry) {
+                    Some(entry) => entry,
+                    None => break,
+                };
+                let len = match entry >> 12 {
+                    IMAGE_REL_BASED_HIGHLOW => 4,
+                    IMAGE_REL_BASED_DIR64 => 8,
+                    _ => continue,
+                };
+                relocations.push((page + u32::from(entry & 0xfff), len));
+            }
+            block += block_size as usize;
+        }
+        relocations
+    }
+}
+
+/// Returns a path from a UTF-16 buffer filled by a Windows function, `None` if it failed.
+fn wide_path(buf: &[u16], len: DWORD) -> Option<PathBuf> {
+    if len == 0 || len as usize >= buf.len() {
+        None
+    } else {
+        Some(PathBuf::from(String::from_utf16_lossy(&buf[..len as usize])))
+    }
+}
+
+/// Returns `true` if `path` is `amsi.dll` in the system directory (or its WOW64 counterpart).
+fn is_system_path(path: &Path) -> bool {
+    let mut buf = vec![0u16; PATH_LEN];
+    let mut directories = Vec::new();
+    let len = unsafe { GetSystemDirectoryW(buf.as_mut_ptr(), buf.len() as DWORD) };
+    directories.extend(wide_path(&buf, len));
+    if is_wow64() {
+        let len = unsafe { GetSystemWow64DirectoryW(buf.as_mut_ptr(), buf.len() as DWORD) };
+        directories.extend(wide_path(&buf, len));
+    }
+
+    // paths on Windows are case-insensitive.
+    let path = path.to_string_lossy().to_lowercase();
+    directories.iter().any(|dir| path == dir.join("amsi.dll").to_string_lossy().to_lowercase())
+}
+
+/// Checks the `amsi.dll` loaded in the current process for signs of tampering.
+///
+/// The checks are:
+/// * `amsi.dll` has to be loaded from the system directory.
+/// * the exports of the AMSI functions have to point into the mapped module, at the addresses the export table of
+///   the file on disk names.
+/// * the first bytes of each function have to match the file on disk. Bytes the loader relocates are skipped.
+///
+/// If `amsi.dll` isn't loaded, nothing is checked and the report says so. Fails if the file of the module can't be
+/// read or either copy isn't a valid PE image.
+pub fn check() -> std::io::Result<IntegrityReport> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "amsi.dll is not a valid PE image");
+
+    let module_name = amsi_dll();
+    let module = unsafe { GetModuleHandleW(module_name.as_ptr()) };
+    if module.is_null() {
+        return Ok(IntegrityReport{
+            module_path: None,
+            findings: Vec::new(),
+        });
+    }
+
+    let mut buf = vec![0u16; PATH_LEN];
+    let len = unsafe { GetModuleFileNameW(module, buf.as_mut_ptr(), buf.len() as DWORD) };
+    let module_path = wide_path(&buf, len).ok_or_else(std::io::Error::last_os_error)?;
+
+    let mut findings = Vec::new();
+    if !is_system_path(&module_path) {
+        findings.push(Finding::UnexpectedPath(module_path.clone()));
+    }
+
+    let file = std::fs::read(&module_path)?;
+    let disk = PeImage::parse(&file, false).ok_or_else(invalid)?;
+    // the headers are mapped at the base of the module, they tell how large the whole image is.
+    let headers = unsafe { std::slice::from_raw_parts(module, 0x1000) };
+    let size_of_image = PeImage::parse(headers, true).ok_or_else(invalid)?.size_of_image;
+    let memory = PeImage::parse(unsafe { std::slice::from_raw_parts(module, size_of_image as usize) }, true)
+        .ok_or_else(invalid)?;
+    let relocations = disk.relocations();
+
+    for &function in &FUNCTIONS {
+        let name: Vec<u8> = function.bytes().chain(std::iter::once(0)).collect();
+        let address = unsafe { GetProcAddress(module, name.as_ptr()) };
+        if address.is_null() {
+            findings.push(Finding::MissingExport(function));
+            continue;
+        }
+
+        let offset = (address as usize).wrapping_sub(module as usize);
+        if offset >= memory.size_of_image as usize {
+            findings.push(Finding::ExportOutsideImage(function));
+            continue;
+        }
+        let rva = offset as u32;
+        if disk.export(function) != Some(rva) {
+            findings.push(Finding::ExportModified(function));
+            continue;
+        }
+
+        let (expected, actual) = match (disk.bytes(rva, PROLOGUE_LEN), memory.bytes(rva, PROLOGUE_LEN)) {
+            (Some(expected), Some(actual)) => (expected, actual),
+            _ => continue,
+        };
+        let relocated = |i: usize| {
+            let byte = rva + i as u32;
+            relocations.iter().any(|&(start, len)| byte >= start && ((byte - start) as usize) < len)
+        };
+        if (0..PROLOGUE_LEN).any(|i| expected[i] != actual[i] && !relocated(i)) {
+            findings.push(Finding::PrologueModified(function));
+        }
+    }
+
+    Ok(IntegrityReport{
+        module_path: Some(module_path),
+        findings,
+    })
+}
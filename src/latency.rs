@@ -0,0 +1,74 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use super::{AmsiResult, AmsiSession, ContentName, IntoContentName, ScanError};
+
+/// The outcome of `AmsiSession::scan_or_defer`.
+#[derive(Debug)]
+pub enum LatencyScan {
+    /// The scan finished within the budget.
+    Completed(Result<AmsiResult, ScanError>),
+    /// The scan didn't finish within the budget and is still running, its result is passed to the callback.
+    Deferred,
+}
+
+enum State {
+    Pending,
+    Done(Result<AmsiResult, ScanError>),
+    Deferred,
+}
+
+impl<'a> AmsiSession<'a> {
+    /// Scans a buffer, waiting at most `budget` for the result.
+    ///
+    /// The scan runs on a background thread with the context of this session, like `AmsiContext::scan_buffer`, but
+    /// outside of the session, since the session may be closed while the scan is still running. If it finishes within
+    /// `budget`, its result is returned as `LatencyScan::Completed`. Otherwise `LatencyScan::Deferred` is returned
+    /// right away and `on_complete` is called with the result once the scan finishes. `on_complete` is only called
+    /// for deferred scans.
+    ///
+    /// ## Security
+    /// A deferred scan has no verdict yet. Callers that let the content through in that case (e.g. to not block a page
+    /// render) act on content that may turn out to be malicious, and have to be able to revoke or undo the action from
+    /// `on_complete`. Callers that can't should block deferred content, or use `scan_buffer` instead.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **data** - payload that should be scanned.
+    /// * **budget** - how long to wait for the result.
+    /// * **on_complete** - called with the result of a deferred scan.
+    pub fn scan_or_defer<N, F>(&self, content_name: N, data: &[u8], budget: Duration, on_complete: F) -> LatencyScan
+    where N: IntoContentName, F: FnOnce(Result<AmsiResult, ScanError>) + Send + 'static {
+        let ctx = self.ctx.clone();
+        let content_name = ContentName::new(content_name);
+        let data = data.to_vec();
+        let state = Arc::new((Mutex::new(State::Pending), Condvar::new()));
+
+        let thread_state = state.clone();
+        let spawned = std::thread::Builder::new().name("amsi-deferred-scan".into()).spawn(move || {
+            let result = ctx.scan_buffer(&content_name, &data);
+
+            let (lock, cvar) = &*thread_state;
+            let mut state = lock.lock().unwrap_or_else(|e| e.into_inner());
+            if let State::Deferred = *state {
+                drop(state);
+                on_complete(result);
+            } else {
+                *state = State::Done(result);
+                cvar.notify_one();
+            }
+        });
+        if let Err(err) = spawned {
+            return LatencyScan::Completed(Err(err.into()));
+        }
+
+        let (lock, cvar) = &*state;
+        let state = lock.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut state, _) = cvar.wait_timeout_while(state, budget, |s| matches!(s, State::Pending))
+            .unwrap_or_else(|e| e.into_inner());
+        match std::mem::replace(&mut *state, State::Deferred) {
+            State::Done(result) => LatencyScan::Completed(result),
+            _ => LatencyScan::Deferred,
+        }
+    }
+}
@@ -16,38 +16,168 @@
 //!
 //! ## Note
 //! This crate only works with Windows 10, or Windows Server 2016 and above due to the API it wraps.
+//!
+//! By default `amsi.dll` is linked at build time, so binaries using this crate fail to start on older versions of
+//! Windows. With the `dynamic` feature, `amsi.dll` is loaded when the first context is created instead, and
+//! `AmsiContext::new` fails with `ERROR_MOD_NOT_FOUND` where it is missing.
+//!
+//! Linking `amsi.dll` requires its import library (`amsi.lib`, from the Windows SDK). The `raw-dylib` feature links
+//! it without an import library, e.g. for `x86_64-pc-windows-gnu` or build machines without the SDK. It has no effect
+//! together with `dynamic`, which doesn't link `amsi.dll` at all.
+//!
+//! With the `tracelogging` feature, every scan is reported to ETW through a TraceLogging provider, see
+//! `TRACELOGGING_PROVIDER`, so that telemetry pipelines can pick up the scans of the application.
+//!
+//! With the `prometheus` feature, `ScanMetrics` can be served over HTTP in the Prometheus text format, see
+//! `ScanMetrics::serve_prometheus`.
+//!
+//! With the `defender` feature, `defender_versions` returns the engine and definition versions of Windows Defender,
+//! for recording along with scan results.
+
+#![allow(non_camel_case_types, clippy::upper_case_acronyms)]
+
+use std::io::{BufRead, Read};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 #[cfg(test)]
 mod tests;
+mod audit;
+mod availability;
+pub mod batch;
+mod builder;
+mod cache;
+mod cancel;
+mod clipboard;
+mod clock;
+mod com;
+mod confidence;
+mod definitions;
+#[cfg(feature = "defender")]
+mod defender;
+#[cfg(feature = "dynamic")]
+mod dynamic;
+pub mod etw;
+mod eventlog;
+mod events;
+mod file;
+mod filter;
+mod future;
+pub mod integrity;
+mod latency;
+mod metrics;
+mod name;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod notify;
+mod owned;
+mod policy;
+mod pool;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+pub mod provider;
+mod providers;
+mod ratelimit;
+mod recovery;
+mod registry;
+mod report;
+mod rescan;
+mod retry;
+mod scannable;
+mod self_test;
+mod sha256;
+mod stream;
+mod timeout;
+#[cfg(feature = "tracelogging")]
+mod tracelog;
+pub mod sys;
+mod wide;
+mod wow64;
 
-type HRESULT = u32;
-type LPCWSTR = *const u16;
-type HAMSICONTEXT = *const u8;
-type HAMSISESSION = *const u8;
-type DWORD = u32;
-type AMSI_RESULT = u32;
+pub use audit::{AuditRecord, AuditStore, LogAuditStore};
+pub use availability::{Availability, is_available};
+pub use builder::{AmsiContextBuilder, DEFAULT_CHUNK_SIZE};
+pub use cache::CachedSession;
+pub use cancel::CancellationToken;
+pub use clipboard::ClipboardScan;
+pub use clock::{Clock, MockClock, SystemClock};
+pub use com::{Antimalware, ComApartment, ComScan, Guid};
+pub use confidence::{ScanConfidence, SkipReason};
+#[cfg(feature = "defender")]
+pub use defender::{DefenderVersions, defender_versions};
+pub use definitions::DefinitionsToken;
+pub use eventlog::EventLogAuditStore;
+pub use events::{ScanEvent, ScanEvents};
+pub use filter::{FilterChain, FilterDecision, ScanRequest};
+pub use future::ScanFuture;
+pub use latency::LatencyScan;
+pub use metrics::{MetricsSnapshot, ScanMetrics};
+pub use name::{AppName, ContentName, IntoContentName};
+pub use owned::OwnedAmsiSession;
+pub use policy::{Verdict, VerdictPolicy};
+pub use pool::{AmsiSessionPool, PooledSession};
+pub use providers::{ProviderInfo, providers};
+pub use ratelimit::RateLimitMode;
+pub use recovery::RecoveryEvent;
+pub use report::{GroupedReport, ReportGroup, ScanReportBuilder};
+pub use rescan::RescanQueue;
+pub use retry::RetryPolicy;
+pub use scannable::{FromReader, Scannable};
+pub use self_test::{SelfTest, self_test};
+pub use stream::ScanAttributes;
+#[cfg(feature = "tracelogging")]
+pub use tracelog::TRACELOGGING_PROVIDER;
+pub use wow64::is_wow64;
 
-#[link(name="amsi")]
-extern "system" {
-    fn AmsiInitialize(name: LPCWSTR, context: &mut HAMSICONTEXT) -> HRESULT;
-    fn AmsiUninitialize(content: HAMSICONTEXT);
-    fn AmsiScanString(context: HAMSICONTEXT, string: LPCWSTR, content_name: LPCWSTR, session: HAMSISESSION, result: &mut AMSI_RESULT) -> HRESULT;
-    fn AmsiScanBuffer(context: HAMSICONTEXT, buffer: *const u8, length: usize, content_name: LPCWSTR, session: HAMSISESSION, result: &mut AMSI_RESULT) -> HRESULT;
-    fn AmsiOpenSession(context: HAMSICONTEXT, session: &mut HAMSISESSION) -> HRESULT;
-    fn AmsiCloseSession(context: HAMSICONTEXT, session: HAMSISESSION);
-}
+use sys::{AMSI_RESULT_CLEAN, AMSI_RESULT_DETECTED, DWORD, HAMSICONTEXT, HAMSISESSION, HRESULT, LPCWSTR, ULONG};
+use sys::{AmsiCloseSession, AmsiInitialize, AmsiOpenSession, AmsiScanBuffer, AmsiScanString, AmsiUninitialize};
+use sys::{GetLastError, hresult_from_win32};
+
+/// The result of scans, `Result<T, ScanError>` unless another error type is given.
+pub type Result<T, E = ScanError> = std::result::Result<T, E>;
+
+/// The size, in bytes, up to which `AmsiSession::scan_slices` coalesces short slices into one fragment.
+pub const SMALL_SCAN_THRESHOLD: usize = 4096;
+
+/// The size limit of `AmsiSession::scan_file`, in bytes.
+pub const FILE_SIZE_LIMIT: u64 = 256 * 1024 * 1024;
 
 #[link(name="kernel32")]
 extern "system" {
-    fn GetLastError() -> DWORD;
+    fn FormatMessageW(flags: DWORD, source: *const u8, message_id: DWORD, language_id: DWORD, buffer: *mut u16, size: DWORD, arguments: *const u8) -> DWORD;
 }
 
+const E_FILE_NOT_FOUND: HRESULT = 0x8007_0002;
+const E_PATH_NOT_FOUND: HRESULT = 0x8007_0003;
+const E_ACCESSDENIED: HRESULT = 0x8007_0005;
+const E_OUTOFMEMORY: HRESULT = 0x8007_000e;
+const E_INVALIDARG: HRESULT = 0x8007_0057;
+const RPC_E_SERVER_UNAVAILABLE: HRESULT = 0x8007_06ba;
+const E_TIMEOUT: HRESULT = 0x8007_05b4;
+const RPC_E_SERVER_TOO_BUSY: HRESULT = 0x8007_06bb;
+const RPC_E_CALL_FAILED: HRESULT = 0x8007_06be;
+const RPC_E_DISCONNECTED: HRESULT = 0x8001_0108;
+pub(crate) const E_NOT_VALID_STATE: HRESULT = 0x8007_139f;
+const REGDB_E_CLASSNOTREG: HRESULT = 0x8004_0154;
+const CO_E_NOTINITIALIZED: HRESULT = 0x8004_01f0;
+
+const FORMAT_MESSAGE_IGNORE_INSERTS: DWORD = 0x200;
+const FORMAT_MESSAGE_FROM_SYSTEM: DWORD = 0x1000;
+
 /// Represents a Windows Error
+///
+/// The error keeps the code it was created from: a Windows error code for `from_code` and `new`, an `HRESULT` for
+/// `from_hresult`. `hresult()` returns either as an `HRESULT`.
+///
+/// Errors returned by this crate also name the call that failed and, for scans, the content name of the payload.
 #[derive(Debug)]
 pub struct WinError {
     code: DWORD,
+    operation: Option<&'static str>,
+    content_name: Option<String>,
 }
 
+#[allow(clippy::new_without_default)]
 impl WinError {
     /// Creates a new `WinError`. This function will actually call `GetLastError()`.
     pub fn new() -> WinError {
@@ -60,40 +190,428 @@ impl WinError {
     pub fn from_code(code: DWORD) -> WinError {
         WinError{
             code,
+            operation: None,
+            content_name: None,
         }
     }
 
     /// Creates a new `WinError` from the specified `HRESULT` code.
     pub fn from_hresult(res: HRESULT) -> WinError {
-        Self::from_code(res & 0xffff)
+        Self::from_code(res)
+    }
+
+    /// Records the call that failed, e.g. `AmsiScanBuffer`.
+    pub(crate) fn during(mut self, operation: &'static str) -> WinError {
+        self.operation = Some(operation);
+        self
+    }
+
+    /// Records the content name of the payload the failed call concerned.
+    pub(crate) fn for_content(mut self, content_name: &str) -> WinError {
+        self.content_name = Some(content_name.to_owned());
+        self
+    }
+
+    /// Returns the name of the call that failed, e.g. `AmsiInitialize`, `AmsiOpenSession` or `AmsiScanBuffer`.
+    pub fn operation(&self) -> Option<&str> {
+        self.operation
+    }
+
+    /// Returns the content name of the payload, if the error occurred while scanning one.
+    pub fn content_name(&self) -> Option<&str> {
+        self.content_name.as_deref()
+    }
+
+    /// Returns the low word of the error code.
+    ///
+    /// This used to be the only code kept for `HRESULT`s, which made errors of different facilities
+    /// indistinguishable (`0x80070005` and `0x80040005` both became `0x5`).
+    #[deprecated(note = "loses the facility of HRESULTs, use `hresult()` instead")]
+    pub fn code(&self) -> DWORD {
+        if self.is_failure() {
+            self.code & 0xffff
+        } else {
+            self.code
+        }
+    }
+
+    /// Returns the error as an `HRESULT`. Windows error codes are converted like with `HRESULT_FROM_WIN32`, so
+    /// `ERROR_ACCESS_DENIED` is returned as `0x80070005`.
+    pub fn hresult(&self) -> HRESULT {
+        hresult_from_win32(self.code)
+    }
+
+    /// Returns the facility of the `HRESULT`, e.g. `7` (`FACILITY_WIN32`) for Windows error codes.
+    pub fn facility(&self) -> u16 {
+        ((self.hresult() >> 16) & 0x1fff) as u16
+    }
+
+    /// Returns `true` if the severity bit of the `HRESULT` is set, as it is for all errors of the AMSI API.
+    pub fn is_failure(&self) -> bool {
+        self.hresult() & 0x8000_0000 != 0
+    }
+
+    /// Returns the class of the error.
+    pub fn kind(&self) -> AmsiError {
+        match self.hresult() {
+            E_ACCESSDENIED => AmsiError::AccessDenied,
+            E_INVALIDARG => AmsiError::InvalidArg,
+            E_NOT_VALID_STATE | CO_E_NOTINITIALIZED => AmsiError::NotInitialized,
+            RPC_E_SERVER_UNAVAILABLE => AmsiError::RpcServerUnavailable,
+            hres => AmsiError::Other(hres),
+        }
+    }
+
+    /// Returns the description of the error from the system message table, `None` for unknown codes.
+    pub fn message(&self) -> Option<String> {
+        let mut buf = [0u16; 512];
+        let len = unsafe {
+            FormatMessageW(FORMAT_MESSAGE_FROM_SYSTEM | FORMAT_MESSAGE_IGNORE_INSERTS, std::ptr::null(), self.code, 0, buf.as_mut_ptr(), buf.len() as DWORD, std::ptr::null())
+        };
+        if len == 0 {
+            return None;
+        }
+        // system messages end with a line break.
+        Some(String::from_utf16_lossy(&buf[..len as usize]).trim_end().to_owned())
     }
 }
 
-/// A Context that can be used for scanning payloads.
+/// The class of a failure, for deciding how to handle it. See `WinError::kind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AmsiError {
+    /// An argument was rejected (`E_INVALIDARG`), which is a bug in the caller rather than a condition to handle.
+    InvalidArg,
+    /// AMSI or COM is not in a state to handle the call (`E_NOT_VALID_STATE` or `CO_E_NOTINITIALIZED`), e.g. a
+    /// context was used after it was uninitialized.
+    NotInitialized,
+    /// The antimalware service can't be reached (`RPC_S_SERVER_UNAVAILABLE` as an `HRESULT`), e.g. because it is restarting after a
+    /// definition update. Worth retrying.
+    RpcServerUnavailable,
+    /// Access was denied (`E_ACCESSDENIED`).
+    AccessDenied,
+    /// Any other failure, as an `HRESULT`.
+    Other(HRESULT),
+}
+
+impl AmsiError {
+    /// Returns `true` for failures that may go away on their own, and are worth retrying: besides
+    /// `RpcServerUnavailable`, the RPC errors of a service that is busy or went away during the call
+    /// (`RPC_S_SERVER_TOO_BUSY`, `RPC_S_CALL_FAILED` and `RPC_E_DISCONNECTED`).
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            AmsiError::RpcServerUnavailable => true,
+            AmsiError::Other(code) => code == RPC_E_SERVER_TOO_BUSY || code == RPC_E_CALL_FAILED || code == RPC_E_DISCONNECTED,
+            _ => false,
+        }
+    }
+}
+
+/// Writes the system description of the error followed by its code, e.g. `Access is denied. (0x5)`, preceded by the
+/// failed call and the content name if known: `AmsiScanBuffer failed for "script.ps1": ...`.
+impl std::fmt::Display for WinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.operation, self.content_name()) {
+            (Some(operation), Some(content_name)) => write!(f, "{} failed for {:?}: ", operation, content_name)?,
+            (Some(operation), None) => write!(f, "{} failed: ", operation)?,
+            (None, Some(content_name)) => write!(f, "failed for {:?}: ", content_name)?,
+            (None, None) => {},
+        }
+        match self.message() {
+            Some(message) => write!(f, "{} ({:#x})", message, self.code),
+            None => write!(f, "Windows error {:#x}", self.code),
+        }
+    }
+}
+
+impl std::error::Error for WinError {}
+
+/// Errors that may occur while scanning a payload.
 #[derive(Debug)]
+pub enum ScanError {
+    /// The native API failed.
+    Win(WinError),
+    /// The scan exceeded the rate limit of the context, see `AmsiContext::set_rate_limit`.
+    RateLimited,
+    /// Reading the payload failed.
+    Io(std::io::Error),
+    /// The scan failed because no provider is available for the bitness of this process.
+    ///
+    /// This happens to 32-bit processes on 64-bit Windows (see `is_wow64`) when the installed antimalware product
+    /// only registered a 64-bit provider. Using a 64-bit build of the application solves the problem.
+    Wow64Mismatch(WinError),
+    /// The provider didn't respond within the timeout of `scan_buffer_with_timeout`.
+    TimedOut(std::time::Duration),
+    /// The scan was cancelled through a `CancellationToken` before it was handed to the provider.
+    Cancelled,
+}
+
+impl std::fmt::Display for ScanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            ScanError::Win(ref err) => err.fmt(f),
+            ScanError::RateLimited => f.write_str("scan rejected by the rate limit"),
+            ScanError::Io(ref err) => write!(f, "reading the payload failed: {}", err),
+            ScanError::Wow64Mismatch(ref err) => write!(f, "no antimalware provider is available for 32-bit processes: {}", err),
+            ScanError::TimedOut(timeout) => write!(f, "scan timed out after {:?}", timeout),
+            ScanError::Cancelled => f.write_str("scan cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for ScanError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            ScanError::Win(ref err) | ScanError::Wow64Mismatch(ref err) => Some(err),
+            ScanError::Io(ref err) => Some(err),
+            ScanError::RateLimited | ScanError::TimedOut(_) | ScanError::Cancelled => None,
+        }
+    }
+}
+
+/// Converts the error to an I/O error of the matching kind, with the `WinError` as its inner error.
+impl From<WinError> for std::io::Error {
+    fn from(err: WinError) -> std::io::Error {
+        std::io::Error::new(io_error_kind(&err), err)
+    }
+}
+
+/// Converts the error to an I/O error. Read failures come out as the original I/O error, other errors are kept as the
+/// inner error.
+impl From<ScanError> for std::io::Error {
+    fn from(err: ScanError) -> std::io::Error {
+        match err {
+            ScanError::Io(err) => err,
+            ScanError::Win(err) => err.into(),
+            ScanError::Wow64Mismatch(ref win) => std::io::Error::new(io_error_kind(win), err),
+            ScanError::RateLimited | ScanError::Cancelled => std::io::Error::other(err),
+            ScanError::TimedOut(_) => std::io::Error::new(std::io::ErrorKind::TimedOut, err),
+        }
+    }
+}
+
+fn io_error_kind(err: &WinError) -> std::io::ErrorKind {
+    use std::io::ErrorKind;
+
+    match err.hresult() {
+        E_ACCESSDENIED => ErrorKind::PermissionDenied,
+        E_INVALIDARG => ErrorKind::InvalidInput,
+        E_OUTOFMEMORY => ErrorKind::OutOfMemory,
+        RPC_E_SERVER_UNAVAILABLE => ErrorKind::NotConnected,
+        E_FILE_NOT_FOUND | E_PATH_NOT_FOUND | REGDB_E_CLASSNOTREG => ErrorKind::NotFound,
+        E_TIMEOUT => ErrorKind::TimedOut,
+        _ => ErrorKind::Other,
+    }
+}
+
+impl From<WinError> for ScanError {
+    fn from(err: WinError) -> ScanError {
+        ScanError::Win(err)
+    }
+}
+
+impl From<std::io::Error> for ScanError {
+    fn from(err: std::io::Error) -> ScanError {
+        ScanError::Io(err)
+    }
+}
+
+/// A Context that can be used for scanning payloads.
+///
+/// Contexts are `Send` and `Sync`: a context (or its clones) can serve scans from any number of threads at once.
+///
+/// Cloning a context is cheap: clones share the underlying AMSI context, which is uninitialized when the last clone
+/// is dropped, along with the rate limit, the filter chain, the audit store and the cache of encoded content names.
+/// Settings changed on a clone later on (e.g. with `set_rate_limit`, `set_verdict_policy` or `reinitialize`) only apply
+/// to that clone. A context that recovers from a fatal failure (see `set_recovery`) does so for all clones that share
+/// its AMSI context though.
+#[derive(Clone)]
 pub struct AmsiContext {
+    handle: Arc<recovery::HandleSlot>,
+    app_name: AppName,
+    limiter: Arc<ratelimit::RateLimiter>,
+    names: Arc<name::NameCache>,
+    filters: Arc<FilterChain>,
+    policy: VerdictPolicy,
+    audit_store: Option<Arc<dyn AuditStore>>,
+    detection_store: Option<Arc<dyn AuditStore>>,
+    metrics: Option<Arc<ScanMetrics>>,
+    clock: Arc<dyn Clock>,
+    chunk_size: usize,
+    max_payload_size: Option<usize>,
+    retry_policy: RetryPolicy,
+    recovery: bool,
+    on_recovery: Option<recovery::RecoveryHandler>,
+}
+
+/// An initialized AMSI context, uninitialized on drop.
+#[derive(Debug)]
+struct ContextHandle {
     ctx: HAMSICONTEXT,
+    /// The session used by the context's own scans, or null.
+    default_session: HAMSISESSION,
+}
+
+impl ContextHandle {
+    fn new(app_name: &AppName, default_session: bool) -> Result<ContextHandle, WinError> {
+        let mut handle = ContextHandle{
+            ctx: AmsiContext::initialize(app_name.as_wide())?,
+            default_session: std::ptr::null(),
+        };
+        // on failure, dropping the handle releases the context.
+        if default_session {
+            handle.default_session = AmsiContext::open_raw_session(handle.ctx, &RetryPolicy::none(), &SystemClock)?;
+        }
+        Ok(handle)
+    }
+}
+
+impl Drop for ContextHandle {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.default_session.is_null() {
+                AmsiCloseSession(self.ctx, self.default_session);
+            }
+            AmsiUninitialize(self.ctx);
+        }
+    }
+}
+
+// AMSI contexts aren't bound to the thread that created them: all AMSI functions may be called concurrently with the
+// same context from any thread, and the provider is responsible for synchronizing its own state. The handle is only
+// released in `Drop`, which requires exclusive access.
+unsafe impl Send for ContextHandle {}
+unsafe impl Sync for ContextHandle {}
+
+/// The AMSI context and session a scan is handed to.
+#[derive(Clone, Copy)]
+struct ScanTarget {
+    ctx: HAMSICONTEXT,
+    session: HAMSISESSION,
+}
+
+impl ContextHandle {
+    /// Targets the default session of the context, or no session.
+    fn target(&self) -> ScanTarget {
+        ScanTarget{
+            ctx: self.ctx,
+            session: self.default_session,
+        }
+    }
+}
+
+impl std::fmt::Debug for AmsiContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let handle = self.handle.get();
+        f.debug_struct("AmsiContext")
+            .field("ctx", &handle.ctx)
+            .field("app_name", &self.app_name.as_str())
+            .field("limiter", &self.limiter)
+            .field("names", &self.names.len())
+            .field("filters", &self.filters)
+            .field("policy", &self.policy)
+            .field("audit_store", &self.audit_store.is_some())
+            .field("detection_store", &self.detection_store.is_some())
+            .field("metrics", &self.metrics)
+            .field("chunk_size", &self.chunk_size)
+            .field("max_payload_size", &self.max_payload_size)
+            .field("retry_policy", &self.retry_policy)
+            .field("recovery", &self.recovery)
+            .field("default_session", &!handle.default_session.is_null())
+            .finish()
+    }
 }
 
 /// Represents a scan session.
+///
+/// Sessions can be shared between threads: scans in the same session may run concurrently, the provider sees them as
+/// related content either way.
 #[derive(Debug)]
 pub struct AmsiSession<'a> {
     ctx: &'a AmsiContext,
+    /// The AMSI context the session was opened on, which stays alive until the session is closed even if `ctx` is
+    /// reinitialized in the meantime.
+    handle: Arc<ContextHandle>,
     session: HAMSISESSION,
+    cancellation: Option<CancellationToken>,
+}
+
+// the session handle is an opaque value that AMSI passes on to the provider to correlate scans, it has no thread
+// affinity and the same rules as for the context apply, see `ContextHandle`.
+unsafe impl<'a> Send for AmsiSession<'a> {}
+unsafe impl<'a> Sync for AmsiSession<'a> {}
+
+/// The classification of a scan result code, see `AmsiResult::kind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AmsiResultKind {
+    /// Known good, no detection found and the result is likely not going to change after a definition update.
+    Clean,
+    /// No detection found, but the result might change after a definition update.
+    NotDetected,
+    /// Blocked by an administrator policy (`0x4000` - `0x4fff`). Carries the offset of the code into that range
+    /// (`0` - `0xfff`), whose meaning is up to the policy.
+    BlockedByAdmin(u16),
+    /// Detected as malware (`0x8000` and above). Carries the offset of the code from `0x8000`, which providers use
+    /// to report how certain the detection is, saturating at `u16::MAX` for codes above `0x17fff`.
+    Detected(u16),
+    /// A code outside of the documented ranges.
+    ///
+    /// Such codes are only returned by nonstandard (or misbehaving) providers. Since nothing is known about their
+    /// meaning, they should be treated conservatively rather than as clean.
+    Unknown(u32),
+}
+
+impl AmsiResultKind {
+    fn classify(code: u32) -> AmsiResultKind {
+        match code {
+            0 => AmsiResultKind::Clean,
+            1 => AmsiResultKind::NotDetected,
+            0x4000..=0x4fff => AmsiResultKind::BlockedByAdmin((code - 0x4000) as u16),
+            0x8000..=0xffff_ffff => AmsiResultKind::Detected(std::cmp::min(code - 0x8000, u16::MAX as u32) as u16),
+            code => AmsiResultKind::Unknown(code),
+        }
+    }
+}
+
+/// How risky a detection is, as estimated by the provider. See `AmsiResult::risk_level`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RiskLevel {
+    /// `0x8000` - `0x9fff`.
+    Low,
+    /// `0xa000` - `0xbfff`.
+    Medium,
+    /// `0xc000` - `0xdfff`.
+    High,
+    /// `0xe000` and above.
+    Critical,
 }
 
 /// Allows you to tell if a scan result is malicious or not.
 ///
 /// This structure is returned by scan functions.
-#[derive(Debug)]
+///
+/// Results compare, hash and order by their code alone: two scans with the same verdict are equal, regardless of
+/// their correlation IDs.
+#[derive(Clone, Copy)]
 pub struct AmsiResult {
     code: u32,
+    correlation_id: Option<u64>,
 }
 
 impl AmsiResult {
     pub(crate) fn new(code: u32) -> AmsiResult {
         AmsiResult{
             code,
+            correlation_id: None,
+        }
+    }
+
+    /// Creates the result of a scan that was handed to the provider, with a fresh correlation ID.
+    pub(crate) fn scanned(code: u32) -> AmsiResult {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+        AmsiResult{
+            code,
+            correlation_id: Some(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
         }
     }
 
@@ -112,6 +630,16 @@ impl AmsiResult {
         self.code == 1
     }
 
+    /// Returns `true` if the content should be scanned again later.
+    ///
+    /// Unlike a clean result, a "not detected" result only means that the current definitions don't flag the content.
+    /// Content that is kept around (quarantined uploads, stored attachments) should be re-scanned once the provider's
+    /// definitions are updated, which for Windows Defender happens several times a day; re-scanning daily is a
+    /// reasonable default when the update schedule is unknown.
+    pub fn should_rescan_later(&self) -> bool {
+        self.is_not_detected()
+    }
+
     pub fn is_blocked_by_admin(&self) -> bool {
         self.code >= 0x4000 && self.code <= 0x4fff
     }
@@ -119,6 +647,133 @@ impl AmsiResult {
     pub fn get_code(&self) -> u32 {
         self.code
     }
+
+    /// Returns the ID that identifies the scan this result came from.
+    ///
+    /// AMSI doesn't expose an identifier of its own, so the ID is generated by this crate: every scan that is handed to
+    /// the provider gets an ID that is unique within the process, and the same ID is passed to the audit store (see
+    /// `AuditRecord::correlation_id`). Results that were decided without calling the provider (filtered or empty
+    /// content) have no ID. Providers never see the ID, correlating it with their telemetry has to go through the
+    /// content name and the time of the scan.
+    pub fn correlation_id(&self) -> Option<u64> {
+        self.correlation_id
+    }
+
+    /// Returns the classification of the result code, for matching on instead of calling the `is_*` predicates.
+    ///
+    /// Unlike the `is_*` predicates, this doesn't let undocumented codes pass as "not malware": they are reported as
+    /// `AmsiResultKind::Unknown`.
+    pub fn kind(&self) -> AmsiResultKind {
+        AmsiResultKind::classify(self.code)
+    }
+
+    /// Returns the risk of a detection, `None` if the result isn't malware.
+    ///
+    /// AMSI leaves the meaning of codes above `0x8000` to providers, which may use them to report how risky a
+    /// detection is: the higher, the riskier. The range up to `0xffff` is split into four levels of equal size.
+    /// Providers that don't grade their detections, such as Microsoft Defender, always return `0x8000`, which is
+    /// reported as `Low` even though it is a definite detection. Only compare levels of the same provider.
+    pub fn risk_level(&self) -> Option<RiskLevel> {
+        match self.kind() {
+            AmsiResultKind::Detected(offset) => Some(match offset {
+                0..=0x1fff => RiskLevel::Low,
+                0x2000..=0x3fff => RiskLevel::Medium,
+                0x4000..=0x5fff => RiskLevel::High,
+                _ => RiskLevel::Critical,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns a message describing the result, suitable for showing to end users.
+    ///
+    /// Unlike the `Debug` output, the message contains no codes or other technical details.
+    pub fn user_message(&self) -> &'static str {
+        match self.kind() {
+            AmsiResultKind::Clean | AmsiResultKind::NotDetected => "No threats were found.",
+            AmsiResultKind::BlockedByAdmin(_) => "This content was blocked by your administrator.",
+            AmsiResultKind::Detected(_) => "This content was blocked because it may be harmful.",
+            AmsiResultKind::Unknown(_) => "This content could not be verified.",
+        }
+    }
+
+    /// Returns a coarse risk score between `0.0` and `1.0`, for comparing results across providers.
+    ///
+    /// | Result kind      | Score |
+    /// |------------------|-------|
+    /// | `Clean`          | `0.0` |
+    /// | `NotDetected`    | `0.1` |
+    /// | `Unknown`        | `0.5` |
+    /// | `BlockedByAdmin` | `0.9` |
+    /// | `Detected`       | `1.0` |
+    ///
+    /// The score only depends on the classification of the result, it is not a confidence reported by the provider.
+    pub fn normalized_score(&self) -> f32 {
+        match self.kind() {
+            AmsiResultKind::Clean => 0.0,
+            AmsiResultKind::NotDetected => 0.1,
+            AmsiResultKind::Unknown(_) => 0.5,
+            AmsiResultKind::BlockedByAdmin(_) => 0.9,
+            AmsiResultKind::Detected(_) => 1.0,
+        }
+    }
+
+    /// Returns whichever of the two results has the higher `normalized_score`, preferring `self` on ties.
+    pub(crate) fn most_severe(self, other: AmsiResult) -> AmsiResult {
+        if other.normalized_score() > self.normalized_score() {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+impl std::fmt::Debug for AmsiResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("AmsiResult")
+            .field("code", &format_args!("{:#x}", self.code))
+            .field("kind", &self.kind())
+            .finish()
+    }
+}
+
+impl PartialEq for AmsiResult {
+    fn eq(&self, other: &AmsiResult) -> bool {
+        self.code == other.code
+    }
+}
+
+impl Eq for AmsiResult {}
+
+impl std::hash::Hash for AmsiResult {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.code.hash(state);
+    }
+}
+
+impl PartialOrd for AmsiResult {
+    fn partial_cmp(&self, other: &AmsiResult) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for AmsiResult {
+    fn cmp(&self, other: &AmsiResult) -> std::cmp::Ordering {
+        self.code.cmp(&other.code)
+    }
+}
+
+/// Describes the result for logs, e.g. `Detected (code 0x8000)`. See `user_message` for end users.
+impl std::fmt::Display for AmsiResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.kind() {
+            AmsiResultKind::Clean => f.write_str("Clean"),
+            AmsiResultKind::NotDetected => f.write_str("Not detected"),
+            AmsiResultKind::BlockedByAdmin(_) => f.write_str("Blocked by administrator policy"),
+            AmsiResultKind::Detected(_) => write!(f, "Detected (code {:#x})", self.code),
+            AmsiResultKind::Unknown(_) => write!(f, "Unknown result (code {:#x})", self.code),
+        }
+    }
 }
 
 impl AmsiContext {
@@ -127,100 +782,573 @@ impl AmsiContext {
     /// ## Parameters
     /// * **app_name** - name, version or GUID of the application using AMSI API.
     pub fn new(app_name: &str) -> Result<AmsiContext, WinError> {
-        let name_utf16: Vec<u16> = app_name.encode_utf16().chain(std::iter::once(0)).collect();
+        Self::builder().app_name(app_name).build()
+    }
+
+    /// Returns a builder for contexts with non-default settings, such as a retry policy or a default session.
+    pub fn builder() -> AmsiContextBuilder {
+        AmsiContextBuilder::new()
+    }
+
+    pub(crate) fn with_name(app_name: AppName, default_session: bool) -> Result<AmsiContext, WinError> {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+
+        Ok(AmsiContext{
+            handle: Arc::new(recovery::HandleSlot::new(ContextHandle::new(&app_name, default_session)?)),
+            app_name,
+            limiter: Arc::new(ratelimit::RateLimiter::new(clock.clone())),
+            names: Arc::new(name::NameCache::default()),
+            filters: Arc::new(FilterChain::new()),
+            policy: VerdictPolicy::default(),
+            audit_store: None,
+            detection_store: None,
+            metrics: None,
+            clock,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_payload_size: None,
+            retry_policy: RetryPolicy::none(),
+            recovery: false,
+            on_recovery: None,
+        })
+    }
 
+    fn initialize(app_name: &[u16]) -> Result<HAMSICONTEXT, WinError> {
         unsafe {
             let mut amsi_ctx = std::mem::zeroed::<HAMSICONTEXT>();
 
-            let res = AmsiInitialize(name_utf16.as_ptr(), &mut amsi_ctx);
+            let res = AmsiInitialize(app_name.as_ptr(), &mut amsi_ctx);
 
             if res == 0 {
-                Ok(AmsiContext{
-                    ctx: amsi_ctx,
-                })
+                Ok(amsi_ctx)
             }
             else {
-                Err(WinError::from_hresult(res))
+                Err(WinError::from_hresult(res).during("AmsiInitialize"))
             }
         }
     }
 
+    /// Re-creates the underlying AMSI context in place, keeping the app name and all settings.
+    ///
+    /// This is a recovery path for long running services: when the antimalware service restarts, scans fail with
+    /// errors such as `RPC_S_SERVER_UNAVAILABLE` or `RPC_S_CALL_FAILED` on every session, and opening new sessions
+    /// doesn't help since they are bound to the stale context. Errors that concern a particular payload or session
+    /// (e.g. `E_INVALIDARG`) don't justify a reinitialization, dropping the session and creating a new one is enough.
+    ///
+    /// The new context is initialized before the old one is released, so if this function fails the context is left
+    /// as it was. The default session, if any, is reopened on the new context. Clones of this context keep using the
+    /// old context, which is released once the last of them is dropped. See `set_recovery` for reinitializing
+    /// automatically.
+    pub fn reinitialize(&mut self) -> Result<(), WinError> {
+        let handle = ContextHandle::new(&self.app_name, !self.handle.get().default_session.is_null())?;
+        self.handle = Arc::new(recovery::HandleSlot::new(handle));
+        Ok(())
+    }
+
     /// Creates a scan session from the current context.
-    pub fn create_session<'a>(&self) -> Result<AmsiSession, WinError> {
-        unsafe {
-            let mut session = std::mem::zeroed::<HAMSISESSION>();
-            let res = AmsiOpenSession(self.ctx, &mut session);
-            if res == 0 {
-                Ok(AmsiSession{
-                    ctx: self,
-                    session,
-                })
-            } else {
-                Err(WinError::from_hresult(res))
-            }
+    pub fn create_session(&self) -> Result<AmsiSession<'_>, WinError> {
+        let handle = self.handle.get();
+        Ok(AmsiSession{
+            ctx: self,
+            session: Self::open_raw_session(handle.ctx, &self.retry_policy, &*self.clock)?,
+            handle,
+            cancellation: None,
+        })
+    }
+
+    fn open_raw_session(ctx: HAMSICONTEXT, retry_policy: &RetryPolicy, clock: &dyn Clock) -> Result<HAMSISESSION, WinError> {
+        let mut session = std::ptr::null();
+        let res = retry_policy.run(clock, || unsafe {
+            AmsiOpenSession(ctx, &mut session)
+        });
+        if res == 0 {
+            Ok(session)
+        } else {
+            Err(WinError::from_hresult(res).during("AmsiOpenSession"))
         }
     }
-}
 
-impl<'a> AmsiSession<'a> {
-    /// Scans a string
+    /// Returns the application name the context was initialized with.
+    pub fn app_name(&self) -> &AppName {
+        &self.app_name
+    }
+
+    /// Returns the chunk size that chunked scans should use, in bytes, see `AmsiContextBuilder::chunk_size`.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Replaces the clock used for timing by this context, which is `SystemClock` by default.
     ///
-    /// This is usually useful for scanning scripts.
+    /// This is mostly useful for tests, where a `MockClock` makes timing-dependent behavior (such as the rate limit)
+    /// deterministic.
+    ///
+    /// The context stops sharing its rate limit with its clones, since they keep their clock.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.limiter = Arc::new(self.limiter.with_clock(clock.clone()));
+        self.clock = clock;
+    }
+
+    /// Limits the rate at which scans may be performed through this context.
+    ///
+    /// The limit is enforced by a lock-free token bucket shared by all sessions of the context. The bucket holds up
+    /// to `scans_per_sec` tokens, so short bursts are allowed as long as the average rate stays below the limit.
+    ///
+    /// When the bucket is empty, the behavior depends on `mode`:
+    /// * `RateLimitMode::Error` - scan functions fail with `ScanError::RateLimited`, without calling the provider.
+    /// * `RateLimitMode::Block` - scan functions wait until a token is available, and then scan as usual.
+    ///
+    /// The context gets a new, full bucket, so it stops sharing its rate limit with the clones made before this call.
+    /// Clones made afterwards share the new limit.
+    ///
+    /// ## Parameters
+    /// * **scans_per_sec** - maximum amount of scans per second, `0` removes the limit.
+    /// * **mode** - what scan functions should do when the limit is exceeded.
+    pub fn set_rate_limit(&mut self, scans_per_sec: u32, mode: RateLimitMode) {
+        let limiter = ratelimit::RateLimiter::new(self.clock.clone());
+        limiter.configure(scans_per_sec, mode);
+        self.limiter = Arc::new(limiter);
+    }
+
+    /// Sets the filters that run before every scan performed through this context.
+    ///
+    /// A filter that blocks a payload makes the scan report it as malware (`is_malware()` returns `true`), and a
+    /// filter that allows a payload makes the scan report it as clean. In both cases the provider isn't called.
+    pub fn set_filter_chain(&mut self, filters: FilterChain) {
+        self.filters = Arc::new(filters);
+    }
+
+    /// Sets how scans (and opening sessions) are retried after transient failures, see `RetryPolicy`. By default they
+    /// aren't.
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Replaces the policy used by `verdict` and `should_block`, see `VerdictPolicy` for the default.
+    pub fn set_verdict_policy(&mut self, policy: VerdictPolicy) {
+        self.policy = policy;
+    }
+
+    /// Sets the store that `AmsiSession::scan_buffer_audited` records scans to.
+    pub fn set_audit_store(&mut self, store: Box<dyn AuditStore>) {
+        self.audit_store = Some(Arc::from(store));
+    }
+
+    /// Sets a store that every scan of this context that is detected as malware is recorded to, whichever function
+    /// scanned it, e.g. an `EventLogAuditStore`.
+    ///
+    /// Unlike with `set_audit_store`, scans don't fail if the store does, errors of the store are ignored. Strings are
+    /// hashed as UTF-16, the way they are handed to the provider.
+    pub fn set_detection_store(&mut self, store: Box<dyn AuditStore>) {
+        self.detection_store = Some(Arc::from(store));
+    }
+
+    /// Counts the scans of this context in `metrics`, which may be shared with other contexts.
+    pub fn set_metrics(&mut self, metrics: Arc<ScanMetrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Returns the metrics set with `set_metrics`.
+    pub fn metrics(&self) -> Option<&Arc<ScanMetrics>> {
+        self.metrics.as_ref()
+    }
+
+    /// Returns the verdict of this context's policy for a scan result.
+    pub fn verdict(&self, result: &AmsiResult) -> Verdict {
+        self.policy.verdict(result)
+    }
+
+    /// Returns `true` if this context's policy blocks the scanned content.
+    ///
+    /// Content that is held for review (`Verdict::Review`) is blocked as well, until the review allows it.
+    pub fn should_block(&self, result: &AmsiResult) -> bool {
+        self.verdict(result) != Verdict::Allow
+    }
+
+    /// Scans a string without a session
+    ///
+    /// The scan is a one-off scan that the provider doesn't correlate with any other content, see `AmsiSession` for
+    /// scans that belong together. Otherwise this is the same as `AmsiSession::scan_string`. Contexts built with
+    /// `AmsiContextBuilder::default_session` scan in their default session instead.
     ///
     /// ## Parameters
     /// * **content_name** - File name, URL or unique script ID
     /// * **data** - Content that should be scanned.
-    pub fn scan_string(&self, content_name: &str, data: &str) -> Result<AmsiResult, WinError> {
-        let name : Vec<u16> = content_name.encode_utf16().chain(std::iter::once(0)).collect();
+    pub fn scan_string<N: IntoContentName>(&self, content_name: N, data: &str) -> Result<AmsiResult, ScanError> {
+        self.recovering(|handle| self.scan_string_in(handle.target(), &content_name, data))
+    }
+
+    /// Scans a buffer without a session
+    ///
+    /// The scan is a one-off scan that the provider doesn't correlate with any other content, see `AmsiSession` for
+    /// scans that belong together. No session is opened, so there is nothing to save by scanning small payloads
+    /// differently. Contexts built with `AmsiContextBuilder::default_session` scan in their default session instead.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **data** - payload that should be scanned.
+    pub fn scan_buffer<N: IntoContentName>(&self, content_name: N, data: &[u8]) -> Result<AmsiResult, ScanError> {
+        self.recovering(|handle| self.scan_buffer_in(handle.target(), &content_name, data))
+    }
+
+    fn scan_string_in(&self, target: ScanTarget, content_name: &dyn IntoContentName, data: &str) -> Result<AmsiResult, ScanError> {
+        if data.trim().is_empty() {
+            return Ok(SkipReason::Empty.result());
+        }
+
+        let display_name = content_name.to_str_lossy();
+        if let Some(reason) = self.before_scan(&display_name, data.as_bytes())? {
+            return Ok(reason.result());
+        }
+
         let content: Vec<u16> = data.encode_utf16().chain(std::iter::once(0)).collect();
+        self.scan_wide_unchecked(target, &self.names.encode(content_name, &display_name), &content, &display_name)
+    }
 
+    /// Calls `AmsiScanString` with a nul-terminated name and content, after the checks in `before_scan`.
+    fn scan_wide_unchecked(&self, target: ScanTarget, name: &[u16], content: &[u16], display_name: &str) -> Result<AmsiResult, ScanError> {
+        let started = self.clock.now();
         let mut result = 0;
 
-        let res = unsafe {
-            AmsiScanString(self.ctx.ctx, content.as_ptr(), name.as_ptr(), self.session, &mut result)
-        };
+        let res = self.retry_policy.run(&*self.clock, || unsafe {
+            AmsiScanString(target.ctx, content.as_ptr(), name.as_ptr(), target.session, &mut result)
+        });
+        self.scan_completed(name, (content.len() - 1) * 2, res, result, started);
 
         if res == 0 {
-            Ok(AmsiResult::new(result))
+            let result = AmsiResult::scanned(result);
+            if result.is_malware() && self.detection_store.is_some() {
+                let bytes: Vec<u8> = content[..content.len() - 1].iter().flat_map(|c| c.to_le_bytes()).collect();
+                self.record_detection(display_name, &bytes, &result);
+            }
+            Ok(result)
         }
         else {
-            Err(WinError::from_hresult(res))
+            Err(wow64::scan_error(WinError::from_hresult(res).during("AmsiScanString").for_content(display_name)))
         }
     }
 
+    fn scan_buffer_in(&self, target: ScanTarget, content_name: &dyn IntoContentName, data: &[u8]) -> Result<AmsiResult, ScanError> {
+        self.scan_buffer_confident_in(target, content_name, data).map(ScanConfidence::result)
+    }
+
+    fn scan_buffer_confident_in(&self, target: ScanTarget, content_name: &dyn IntoContentName, data: &[u8]) -> Result<ScanConfidence, ScanError> {
+        let display_name = content_name.to_str_lossy();
+        if let Some(reason) = self.before_scan(&display_name, data)? {
+            return Ok(ScanConfidence::Skipped(reason));
+        }
+
+        let length = buffer_length(data)?;
+        let name = self.names.encode(content_name, &display_name);
+        let started = self.clock.now();
+        let mut result = 0;
+
+        let hres = self.retry_policy.run(&*self.clock, || unsafe {
+            AmsiScanBuffer(target.ctx, data.as_ptr(), length, name.as_ptr(), target.session, &mut result)
+        });
+        self.scan_completed(&name, data.len(), hres, result, started);
+
+        if hres == 0 {
+            let result = AmsiResult::scanned(result);
+            if result.is_malware() {
+                self.record_detection(&display_name, data, &result);
+            }
+            Ok(ScanConfidence::Scanned(result))
+        } else {
+            Err(wow64::scan_error(WinError::from_hresult(hres).during("AmsiScanBuffer").for_content(&display_name)))
+        }
+    }
+
+    /// Reports a call into the provider to the metrics of the context, and with TraceLogging (see
+    /// `TRACELOGGING_PROVIDER`).
+    #[cfg_attr(not(feature = "tracelogging"), allow(unused_variables))]
+    fn scan_completed(&self, content_name: &[u16], size: usize, hres: HRESULT, result: u32, started: std::time::Instant) {
+        let duration = self.clock.now() - started;
+        if let Some(ref metrics) = self.metrics {
+            metrics.record(hres, result, duration);
+        }
+        #[cfg(feature = "tracelogging")]
+        tracelog::log_scan(self.app_name.as_wide(), content_name, size, hres, result, duration);
+    }
+
+    /// Records a scan that was detected as malware to the detection store, if there is one.
+    fn record_detection(&self, content_name: &str, data: &[u8], result: &AmsiResult) {
+        if let Some(ref store) = self.detection_store {
+            // a failing store must not turn a detection into a failed scan, which callers may treat more leniently.
+            let _ = store.record(&AuditRecord::new(content_name, data, result, self.verdict(result)));
+        }
+    }
+
+    /// Runs the checks that precede every scan.
+    ///
+    /// Returns the reason if the payload shouldn't be handed to the provider.
+    fn before_scan(&self, content_name: &str, data: &[u8]) -> Result<Option<SkipReason>, ScanError> {
+        match self.filters.evaluate(&ScanRequest::new(content_name, data)) {
+            FilterDecision::Continue => {},
+            decision => return Ok(Some(SkipReason::Filtered(decision))),
+        }
+
+        if let Some(max) = self.max_payload_size {
+            if data.len() > max {
+                let message = format!("payload of {} bytes exceeds the limit of {} bytes", data.len(), max);
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into());
+            }
+        }
+
+        self.limiter.acquire()?;
+        Ok(None)
+    }
+}
+
+impl<'a> AmsiSession<'a> {
+    fn target(&self) -> ScanTarget {
+        ScanTarget{
+            ctx: self.handle.ctx,
+            session: self.session,
+        }
+    }
+
+    fn scan_string_in(&self, content_name: &dyn IntoContentName, data: &str) -> Result<AmsiResult, ScanError> {
+        self.check_cancelled()?;
+        self.ctx.scan_string_in(self.target(), content_name, data)
+    }
+
+    fn scan_buffer_in(&self, content_name: &dyn IntoContentName, data: &[u8]) -> Result<AmsiResult, ScanError> {
+        self.check_cancelled()?;
+        self.ctx.scan_buffer_in(self.target(), content_name, data)
+    }
+
+    /// Scans a string
+    ///
+    /// This is usually useful for scanning scripts.
+    ///
+    /// Empty and whitespace-only strings can't carry any content, they are reported as clean without calling the
+    /// provider, so the result doesn't depend on which provider is installed.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID
+    /// * **data** - Content that should be scanned.
+    pub fn scan_string<N: IntoContentName>(&self, content_name: N, data: &str) -> Result<AmsiResult, ScanError> {
+        self.scan_string_in(&content_name, data)
+    }
+
     /// Scans a buffer
     ///
+    /// Providers use the content name for reputation lookups, so when content is re-scanned from a temporary or
+    /// quarantine location, passing the path it originally came from gives better verdicts than the path the bytes
+    /// were actually read from.
+    ///
     /// ## Parameters
     /// * **content_name** - File name, URL or unique script ID.
     /// * **data** - payload that should be scanned.
-    pub fn scan_buffer(&self, content_name: &str, data: &[u8]) -> Result<AmsiResult, WinError> {
-        let name: Vec<u16> = content_name.encode_utf16().chain(std::iter::once(0)).collect();
-        let mut result = 0;
+    pub fn scan_buffer<N: IntoContentName>(&self, content_name: N, data: &[u8]) -> Result<AmsiResult, ScanError> {
+        self.scan_buffer_in(&content_name, data)
+    }
 
-        let hres = unsafe {
-            AmsiScanBuffer(self.ctx.ctx, data.as_ptr(), data.len(), name.as_ptr(), self.session, &mut result)
-        };
+    /// Scans UTF-8 text as-is
+    ///
+    /// `scan_string` re-encodes the text to UTF-16, which is what script hosts such as PowerShell hand to AMSI. When
+    /// the source is UTF-8 (e.g. a script file as it is stored on disk), the provider might be better off seeing
+    /// the exact bytes, this function passes them unmodified through `AmsiScanBuffer`.
+    ///
+    /// The provider learns the language of the script from the content name, so it should carry the extension of
+    /// the script (e.g. `"install.ps1"` or `"macro.vbs"`).
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **text** - source text that should be scanned.
+    pub fn scan_utf8<N: IntoContentName>(&self, content_name: N, text: &str) -> Result<AmsiResult, ScanError> {
+        self.scan_buffer(content_name, text.as_bytes())
+    }
 
-        if hres == 0 {
-            Ok(AmsiResult::new(result))
-        } else {
-            Err(WinError::from_hresult(hres))
+    /// Scans the same buffer under several content names
+    ///
+    /// Providers may take the content name into account (e.g. the file extension), this allows probing how the
+    /// name affects the verdict. The buffer is passed to every scan as-is, without being copied.
+    ///
+    /// ## Parameters
+    /// * **names** - content names to present the payload under.
+    /// * **data** - payload that should be scanned.
+    pub fn scan_buffer_as_names(&self, names: &[&str], data: &[u8]) -> Vec<Result<AmsiResult, ScanError>> {
+        names.iter()
+            .map(|name| self.scan_buffer(name, data))
+            .collect()
+    }
+
+    /// Scans content that arrives in chunks, without concatenating them first
+    ///
+    /// Every chunk is scanned as a fragment of the same content within this session, so the provider is able to
+    /// correlate it with the chunks that came before it. The most severe result is returned, and scanning stops at
+    /// the first chunk that is detected as malware. Content without any chunks yields a clean result.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **chunks** - consecutive parts of the payload that should be scanned.
+    pub fn scan_chunks<'c, N: IntoContentName, I: IntoIterator<Item = &'c [u8]>>(&self, content_name: N, chunks: I) -> Result<AmsiResult, ScanError> {
+        let mut worst = AmsiResult::new(AMSI_RESULT_CLEAN);
+        for chunk in chunks {
+            worst = worst.most_severe(self.scan_buffer_in(&content_name, chunk)?);
+            if worst.is_malware() {
+                break;
+            }
+        }
+        Ok(worst)
+    }
+
+    /// Scans a payload that is made of several non-contiguous slices, e.g. headers, a body and decoded attachments
+    ///
+    /// The slices are scanned like with `scan_chunks`, as fragments of the same content within this session. Runs of
+    /// slices smaller than `SMALL_SCAN_THRESHOLD` are coalesced into one fragment of up to that size first, so that
+    /// short pieces (such as headers) reach the provider together instead of in calls of a few bytes each. Larger
+    /// slices are scanned in place, without copying. The most severe result is returned.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **slices** - consecutive parts of the payload that should be scanned.
+    pub fn scan_slices<N: IntoContentName>(&self, content_name: N, slices: &[&[u8]]) -> Result<AmsiResult, ScanError> {
+        let mut worst = AmsiResult::new(AMSI_RESULT_CLEAN);
+        let mut pending: Vec<u8> = Vec::new();
+
+        for slice in slices {
+            if pending.len() + slice.len() > SMALL_SCAN_THRESHOLD && !pending.is_empty() {
+                worst = worst.most_severe(self.scan_buffer_in(&content_name, &pending)?);
+                pending.clear();
+                if worst.is_malware() {
+                    return Ok(worst);
+                }
+            }
+
+            if slice.len() >= SMALL_SCAN_THRESHOLD {
+                worst = worst.most_severe(self.scan_buffer_in(&content_name, slice)?);
+                if worst.is_malware() {
+                    return Ok(worst);
+                }
+            } else {
+                pending.extend_from_slice(slice);
+            }
+        }
+
+        if !pending.is_empty() {
+            worst = worst.most_severe(self.scan_buffer_in(&content_name, &pending)?);
+        }
+        Ok(worst)
+    }
+
+    /// Scans content from a reader in chunks, without reading all of it into memory first
+    ///
+    /// The reader is read in chunks of `chunk_size` bytes (the last one may be shorter), which are scanned like with
+    /// `scan_chunks`: in this session, so the provider is able to correlate them, stopping at the first chunk that is
+    /// detected as malware. The most severe result is returned. Content that straddles two chunks is only seen in
+    /// pieces by the provider. To scan content of any size as a whole, see `Antimalware::scan_reader`.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **reader** - source of the payload, e.g. a socket or a decompression stream.
+    /// * **chunk_size** - size of the chunks, in bytes.
+    pub fn scan_reader<N: IntoContentName, R: Read>(&self, content_name: N, mut reader: R, chunk_size: usize) -> Result<AmsiResult, ScanError> {
+        let mut chunk = vec![0u8; std::cmp::max(chunk_size, 1)];
+        let mut worst = AmsiResult::new(AMSI_RESULT_CLEAN);
+        loop {
+            let mut filled = 0;
+            while filled < chunk.len() {
+                match reader.read(&mut chunk[filled..]) {
+                    Ok(0) => break,
+                    Ok(read) => filled += read,
+                    Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => {},
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            if filled == 0 {
+                return Ok(worst);
+            }
+
+            worst = worst.most_severe(self.scan_buffer_in(&content_name, &chunk[..filled])?);
+            if worst.is_malware() || filled < chunk.len() {
+                return Ok(worst);
+            }
+        }
+    }
+
+    /// Scans text line by line
+    ///
+    /// Every line is scanned as a fragment of the same content within this session, so the provider is able to
+    /// correlate it with the lines that came before it. The returned iterator yields a `LineVerdict` per line: the
+    /// (1-based) line number and the verdict for that line, which makes it easy to tell which line triggered a
+    /// detection, along with the most severe verdict of the lines so far, i.e. the verdict of the text up to that line.
+    ///
+    /// The iterator stops after the first error. Note that AMSI has no notion of a "final" fragment, the provider
+    /// considers the content complete when the session is closed.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **reader** - source of the text that should be scanned.
+    pub fn scan_lines<'s, N: IntoContentName, R: BufRead>(&'s self, content_name: N, reader: R) -> ScanLines<'s, 'a, R> {
+        ScanLines{
+            session: self,
+            content_name: ContentName::new(content_name),
+            lines: reader.lines().enumerate(),
+            worst: AmsiResult::new(AMSI_RESULT_CLEAN),
+            failed: false,
         }
     }
 }
 
-impl Drop for AmsiContext {
-    fn drop(&mut self) {
-        unsafe {
-            AmsiUninitialize(self.ctx);
+/// The verdict of a line, yielded by `ScanLines`.
+#[derive(Debug, Clone, Copy)]
+pub struct LineVerdict {
+    /// The line number, starting at 1.
+    pub line: usize,
+    /// The verdict for this line.
+    pub result: AmsiResult,
+    /// The most severe verdict of this line and the lines before it.
+    pub worst: AmsiResult,
+}
+
+/// Iterator over the verdicts of `AmsiSession::scan_lines`.
+pub struct ScanLines<'s, 'a: 's, R> {
+    session: &'s AmsiSession<'a>,
+    content_name: ContentName,
+    lines: std::iter::Enumerate<std::io::Lines<R>>,
+    worst: AmsiResult,
+    failed: bool,
+}
+
+impl<'s, 'a, R: BufRead> Iterator for ScanLines<'s, 'a, R> {
+    type Item = Result<LineVerdict, ScanError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
         }
+
+        let (idx, line) = self.lines.next()?;
+        let res = line.map_err(ScanError::from)
+            .and_then(|line| self.session.scan_string(&self.content_name, &line))
+            .map(|result| {
+                self.worst = self.worst.most_severe(result);
+                LineVerdict{
+                    line: idx + 1,
+                    result,
+                    worst: self.worst,
+                }
+            });
+
+        self.failed = res.is_err();
+        Some(res)
+    }
+}
+
+/// Returns the length of a payload as expected by `AmsiScanBuffer`, which takes a 32-bit length.
+fn buffer_length(data: &[u8]) -> Result<ULONG, ScanError> {
+    if data.len() > ULONG::MAX as usize {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "payloads of 4 GiB and larger can't be scanned as a buffer").into());
     }
+    Ok(data.len() as ULONG)
 }
 
 impl<'a> Drop for AmsiSession<'a> {
     fn drop(&mut self) {
         unsafe {
-            AmsiCloseSession(self.ctx.ctx, self.session);
+            AmsiCloseSession(self.handle.ctx, self.session);
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,131 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use super::{AmsiResultKind, HRESULT};
+
+/// The latency histogram has a bucket for every power of two microseconds up to about 34 seconds, and one for slower
+/// scans.
+const LATENCY_BUCKETS: usize = 27;
+
+/// Counts the scans performed through the contexts it is attached to, see `AmsiContext::set_metrics`.
+///
+/// Only scans that are handed to the provider are counted, once each, regardless of how often they were retried.
+/// Counting is lock-free, so a single instance can be shared by all contexts and threads of an application.
+#[derive(Debug, Default)]
+pub struct ScanMetrics {
+    scans: AtomicU64,
+    detections: AtomicU64,
+    blocked_by_admin: AtomicU64,
+    failures: AtomicU64,
+    total_latency_us: AtomicU64,
+    max_latency_us: AtomicU64,
+    latencies: [AtomicU64; LATENCY_BUCKETS],
+}
+
+/// The numbers of a `ScanMetrics` at some point in time, returned by `ScanMetrics::snapshot`.
+///
+/// Latency percentiles are estimated from a histogram with buckets of powers of two, so they are accurate to within a
+/// factor of two, and never above `max_latency`. They are zero while no scans were counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MetricsSnapshot {
+    /// Scans handed to the provider, including failed ones.
+    pub scans: u64,
+    /// Scans whose result `is_malware()`.
+    pub detections: u64,
+    /// Scans whose result `is_blocked_by_admin()`.
+    pub blocked_by_admin: u64,
+    /// Scans that failed.
+    pub failures: u64,
+    /// The time all scans took together.
+    pub total_latency: Duration,
+    /// The average time scans took, including retries.
+    pub mean_latency: Duration,
+    /// The time half of the scans took at most.
+    pub p50_latency: Duration,
+    /// The time 90% of the scans took at most.
+    pub p90_latency: Duration,
+    /// The time 99% of the scans took at most.
+    pub p99_latency: Duration,
+    /// The time the slowest scan took.
+    pub max_latency: Duration,
+}
+
+fn bucket(latency_us: u64) -> usize {
+    let bits = (64 - latency_us.saturating_sub(1).leading_zeros()) as usize;
+    std::cmp::min(bits, LATENCY_BUCKETS - 1)
+}
+
+impl ScanMetrics {
+    /// Creates metrics with all counters at zero.
+    pub fn new() -> ScanMetrics {
+        ScanMetrics::default()
+    }
+
+    pub(crate) fn record(&self, hres: HRESULT, result: u32, latency: Duration) {
+        self.scans.fetch_add(1, Ordering::Relaxed);
+        if hres != 0 {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        } else {
+            match AmsiResultKind::classify(result) {
+                AmsiResultKind::Detected(_) => { self.detections.fetch_add(1, Ordering::Relaxed); },
+                AmsiResultKind::BlockedByAdmin(_) => { self.blocked_by_admin.fetch_add(1, Ordering::Relaxed); },
+                _ => {},
+            }
+        }
+
+        let latency_us = std::cmp::min(latency.as_micros(), u64::MAX as u128) as u64;
+        self.total_latency_us.fetch_add(latency_us, Ordering::Relaxed);
+        self.max_latency_us.fetch_max(latency_us, Ordering::Relaxed);
+        self.latencies[bucket(latency_us)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the current numbers.
+    ///
+    /// Scans that are counted while the snapshot is taken may be included in some numbers but not in others.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let latencies: Vec<u64> = self.latencies.iter().map(|count| count.load(Ordering::Relaxed)).collect();
+        let counted: u64 = latencies.iter().sum();
+        let max_latency_us = self.max_latency_us.load(Ordering::Relaxed);
+        let total_latency_us = self.total_latency_us.load(Ordering::Relaxed);
+
+        // the upper bound of the bucket that holds the `p`th percentile.
+        let percentile = |p: u64| {
+            if counted == 0 {
+                return Duration::from_micros(0);
+            }
+            let rank = std::cmp::max((counted * p).div_ceil(100), 1);
+            let mut seen = 0;
+            for (index, &count) in latencies.iter().enumerate() {
+                seen += count;
+                if seen >= rank {
+                    let upper = if index == LATENCY_BUCKETS - 1 { max_latency_us } else { 1 << index };
+                    return Duration::from_micros(std::cmp::min(upper, max_latency_us));
+                }
+            }
+            Duration::from_micros(max_latency_us)
+        };
+
+        MetricsSnapshot{
+            scans: self.scans.load(Ordering::Relaxed),
+            detections: self.detections.load(Ordering::Relaxed),
+            blocked_by_admin: self.blocked_by_admin.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            total_latency: Duration::from_micros(total_latency_us),
+            mean_latency: Duration::from_micros(total_latency_us.checked_div(counted).unwrap_or(0)),
+            p50_latency: percentile(50),
+            p90_latency: percentile(90),
+            p99_latency: percentile(99),
+            max_latency: Duration::from_micros(max_latency_us),
+        }
+    }
+
+    /// Sets all counters back to zero.
+    pub fn reset(&self) {
+        for counter in [&self.scans, &self.detections, &self.blocked_by_admin, &self.failures, &self.total_latency_us, &self.max_latency_us].iter() {
+            counter.store(0, Ordering::Relaxed);
+        }
+        for count in &self.latencies {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+}
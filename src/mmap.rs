@@ -0,0 +1,136 @@
+use std::os::raw::c_void;
+use std::path::Path;
+
+use super::{AMSI_RESULT_CLEAN, AmsiResult, AmsiSession, DWORD, IntoContentName, LPCWSTR, ScanError, WinError};
+use super::sys::{BOOL, CloseHandle, GetFileSizeEx, HANDLE, INVALID_HANDLE_VALUE};
+
+const GENERIC_READ: DWORD = 0x8000_0000;
+const FILE_SHARE_READ: DWORD = 0x1;
+const OPEN_EXISTING: DWORD = 3;
+const FILE_ATTRIBUTE_NORMAL: DWORD = 0x80;
+const PAGE_READONLY: DWORD = 0x2;
+const FILE_MAP_READ: DWORD = 0x4;
+
+/// Views have to start at multiples of the allocation granularity, which is 64 KiB on all versions of Windows.
+const ALLOCATION_GRANULARITY: u64 = 64 * 1024;
+
+#[link(name="kernel32")]
+extern "system" {
+    fn CreateFileW(file_name: LPCWSTR, desired_access: DWORD, share_mode: DWORD, security_attributes: *mut c_void, creation_disposition: DWORD, flags_and_attributes: DWORD, template_file: HANDLE) -> HANDLE;
+    fn CreateFileMappingW(file: HANDLE, attributes: *mut c_void, protect: DWORD, maximum_size_high: DWORD, maximum_size_low: DWORD, name: LPCWSTR) -> HANDLE;
+    fn MapViewOfFile(mapping: HANDLE, desired_access: DWORD, offset_high: DWORD, offset_low: DWORD, bytes: usize) -> *mut c_void;
+    fn UnmapViewOfFile(base_address: *const c_void) -> BOOL;
+}
+
+/// A handle, closed on drop.
+struct OwnedHandle(HANDLE);
+
+impl Drop for OwnedHandle {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.0);
+        }
+    }
+}
+
+/// A mapped view of a file, unmapped on drop.
+struct View {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl View {
+    fn map(mapping: &OwnedHandle, offset: u64, len: usize) -> Result<View, WinError> {
+        let ptr = unsafe {
+            MapViewOfFile(mapping.0, FILE_MAP_READ, (offset >> 32) as DWORD, offset as DWORD, len)
+        };
+        if ptr.is_null() {
+            return Err(WinError::new().during("MapViewOfFile"));
+        }
+        Ok(View{
+            ptr,
+            len,
+        })
+    }
+
+    fn data(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(self.ptr as *const u8, self.len)
+        }
+    }
+}
+
+impl Drop for View {
+    fn drop(&mut self) {
+        unsafe {
+            UnmapViewOfFile(self.ptr);
+        }
+    }
+}
+
+impl<'a> AmsiSession<'a> {
+    /// Scans a file through a memory mapping, without reading it into memory. Requires the `mmap` feature.
+    ///
+    /// Files of up to `chunk_size` bytes are mapped and scanned as a whole. Larger files are mapped and scanned in
+    /// chunks of `chunk_size` bytes (rounded down to a multiple of 64 KiB), one view at a time, like with
+    /// `scan_chunks`: the most severe result is returned, and scanning stops at the first chunk that is detected as
+    /// malware. Content that straddles two chunks is only seen in pieces by the provider, though it can correlate
+    /// them within the session.
+    ///
+    /// The file is opened with `FILE_SHARE_READ`, so it can't be modified while it is scanned. The path is used as
+    /// the content name.
+    ///
+    /// ## Parameters
+    /// * **path** - path to the file that should be scanned.
+    /// * **chunk_size** - size of the largest view that is scanned at once, in bytes.
+    pub fn scan_file_mmap<P: AsRef<Path>>(&self, path: P, chunk_size: usize) -> Result<AmsiResult, ScanError> {
+        let path = path.as_ref();
+        let content_name = path.to_string_lossy().into_owned();
+        let path = path.to_wide();
+
+        let file = unsafe {
+            CreateFileW(path.as_ptr(), GENERIC_READ, FILE_SHARE_READ, std::ptr::null_mut(), OPEN_EXISTING, FILE_ATTRIBUTE_NORMAL, std::ptr::null_mut())
+        };
+        if file == INVALID_HANDLE_VALUE {
+            return Err(WinError::new().during("CreateFileW").for_content(&content_name).into());
+        }
+        let file = OwnedHandle(file);
+
+        let mut size = 0;
+        if unsafe { GetFileSizeEx(file.0, &mut size) } == 0 {
+            return Err(WinError::new().during("GetFileSizeEx").for_content(&content_name).into());
+        }
+        let size = size as u64;
+        // empty files can't be mapped.
+        if size == 0 {
+            return self.scan_buffer(&content_name, &[]);
+        }
+
+        let mapping = unsafe {
+            CreateFileMappingW(file.0, std::ptr::null_mut(), PAGE_READONLY, 0, 0, std::ptr::null())
+        };
+        if mapping.is_null() {
+            return Err(WinError::new().during("CreateFileMappingW").for_content(&content_name).into());
+        }
+        let mapping = OwnedHandle(mapping);
+
+        let chunk_size = if size <= chunk_size as u64 {
+            size
+        } else {
+            std::cmp::max(chunk_size as u64 / ALLOCATION_GRANULARITY, 1) * ALLOCATION_GRANULARITY
+        };
+
+        let mut worst = AmsiResult::new(AMSI_RESULT_CLEAN);
+        let mut offset = 0;
+        while offset < size {
+            let len = std::cmp::min(chunk_size, size - offset) as usize;
+            let view = View::map(&mapping, offset, len).map_err(|err| err.for_content(&content_name))?;
+            worst = worst.most_severe(self.scan_buffer(&content_name, view.data())?);
+            if worst.is_malware() {
+                break;
+            }
+            offset += len as u64;
+        }
+        Ok(worst)
+    }
+}
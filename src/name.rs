@@ -0,0 +1,197 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// The most names a `NameCache` holds, it is emptied when it is full.
+const NAME_CACHE_CAPACITY: usize = 256;
+
+/// A value that can be passed as the content name of a scan: a string, a path, or an `OsStr`.
+///
+/// AMSI takes content names as UTF-16. Paths and `OsStr`s are passed with their original UTF-16 data on Windows,
+/// so names that aren't valid Unicode (e.g. file names with unpaired surrogates) reach the provider unchanged. Filters,
+/// audit records and errors see the name as a string, with invalid sequences replaced.
+///
+/// The trait can be implemented for other types that make good content names, such as URLs. Names that are used for
+/// many scans can be encoded once up front with `ContentName`.
+pub trait IntoContentName {
+    /// Returns the name as a string, replacing invalid sequences with `U+FFFD`.
+    fn to_str_lossy(&self) -> Cow<'_, str>;
+
+    /// Returns the name as nul-terminated UTF-16, as passed to AMSI. Defaults to the encoding of `to_str_lossy`.
+    ///
+    /// Contexts cache the encoding of names whose `to_str_lossy` is borrowed, so that repeated scans under the same name
+    /// don't encode it again. Implementations should only override this for names that `to_str_lossy` can't
+    /// represent exactly.
+    fn to_wide(&self) -> Cow<'_, [u16]> {
+        match self.as_wide() {
+            Some(wide) => Cow::Borrowed(wide),
+            None => Cow::Owned(self.to_str_lossy().encode_utf16().chain(std::iter::once(0)).collect()),
+        }
+    }
+
+    /// Returns the name as nul-terminated UTF-16 if it is stored in that form, in which case it is passed to AMSI
+    /// without being encoded or cached. Defaults to `None`.
+    fn as_wide(&self) -> Option<&[u16]> {
+        None
+    }
+}
+
+impl<T: AsRef<OsStr> + ?Sized> IntoContentName for &T {
+    fn to_str_lossy(&self) -> Cow<'_, str> {
+        (*self).as_ref().to_string_lossy()
+    }
+
+    #[cfg(windows)]
+    fn to_wide(&self) -> Cow<'_, [u16]> {
+        use std::os::windows::ffi::OsStrExt;
+
+        Cow::Owned((*self).as_ref().encode_wide().chain(std::iter::once(0)).collect())
+    }
+}
+
+/// A content name that is encoded to UTF-16 once, for names that are used for many scans.
+///
+/// Cloning a `ContentName` doesn't copy the encoded name.
+///
+/// ```no_run
+/// extern crate amsi;
+///
+/// let name = amsi::ContentName::new("snippet.ps1");
+/// let ctx = amsi::AmsiContext::new("scripthost-1.0.0").unwrap();
+/// let session = ctx.create_session().unwrap();
+/// for snippet in &["Write-Host 'a'", "Write-Host 'b'"] {
+///     session.scan_string(&name, snippet).unwrap();
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ContentName {
+    name: String,
+    wide: Arc<[u16]>,
+}
+
+impl ContentName {
+    /// Encodes a content name, such as a string or a path.
+    pub fn new<N: IntoContentName>(name: N) -> ContentName {
+        ContentName{
+            name: name.to_str_lossy().into_owned(),
+            wide: Arc::from(&*name.to_wide()),
+        }
+    }
+
+    /// Returns the name as a string, with invalid sequences replaced.
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+}
+
+impl IntoContentName for &ContentName {
+    fn to_str_lossy(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.name)
+    }
+
+    fn as_wide(&self) -> Option<&[u16]> {
+        Some(&self.wide)
+    }
+}
+
+/// An application name (or GUID), as passed to `AmsiInitialize`, encoded to UTF-16 once.
+///
+/// Contexts keep their app name in this form, see `AmsiContext::app_name`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AppName {
+    name: String,
+    wide: Arc<[u16]>,
+}
+
+impl AppName {
+    /// Encodes the name and version (or GUID) of an application.
+    pub fn new(name: &str) -> AppName {
+        AppName{
+            name: name.to_owned(),
+            wide: name.encode_utf16().chain(std::iter::once(0)).collect::<Vec<u16>>().into(),
+        }
+    }
+
+    /// Returns the name as a string.
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the name as nul-terminated UTF-16.
+    pub fn as_wide(&self) -> &[u16] {
+        &self.wide
+    }
+}
+
+impl<'a> From<&'a str> for AppName {
+    fn from(name: &'a str) -> AppName {
+        AppName::new(name)
+    }
+}
+
+impl std::fmt::Display for AppName {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// A content name encoded by `NameCache::encode`.
+pub(crate) enum EncodedName<'n> {
+    Borrowed(&'n [u16]),
+    Shared(Arc<[u16]>),
+    Owned(Cow<'n, [u16]>),
+}
+
+impl<'n> Deref for EncodedName<'n> {
+    type Target = [u16];
+
+    fn deref(&self) -> &[u16] {
+        match *self {
+            EncodedName::Borrowed(name) => name,
+            EncodedName::Shared(ref name) => name,
+            EncodedName::Owned(ref name) => name,
+        }
+    }
+}
+
+/// Caches the UTF-16 encoding of content names, keyed by the name.
+///
+/// Only names that are valid Unicode (`to_str_lossy` returns a borrowed string) are cached, so names that differ only
+/// in invalid sequences can't share an entry.
+#[derive(Debug, Default)]
+pub(crate) struct NameCache {
+    names: Mutex<HashMap<String, Arc<[u16]>>>,
+}
+
+impl NameCache {
+    /// Returns the UTF-16 encoding of `content_name`, whose `to_str_lossy` is `display_name`.
+    // the name has to be a `Cow`, since only borrowed names are cached.
+    #[allow(clippy::ptr_arg)]
+    pub(crate) fn encode<'n>(&self, content_name: &'n dyn IntoContentName, display_name: &Cow<'_, str>) -> EncodedName<'n> {
+        if let Some(name) = content_name.as_wide() {
+            return EncodedName::Borrowed(name);
+        }
+        let key = match *display_name {
+            Cow::Borrowed(key) => key,
+            Cow::Owned(_) => return EncodedName::Owned(content_name.to_wide()),
+        };
+
+        let mut names = self.names.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(name) = names.get(key) {
+            return EncodedName::Shared(name.clone());
+        }
+        if names.len() >= NAME_CACHE_CAPACITY {
+            names.clear();
+        }
+        let name: Arc<[u16]> = Arc::from(&*content_name.to_wide());
+        names.insert(key.to_owned(), name.clone());
+        EncodedName::Shared(name)
+    }
+
+    /// Returns the amount of cached names.
+    pub(crate) fn len(&self) -> usize {
+        self.names.lock().unwrap_or_else(|err| err.into_inner()).len()
+    }
+}
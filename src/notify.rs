@@ -0,0 +1,62 @@
+use std::sync::OnceLock;
+
+use super::{AmsiContext, AmsiResult, ScanError, WinError, buffer_length, wow64};
+use super::sys::{AMSI_RESULT, ERROR_PROC_NOT_FOUND, GetModuleHandleW, GetProcAddress, HAMSICONTEXT, HRESULT, LPCWSTR, ULONG, amsi_dll};
+
+type AmsiNotifyOperationFn = unsafe extern "system" fn(context: HAMSICONTEXT, buffer: *const u8, length: ULONG, content_name: LPCWSTR, result: &mut AMSI_RESULT) -> HRESULT;
+
+/// Looks up `AmsiNotifyOperation`, which is only exported starting with Windows 10 1809.
+fn notify_operation_fn() -> Option<AmsiNotifyOperationFn> {
+    static NOTIFY_OPERATION: OnceLock<Option<AmsiNotifyOperationFn>> = OnceLock::new();
+
+    *NOTIFY_OPERATION.get_or_init(|| unsafe {
+        // amsi.dll is loaded by now, since a context exists.
+        let amsi = amsi_dll();
+        let module = GetModuleHandleW(amsi.as_ptr());
+        if module.is_null() {
+            return None;
+        }
+
+        let proc_addr = GetProcAddress(module, b"AmsiNotifyOperation\0".as_ptr());
+        if proc_addr.is_null() {
+            return None;
+        }
+        Some(std::mem::transmute::<*const u8, AmsiNotifyOperationFn>(proc_addr))
+    })
+}
+
+impl AmsiContext {
+    /// Notifies the provider of an operation, such as a registry write for persistence or the creation of a WMI
+    /// consumer, and returns its verdict on it.
+    ///
+    /// Unlike a scan, this doesn't hand over content that is about to run, but a description of something that is
+    /// about to happen. The filter chain and rate limit of the context apply as they do to scans.
+    ///
+    /// `AmsiNotifyOperation` is only available starting with Windows 10 1809. On older builds this fails with
+    /// `ERROR_PROC_NOT_FOUND`, which callers can treat as "no objection".
+    ///
+    /// ## Parameters
+    /// * **buffer** - description of the operation.
+    /// * **operation_name** - name of the operation, passed to the provider as the content name.
+    pub fn notify_operation(&self, buffer: &[u8], operation_name: &str) -> Result<AmsiResult, ScanError> {
+        let notify_operation = notify_operation_fn().ok_or_else(|| WinError::from_code(ERROR_PROC_NOT_FOUND).during("AmsiNotifyOperation"))?;
+
+        if let Some(reason) = self.before_scan(operation_name, buffer)? {
+            return Ok(reason.result());
+        }
+
+        let length = buffer_length(buffer)?;
+        let name: Vec<u16> = operation_name.encode_utf16().chain(std::iter::once(0)).collect();
+        let mut result = 0;
+
+        let hres = unsafe {
+            notify_operation(self.handle.get().ctx, buffer.as_ptr(), length, name.as_ptr(), &mut result)
+        };
+
+        if hres == 0 {
+            Ok(AmsiResult::scanned(result))
+        } else {
+            Err(wow64::scan_error(WinError::from_hresult(hres).during("AmsiNotifyOperation").for_content(operation_name)))
+        }
+    }
+}
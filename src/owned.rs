@@ -0,0 +1,48 @@
+use std::ops::Deref;
+use std::sync::Arc;
+
+use super::{AmsiContext, AmsiSession, CancellationToken, WinError};
+
+/// A scan session that keeps its context alive, created by `AmsiContext::create_owned_session`.
+///
+/// Unlike `AmsiSession`, it doesn't borrow the context, so it can be stored next to it in a struct or kept for the
+/// lifetime of the program. All scan functions of `AmsiSession` are available through `Deref`.
+#[derive(Debug)]
+pub struct OwnedAmsiSession {
+    // declared before `ctx`, so the session is closed before the context can be released.
+    session: AmsiSession<'static>,
+    ctx: Arc<AmsiContext>,
+}
+
+impl OwnedAmsiSession {
+    /// Returns the context the session belongs to.
+    pub fn context(&self) -> &Arc<AmsiContext> {
+        &self.ctx
+    }
+
+    /// Makes the scans of this session fail once `token` is cancelled, see `AmsiSession::set_cancellation_token`.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.session.set_cancellation_token(token);
+    }
+}
+
+impl Deref for OwnedAmsiSession {
+    type Target = AmsiSession<'static>;
+
+    fn deref(&self) -> &AmsiSession<'static> {
+        &self.session
+    }
+}
+
+impl AmsiContext {
+    /// Creates a scan session that holds a reference to the context, instead of borrowing it.
+    pub fn create_owned_session(self: &Arc<Self>) -> Result<OwnedAmsiSession, WinError> {
+        // the context lives at least as long as the `Arc` stored next to the session, and the session can't hand out
+        // its reference to the context, so it never outlives it.
+        let ctx: &'static AmsiContext = unsafe { &*(&**self as *const AmsiContext) };
+        Ok(OwnedAmsiSession{
+            session: ctx.create_session()?,
+            ctx: self.clone(),
+        })
+    }
+}
@@ -0,0 +1,61 @@
+use super::{AmsiResult, AmsiResultKind};
+
+/// What should happen to scanned content, as decided by a `VerdictPolicy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Verdict {
+    /// The content may be used.
+    Allow,
+    /// The content must not be used.
+    Block,
+    /// The verdict is left to a human (or another system): the content must not be used until it was reviewed.
+    Review,
+}
+
+/// Maps each kind of scan result to a `Verdict`.
+///
+/// The default policy allows clean and not detected content, blocks detected and admin-blocked content, and holds
+/// results with undocumented codes for review:
+///
+/// | Result kind      | Verdict  |
+/// |------------------|----------|
+/// | `Clean`          | `Allow`  |
+/// | `NotDetected`    | `Allow`  |
+/// | `BlockedByAdmin` | `Block`  |
+/// | `Detected`       | `Block`  |
+/// | `Unknown`        | `Review` |
+///
+/// A policy only affects the verdict helpers (`AmsiContext::verdict` and `AmsiContext::should_block`), the raw
+/// predicates of `AmsiResult` such as `is_malware()` always reflect the result code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VerdictPolicy {
+    pub clean: Verdict,
+    pub not_detected: Verdict,
+    pub blocked_by_admin: Verdict,
+    pub detected: Verdict,
+    pub unknown: Verdict,
+}
+
+impl VerdictPolicy {
+    /// Returns the verdict for a scan result.
+    pub fn verdict(&self, result: &AmsiResult) -> Verdict {
+        match result.kind() {
+            AmsiResultKind::Clean => self.clean,
+            AmsiResultKind::NotDetected => self.not_detected,
+            AmsiResultKind::BlockedByAdmin(_) => self.blocked_by_admin,
+            AmsiResultKind::Detected(_) => self.detected,
+            AmsiResultKind::Unknown(_) => self.unknown,
+        }
+    }
+}
+
+impl Default for VerdictPolicy {
+    fn default() -> VerdictPolicy {
+        VerdictPolicy{
+            clean: Verdict::Allow,
+            not_detected: Verdict::Allow,
+            blocked_by_admin: Verdict::Block,
+            detected: Verdict::Block,
+            unknown: Verdict::Review,
+        }
+    }
+}
@@ -0,0 +1,291 @@
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use super::{AmsiContext, AmsiResult, AmsiSession, CancellationToken, E_NOT_VALID_STATE, OwnedAmsiSession, ScanError};
+use super::WinError;
+
+/// A session of the pool, along with the amount of times it was handed out.
+#[derive(Debug)]
+struct Entry {
+    session: OwnedAmsiSession,
+    uses: u32,
+}
+
+#[derive(Debug)]
+struct State {
+    idle: Vec<Entry>,
+    /// Sessions that are idle or handed out.
+    open: usize,
+    /// Set by `shutdown`, sessions are no longer handed out.
+    shut_down: bool,
+}
+
+/// A fixed number of reusable sessions over one context, for services that scan from many threads.
+///
+/// Sessions are opened on demand, up to the size of the pool, and handed out as `PooledSession` guards that return
+/// them to the pool when dropped. Since the provider correlates all content of a session, sessions can be retired
+/// after a number of uses with `set_max_uses`, so unrelated content doesn't accumulate in one session forever.
+///
+/// ```no_run
+/// extern crate amsi;
+///
+/// use std::sync::Arc;
+///
+/// let ctx = Arc::new(amsi::AmsiContext::new("mailscanner-1.0.0").unwrap());
+/// let pool = Arc::new(amsi::AmsiSessionPool::new(ctx, 4));
+/// let session = pool.get().unwrap();
+/// session.scan_string("mail.eml", "Hello").unwrap();
+/// ```
+#[derive(Debug)]
+pub struct AmsiSessionPool {
+    ctx: Arc<AmsiContext>,
+    size: usize,
+    max_uses: u32,
+    state: Mutex<State>,
+    returned: Condvar,
+}
+
+impl AmsiSessionPool {
+    /// Creates a pool of up to `size` sessions (at least one) over `ctx`.
+    pub fn new(ctx: Arc<AmsiContext>, size: usize) -> AmsiSessionPool {
+        AmsiSessionPool{
+            ctx,
+            size: std::cmp::max(size, 1),
+            max_uses: 0,
+            state: Mutex::new(State{
+                idle: Vec::new(),
+                open: 0,
+                shut_down: false,
+            }),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// Closes sessions after they were handed out `max_uses` times, and opens new ones in their place. `0` (the
+    /// default) keeps sessions open for the lifetime of the pool.
+    pub fn set_max_uses(&mut self, max_uses: u32) {
+        self.max_uses = max_uses;
+    }
+
+    /// Returns the context of the pool.
+    pub fn context(&self) -> &Arc<AmsiContext> {
+        &self.ctx
+    }
+
+    /// Returns the maximum amount of sessions.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the amount of idle sessions.
+    pub fn idle(&self) -> usize {
+        self.lock().idle.len()
+    }
+
+    /// Returns the amount of open sessions, idle or handed out.
+    pub fn session_count(&self) -> usize {
+        self.lock().open
+    }
+
+    /// Hands out a session, waiting for one to be returned if all of them are in use.
+    ///
+    /// Fails with `E_NOT_VALID_STATE` once the pool is shut down, see `shutdown`.
+    pub fn get(&self) -> Result<PooledSession<'_>, WinError> {
+        let mut state = self.lock();
+        loop {
+            if state.shut_down {
+                return Err(WinError::from_hresult(E_NOT_VALID_STATE));
+            }
+            if let Some(entry) = state.idle.pop() {
+                return Ok(self.guard(entry));
+            }
+            if state.open < self.size {
+                return self.open(state);
+            }
+            state = self.returned.wait(state).unwrap_or_else(|err| err.into_inner());
+        }
+    }
+
+    /// Hands out a session if one is available without waiting.
+    pub fn try_get(&self) -> Option<Result<PooledSession<'_>, WinError>> {
+        let mut state = self.lock();
+        if state.shut_down {
+            return Some(Err(WinError::from_hresult(E_NOT_VALID_STATE)));
+        }
+        if let Some(entry) = state.idle.pop() {
+            return Some(Ok(self.guard(entry)));
+        }
+        if state.open < self.size {
+            return Some(self.open(state));
+        }
+        None
+    }
+
+    /// Stops handing out sessions, waits up to `timeout` for the sessions that are handed out to be returned, and
+    /// closes the idle sessions.
+    ///
+    /// Returns `true` if all sessions were returned in time, so that none are open anymore. Otherwise the remaining
+    /// sessions are closed as soon as their guards are dropped. Waiting threads and later calls to `get` and
+    /// `try_get` fail with `E_NOT_VALID_STATE`. The context is kept, since the pool only holds a reference to it.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        state.shut_down = true;
+        self.returned.notify_all();
+
+        while state.open > state.idle.len() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = self.returned.wait_timeout(state, deadline - now).unwrap_or_else(|err| err.into_inner()).0;
+        }
+
+        let idle = std::mem::take(&mut state.idle);
+        state.open -= idle.len();
+        let drained = state.open == 0;
+        drop(state);
+        // the sessions are closed outside of the lock.
+        drop(idle);
+        drained
+    }
+
+    /// Scans a batch of payloads with sessions of the pool, sending each result to `sender` as soon as it's available,
+    /// see `scan_stream_channel_cancellable`.
+    pub fn scan_stream_channel<N, D, I>(&self, items: I, sender: Sender<(String, Result<AmsiResult, ScanError>)>) -> Result<(), WinError>
+        where I: IntoIterator<Item = (N, D)>, N: Into<String>, D: AsRef<[u8]> + Sync
+    {
+        self.scan_stream_channel_cancellable(items, sender, &CancellationToken::new())
+    }
+
+    /// Scans a batch of payloads with sessions of the pool, sending each result to `sender` along with its content
+    /// name as soon as it's available, e.g. so that a UI can list results while a directory is still being scanned.
+    ///
+    /// The first session is handed out like with `get`, waiting for one to be returned if all of them are in use, and
+    /// scans on the calling thread. Every other session of the pool that is available without waiting scans on a thread
+    /// of its own. Results are sent in the order they complete, not in the order of `items`. Returns once every payload
+    /// was scanned, or the scan was aborted. Fails with `E_NOT_VALID_STATE` if the pool is shut down, in which case
+    /// nothing is scanned.
+    ///
+    /// If the receiver is dropped, `token` is cancelled and the remaining payloads aren't scanned. Payloads that weren't
+    /// scanned yet when `token` is cancelled otherwise are sent with `ScanError::Cancelled`.
+    ///
+    /// ## Parameters
+    /// * **items** - pairs of content name and payload.
+    /// * **sender** - channel the results are sent to.
+    /// * **token** - token that aborts the scan, cancelled by this function if the receiver is dropped.
+    pub fn scan_stream_channel_cancellable<N, D, I>(&self, items: I, sender: Sender<(String, Result<AmsiResult, ScanError>)>, token: &CancellationToken) -> Result<(), WinError>
+        where I: IntoIterator<Item = (N, D)>, N: Into<String>, D: AsRef<[u8]> + Sync
+    {
+        let items: Vec<(String, D)> = items.into_iter().map(|(name, data)| (name.into(), data)).collect();
+        let mut sessions = vec![self.get()?];
+        while sessions.len() < std::cmp::min(self.size, items.len()) {
+            match self.try_get() {
+                Some(Ok(session)) => sessions.push(session),
+                _ => break,
+            }
+        }
+
+        let next = AtomicUsize::new(0);
+        let scan = |session: &AmsiSession, sender: Sender<(String, Result<AmsiResult, ScanError>)>| {
+            while let Some((name, data)) = items.get(next.fetch_add(1, Ordering::Relaxed)) {
+                let result = match token.check() {
+                    Ok(()) => session.scan_buffer(name.as_str(), data.as_ref()),
+                    Err(err) => Err(err),
+                };
+                if sender.send((name.clone(), result)).is_err() {
+                    token.cancel();
+                    return;
+                }
+            }
+        };
+
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = sessions[1..].iter()
+                .map(|session| {
+                    let sender = sender.clone();
+                    scope.spawn(move || scan(session, sender))
+                })
+                .collect();
+            scan(&sessions[0], sender);
+            for worker in workers {
+                worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            }
+        });
+        Ok(())
+    }
+
+    /// Opens a new session, with the lock held by `state` released while AMSI opens it.
+    fn open(&self, mut state: MutexGuard<State>) -> Result<PooledSession<'_>, WinError> {
+        state.open += 1;
+        drop(state);
+
+        match self.ctx.create_owned_session() {
+            Ok(session) => Ok(self.guard(Entry{
+                session,
+                uses: 0,
+            })),
+            Err(err) => {
+                self.lock().open -= 1;
+                self.returned.notify_one();
+                Err(err)
+            }
+        }
+    }
+
+    fn guard(&self, entry: Entry) -> PooledSession<'_> {
+        PooledSession{
+            pool: self,
+            entry: Some(entry),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn put_back(&self, mut entry: Entry) {
+        entry.uses = entry.uses.saturating_add(1);
+        let retired = self.max_uses != 0 && entry.uses >= self.max_uses;
+
+        let mut state = self.lock();
+        if retired || state.shut_down {
+            state.open -= 1;
+        } else {
+            state.idle.push(entry);
+        }
+        drop(state);
+        // `shutdown` waits for every session, `get` for any.
+        self.returned.notify_all();
+        // a retired entry is dropped when this function returns, so its session is closed outside of the lock.
+    }
+}
+
+/// A session handed out by `AmsiSessionPool::get`, returned to the pool on drop.
+///
+/// All scan functions of `AmsiSession` are available through `Deref`.
+#[derive(Debug)]
+pub struct PooledSession<'p> {
+    pool: &'p AmsiSessionPool,
+    entry: Option<Entry>,
+}
+
+impl<'p> Deref for PooledSession<'p> {
+    type Target = AmsiSession<'static>;
+
+    fn deref(&self) -> &AmsiSession<'static> {
+        // only `None` while the guard is dropped.
+        &self.entry.as_ref().unwrap().session
+    }
+}
+
+impl<'p> Drop for PooledSession<'p> {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            self.pool.put_back(entry);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::{MetricsSnapshot, ScanMetrics};
+
+/// Escapes a label value of the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl MetricsSnapshot {
+    /// Writes the snapshot in the Prometheus text exposition format, with an `app` label of `app_name` on every
+    /// sample.
+    ///
+    /// The counters are `amsi_scans_total`, `amsi_detections_total`, `amsi_blocked_by_admin_total` and
+    /// `amsi_scan_failures_total`, the latency is the summary `amsi_scan_duration_seconds` with the quantiles `0.5`,
+    /// `0.9` and `0.99`.
+    pub fn write_prometheus<W: Write>(&self, writer: &mut W, app_name: &str) -> std::io::Result<()> {
+        let app = escape_label(app_name);
+        let counters = [
+            ("amsi_scans_total", "Scans handed to the antimalware provider.", self.scans),
+            ("amsi_detections_total", "Scans that detected malware.", self.detections),
+            ("amsi_blocked_by_admin_total", "Scans that were blocked by an administrator policy.", self.blocked_by_admin),
+            ("amsi_scan_failures_total", "Scans that failed.", self.failures),
+        ];
+        for &(name, help, value) in &counters {
+            writeln!(writer, "# HELP {} {}", name, help)?;
+            writeln!(writer, "# TYPE {} counter", name)?;
+            writeln!(writer, "{}{{app=\"{}\"}} {}", name, app, value)?;
+        }
+
+        writeln!(writer, "# HELP amsi_scan_duration_seconds Time scans took, including retries.")?;
+        writeln!(writer, "# TYPE amsi_scan_duration_seconds summary")?;
+        for &(quantile, value) in &[("0.5", self.p50_latency), ("0.9", self.p90_latency), ("0.99", self.p99_latency)] {
+            writeln!(writer, "amsi_scan_duration_seconds{{app=\"{}\",quantile=\"{}\"}} {}", app, quantile, value.as_secs_f64())?;
+        }
+        writeln!(writer, "amsi_scan_duration_seconds_sum{{app=\"{}\"}} {}", app, self.total_latency.as_secs_f64())?;
+        writeln!(writer, "amsi_scan_duration_seconds_count{{app=\"{}\"}} {}", app, self.scans)
+    }
+}
+
+impl ScanMetrics {
+    /// Serves the metrics over HTTP on `addr`, for Prometheus to scrape, see `MetricsSnapshot::write_prometheus`.
+    ///
+    /// Every request is answered with a fresh snapshot, whatever its path. Requests are handled one at a time on a
+    /// thread of its own, which runs until the process exits. Fails if `addr` can't be bound.
+    pub fn serve_prometheus<A: ToSocketAddrs>(self: Arc<Self>, addr: A, app_name: &str) -> std::io::Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(addr)?;
+        let app_name = app_name.to_owned();
+        std::thread::Builder::new().name("amsi-prometheus".into()).spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                // a client that doesn't finish its request would hold up every later scrape.
+                let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+                // a scrape is a single `GET` without a body, its headers end with an empty line.
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).map(|read| read > 0).unwrap_or(false) && !line.trim().is_empty() {
+                    line.clear();
+                }
+
+                let mut body = Vec::new();
+                if self.snapshot().write_prometheus(&mut body, &app_name).is_err() {
+                    continue;
+                }
+                let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len())
+                    .and_then(|_| stream.write_all(&body));
+            }
+        })
+    }
+}
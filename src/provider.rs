@@ -0,0 +1,735 @@
+//! Building blocks for antimalware providers, the other side of AMSI.
+//!
+//! A provider is an in-process COM server (a `cdylib`) that AMSI loads and hands every scan to. Implementing
+//! `Provider` and exporting it with `export_provider!` is all it takes to build one:
+//!
+//! ```ignore
+//! #[macro_use]
+//! extern crate amsi;
+//!
+//! use amsi::provider::{Guid, Provider, ProviderStream, ProviderVerdict};
+//!
+//! const CLSID: Guid = Guid::from_u128(0x0b3d4d52_9c0c_4cf6_a5d4_3f1c2e9a7b10);
+//!
+//! struct MyProvider;
+//!
+//! impl Provider for MyProvider {
+//!     fn display_name(&self) -> String {
+//!         "My Provider".to_owned()
+//!     }
+//!
+//!     fn scan(&self, stream: &ProviderStream) -> ProviderVerdict {
+//!         ProviderVerdict::NotDetected
+//!     }
+//! }
+//!
+//! export_provider!(CLSID, || Box::new(MyProvider));
+//! ```
+//!
+//! The DLL still has to be registered with AMSI, see `register`. `ProviderHarness` calls a provider like AMSI would,
+//! without registering it.
+
+use std::io::{Read, Seek};
+use std::os::raw::c_void;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use super::com::{CoTaskMemAlloc, E_FAIL, E_NOINTERFACE, E_NOT_SUFFICIENT_BUFFER, E_NOTIMPL, E_POINTER, IAntimalwareProviderVtbl, IID_IUNKNOWN, IUnknownVtbl, S_FALSE, S_OK};
+use super::{AmsiResult, IntoContentName, ScanError, WinError, buffer_length};
+use super::com::{ComPtr, take_co_task_string};
+use super::providers::PROVIDERS_KEY;
+use super::registry::{self, HKEY_LOCAL_MACHINE, RegKey};
+use super::stream::{AMSI_ATTRIBUTE_APP_NAME, AMSI_ATTRIBUTE_CONTENT_ADDRESS, AMSI_ATTRIBUTE_CONTENT_NAME, AMSI_ATTRIBUTE_CONTENT_SIZE, AMSI_ATTRIBUTE_REDIRECT_CHAIN_ADDRESS, AMSI_ATTRIBUTE_REDIRECT_CHAIN_SIZE, AMSI_ATTRIBUTE_SESSION, AmsiStream, BufferSource, IAmsiStreamVtbl, ReaderSource, ScanAttributes, is_stream_unsupported, read_all};
+use super::sys::{AMSI_RESULT, AMSI_RESULT_BLOCKED_BY_ADMIN_START, AMSI_RESULT_CLEAN, AMSI_RESULT_DETECTED, AMSI_RESULT_NOT_DETECTED, HRESULT, LPCWSTR, ULONG};
+use super::sys::{ERROR_PROC_NOT_FOUND, GetProcAddress, LoadLibraryW};
+
+pub use super::com::Guid;
+
+type DllGetClassObjectFn = unsafe extern "system" fn(clsid: *const Guid, iid: *const Guid, object: *mut *mut c_void) -> HRESULT;
+
+const CLSID_KEY: &str = r"SOFTWARE\Classes\CLSID";
+
+const CLASS_E_NOAGGREGATION: HRESULT = 0x8004_0110;
+const CLASS_E_CLASSNOTAVAILABLE: HRESULT = 0x8004_0111;
+
+const IID_ICLASSFACTORY: Guid = Guid::from_u128(0x00000001_0000_0000_c000_000000000046);
+const IID_IANTIMALWAREPROVIDER: Guid = Guid::from_u128(0xb2cabfe3_fe04_42b1_a5df_08d483d4d125);
+const IID_IANTIMALWAREPROVIDER2: Guid = Guid::from_u128(0x7c1e6570_3f73_4e0f_8ad4_98b94cd3290f);
+
+/// Number of live objects and server locks, for `dll_can_unload_now`.
+static OUTSTANDING: AtomicUsize = AtomicUsize::new(0);
+
+/// The verdict of a provider on a scan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProviderVerdict {
+    /// Known good, the verdict won't change with future definitions.
+    Clean,
+    /// Nothing found, but the verdict might change with future definitions.
+    NotDetected,
+    /// Blocked by an administrator policy.
+    BlockedByAdmin,
+    /// Detected as malware.
+    Detected,
+    /// A raw `AMSI_RESULT`, for codes the other variants don't cover.
+    Code(u32),
+}
+
+impl ProviderVerdict {
+    fn code(self) -> AMSI_RESULT {
+        match self {
+            ProviderVerdict::Clean => AMSI_RESULT_CLEAN,
+            ProviderVerdict::NotDetected => AMSI_RESULT_NOT_DETECTED,
+            ProviderVerdict::BlockedByAdmin => AMSI_RESULT_BLOCKED_BY_ADMIN_START,
+            ProviderVerdict::Detected => AMSI_RESULT_DETECTED,
+            ProviderVerdict::Code(code) => code,
+        }
+    }
+}
+
+/// An antimalware provider.
+///
+/// AMSI calls providers from any thread, and concurrently.
+pub trait Provider: Send + Sync {
+    /// Returns the name of the provider, e.g. the product name.
+    fn display_name(&self) -> String;
+
+    /// Scans content.
+    fn scan(&self, stream: &ProviderStream) -> ProviderVerdict;
+
+    /// Returns `false` if the provider only scans content that is in memory, see `ProviderStream::content`. Scans of
+    /// other content then fail with `E_NOTIMPL` without calling `scan`.
+    fn supports_streams(&self) -> bool {
+        true
+    }
+
+    /// Called when a session ends, see `ProviderStream::session`.
+    fn close_session(&self, _session: u64) {}
+
+    /// Called for `AmsiNotifyOperation`, see `AmsiContext::notify_operation`. The default doesn't object to anything.
+    fn notify(&self, _data: &[u8], _content_name: &str, _app_name: &str) -> ProviderVerdict {
+        ProviderVerdict::NotDetected
+    }
+}
+
+/// The content of a scan, as handed to `Provider::scan`.
+pub struct ProviderStream {
+    stream: *mut c_void,
+}
+
+impl std::fmt::Debug for ProviderStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ProviderStream")
+            .field("content_name", &self.content_name())
+            .field("content_size", &self.content_size())
+            .finish()
+    }
+}
+
+impl ProviderStream {
+    /// Wraps an `IAmsiStream` pointer, which has to stay valid for the lifetime of the `ProviderStream`.
+    pub(crate) unsafe fn from_raw(stream: *mut c_void) -> ProviderStream {
+        ProviderStream{
+            stream,
+        }
+    }
+
+    fn vtbl(&self) -> &IAmsiStreamVtbl {
+        unsafe {
+            &**(self.stream as *const *const IAmsiStreamVtbl)
+        }
+    }
+
+    /// Queries an attribute of any size.
+    fn attribute(&self, attribute: u32) -> Option<Vec<u8>> {
+        let mut len = 0;
+        let hres = unsafe {
+            (self.vtbl().get_attribute)(self.stream, attribute, 0, std::ptr::null_mut(), &mut len)
+        };
+        if hres != E_NOT_SUFFICIENT_BUFFER && hres != S_OK {
+            return None;
+        }
+
+        let mut data = vec![0u8; len as usize];
+        let hres = unsafe {
+            (self.vtbl().get_attribute)(self.stream, attribute, len, data.as_mut_ptr(), &mut len)
+        };
+        if hres != S_OK {
+            return None;
+        }
+        data.truncate(len as usize);
+        Some(data)
+    }
+
+    /// Queries an attribute that fits into 64 bits.
+    fn attribute_u64(&self, attribute: u32, size: usize) -> Option<u64> {
+        let mut data = [0u8; 8];
+        let mut len = 0;
+        let hres = unsafe {
+            (self.vtbl().get_attribute)(self.stream, attribute, size as ULONG, data.as_mut_ptr(), &mut len)
+        };
+        if hres != S_OK || len as usize != size {
+            return None;
+        }
+        Some(u64::from_le_bytes(data))
+    }
+
+    fn attribute_string(&self, attribute: u32) -> Option<String> {
+        self.attribute(attribute).map(|data| decode_wide(&data).into_iter().next().unwrap_or_default())
+    }
+
+    /// Returns the name of the application that requested the scan.
+    pub fn app_name(&self) -> Option<String> {
+        self.attribute_string(AMSI_ATTRIBUTE_APP_NAME)
+    }
+
+    /// Returns the content name (file name, URL or script ID) of the scan.
+    pub fn content_name(&self) -> Option<String> {
+        self.attribute_string(AMSI_ATTRIBUTE_CONTENT_NAME)
+    }
+
+    /// Returns the size of the content, in bytes.
+    pub fn content_size(&self) -> Option<u64> {
+        self.attribute_u64(AMSI_ATTRIBUTE_CONTENT_SIZE, 8)
+    }
+
+    /// Returns the session of the scan, `0` if it isn't part of one.
+    pub fn session(&self) -> u64 {
+        self.attribute_u64(AMSI_ATTRIBUTE_SESSION, 8).unwrap_or(0)
+    }
+
+    /// Returns the URLs the content was redirected through, if the client reported them.
+    pub fn redirect_chain(&self) -> Vec<String> {
+        let len = self.attribute_u64(AMSI_ATTRIBUTE_REDIRECT_CHAIN_SIZE, 4);
+        let address = self.attribute_u64(AMSI_ATTRIBUTE_REDIRECT_CHAIN_ADDRESS, std::mem::size_of::<usize>());
+        match (len, address) {
+            (Some(len), Some(address)) if address != 0 => {
+                let data = unsafe { std::slice::from_raw_parts(address as usize as *const u8, len as usize) };
+                decode_wide(data)
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns the content, if the client has all of it in memory (as for `AmsiScanBuffer`).
+    ///
+    /// Otherwise the content has to be read with `read_at`.
+    pub fn content(&self) -> Option<&[u8]> {
+        let size = self.content_size()?;
+        let address = self.attribute_u64(AMSI_ATTRIBUTE_CONTENT_ADDRESS, std::mem::size_of::<usize>())?;
+        if address == 0 {
+            return None;
+        }
+        Some(unsafe { std::slice::from_raw_parts(address as usize as *const u8, size as usize) })
+    }
+
+    /// Reads content at `position`, returning the number of bytes read (`0` at the end of the content).
+    pub fn read_at(&self, position: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = std::cmp::min(buf.len(), ULONG::MAX as usize) as ULONG;
+        let mut read = 0;
+        let hres = unsafe {
+            (self.vtbl().read)(self.stream, position, size, buf.as_mut_ptr(), &mut read)
+        };
+        if hres != S_OK {
+            return Err(std::io::Error::other(format!("IAmsiStream::Read failed with {:#x}", hres)));
+        }
+        Ok(read as usize)
+    }
+}
+
+/// Decodes a sequence of nul-terminated UTF-16 strings, as returned by string attributes.
+fn decode_wide(data: &[u8]) -> Vec<String> {
+    let wide: Vec<u16> = data.chunks(2)
+        .filter(|c| c.len() == 2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+
+    wide.split(|&c| c == 0)
+        .take_while(|s| !s.is_empty())
+        .map(String::from_utf16_lossy)
+        .collect()
+}
+
+#[repr(C)]
+struct IAntimalwareProvider2Vtbl {
+    provider: IAntimalwareProviderVtbl,
+    notify: unsafe extern "system" fn(this: *mut c_void, buffer: *const u8, length: ULONG, content_name: LPCWSTR, app_name: LPCWSTR, result: *mut AMSI_RESULT) -> HRESULT,
+}
+
+#[repr(C)]
+struct IClassFactoryVtbl {
+    unknown: IUnknownVtbl,
+    create_instance: unsafe extern "system" fn(this: *mut c_void, outer: *mut c_void, iid: *const Guid, object: *mut *mut c_void) -> HRESULT,
+    lock_server: unsafe extern "system" fn(this: *mut c_void, lock: i32) -> HRESULT,
+}
+
+static PROVIDER_VTBL: IAntimalwareProvider2Vtbl = IAntimalwareProvider2Vtbl{
+    provider: IAntimalwareProviderVtbl{
+        unknown: IUnknownVtbl{
+            query_interface: provider_query_interface,
+            add_ref: provider_add_ref,
+            release: provider_release,
+        },
+        scan: provider_scan,
+        close_session: provider_close_session,
+        display_name: provider_display_name,
+    },
+    notify: provider_notify,
+};
+
+static FACTORY_VTBL: IClassFactoryVtbl = IClassFactoryVtbl{
+    unknown: IUnknownVtbl{
+        query_interface: factory_query_interface,
+        add_ref: factory_add_ref,
+        release: factory_release,
+    },
+    create_instance: factory_create_instance,
+    lock_server: factory_lock_server,
+};
+
+/// The COM object of a provider. The vtable pointer has to be the first field.
+#[repr(C)]
+struct ProviderObject {
+    vtbl: *const IAntimalwareProvider2Vtbl,
+    refs: AtomicU32,
+    provider: Box<dyn Provider>,
+}
+
+/// The class factory of a provider. The vtable pointer has to be the first field.
+#[repr(C)]
+struct FactoryObject {
+    vtbl: *const IClassFactoryVtbl,
+    refs: AtomicU32,
+    create: fn() -> Box<dyn Provider>,
+}
+
+/// Answers `QueryInterface` for objects that implement the given interfaces, all with the same pointer.
+unsafe fn query_interface(this: *mut c_void, iid: *const Guid, object: *mut *mut c_void, iids: &[Guid], refs: &AtomicU32) -> HRESULT {
+    if object.is_null() || iid.is_null() {
+        return E_POINTER;
+    }
+    if *iid == IID_IUNKNOWN || iids.contains(&*iid) {
+        refs.fetch_add(1, Ordering::Relaxed);
+        *object = this;
+        S_OK
+    } else {
+        *object = std::ptr::null_mut();
+        E_NOINTERFACE
+    }
+}
+
+/// Decrements a reference count, returning `true` if the object has to be freed.
+fn release_ref(refs: &AtomicU32) -> (ULONG, bool) {
+    let refs = refs.fetch_sub(1, Ordering::Release) - 1;
+    if refs == 0 {
+        std::sync::atomic::fence(Ordering::Acquire);
+    }
+    (refs, refs == 0)
+}
+
+unsafe extern "system" fn provider_query_interface(this: *mut c_void, iid: *const Guid, object: *mut *mut c_void) -> HRESULT {
+    let refs = &(*(this as *const ProviderObject)).refs;
+    query_interface(this, iid, object, &[IID_IANTIMALWAREPROVIDER, IID_IANTIMALWAREPROVIDER2], refs)
+}
+
+unsafe extern "system" fn provider_add_ref(this: *mut c_void) -> ULONG {
+    (*(this as *const ProviderObject)).refs.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+unsafe extern "system" fn provider_release(this: *mut c_void) -> ULONG {
+    let (refs, free) = release_ref(&(*(this as *const ProviderObject)).refs);
+    if free {
+        drop(Box::from_raw(this as *mut ProviderObject));
+        OUTSTANDING.fetch_sub(1, Ordering::Release);
+    }
+    refs
+}
+
+unsafe extern "system" fn provider_scan(this: *mut c_void, stream: *mut c_void, result: *mut AMSI_RESULT) -> HRESULT {
+    if stream.is_null() || result.is_null() {
+        return E_POINTER;
+    }
+    let object = &*(this as *const ProviderObject);
+    let stream = ProviderStream::from_raw(stream);
+    if !object.provider.supports_streams() && stream.content().is_none() {
+        return E_NOTIMPL;
+    }
+
+    // Unwinding into amsi.dll would abort the host process, a panic fails the scan instead.
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| object.provider.scan(&stream))) {
+        Ok(verdict) => {
+            *result = verdict.code();
+            S_OK
+        },
+        Err(_) => E_FAIL,
+    }
+}
+
+unsafe extern "system" fn provider_close_session(this: *mut c_void, session: u64) {
+    let object = &*(this as *const ProviderObject);
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| object.provider.close_session(session)));
+}
+
+unsafe extern "system" fn provider_display_name(this: *mut c_void, display_name: *mut *mut u16) -> HRESULT {
+    if display_name.is_null() {
+        return E_POINTER;
+    }
+    let object = &*(this as *const ProviderObject);
+    let name = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| object.provider.display_name())) {
+        Ok(name) => name,
+        Err(_) => return E_FAIL,
+    };
+
+    let wide: Vec<u16> = name.encode_utf16().chain(std::iter::once(0)).collect();
+    let ptr = CoTaskMemAlloc(wide.len() * 2) as *mut u16;
+    if ptr.is_null() {
+        return E_FAIL;
+    }
+    std::ptr::copy_nonoverlapping(wide.as_ptr(), ptr, wide.len());
+    *display_name = ptr;
+    S_OK
+}
+
+unsafe fn wide_str(s: LPCWSTR) -> String {
+    if s.is_null() {
+        return String::new();
+    }
+    let mut len = 0;
+    while *s.add(len) != 0 {
+        len += 1;
+    }
+    String::from_utf16_lossy(std::slice::from_raw_parts(s, len))
+}
+
+unsafe extern "system" fn provider_notify(this: *mut c_void, buffer: *const u8, length: ULONG, content_name: LPCWSTR, app_name: LPCWSTR, result: *mut AMSI_RESULT) -> HRESULT {
+    if result.is_null() || (buffer.is_null() && length != 0) {
+        return E_POINTER;
+    }
+    let object = &*(this as *const ProviderObject);
+    let data = if length == 0 { &[][..] } else { std::slice::from_raw_parts(buffer, length as usize) };
+    let content_name = wide_str(content_name);
+    let app_name = wide_str(app_name);
+
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| object.provider.notify(data, &content_name, &app_name))) {
+        Ok(verdict) => {
+            *result = verdict.code();
+            S_OK
+        },
+        Err(_) => E_FAIL,
+    }
+}
+
+unsafe extern "system" fn factory_query_interface(this: *mut c_void, iid: *const Guid, object: *mut *mut c_void) -> HRESULT {
+    let refs = &(*(this as *const FactoryObject)).refs;
+    query_interface(this, iid, object, &[IID_ICLASSFACTORY], refs)
+}
+
+unsafe extern "system" fn factory_add_ref(this: *mut c_void) -> ULONG {
+    (*(this as *const FactoryObject)).refs.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+unsafe extern "system" fn factory_release(this: *mut c_void) -> ULONG {
+    let (refs, free) = release_ref(&(*(this as *const FactoryObject)).refs);
+    if free {
+        drop(Box::from_raw(this as *mut FactoryObject));
+        OUTSTANDING.fetch_sub(1, Ordering::Release);
+    }
+    refs
+}
+
+unsafe extern "system" fn factory_create_instance(this: *mut c_void, outer: *mut c_void, iid: *const Guid, object: *mut *mut c_void) -> HRESULT {
+    if object.is_null() {
+        return E_POINTER;
+    }
+    *object = std::ptr::null_mut();
+    if !outer.is_null() {
+        return CLASS_E_NOAGGREGATION;
+    }
+
+    let factory = &*(this as *const FactoryObject);
+    let provider = match std::panic::catch_unwind(factory.create) {
+        Ok(provider) => provider,
+        Err(_) => return E_FAIL,
+    };
+    let instance = new_provider_object(provider);
+    let hres = provider_query_interface(instance, iid, object);
+    provider_release(instance);
+    hres
+}
+
+unsafe extern "system" fn factory_lock_server(_this: *mut c_void, lock: i32) -> HRESULT {
+    if lock != 0 {
+        OUTSTANDING.fetch_add(1, Ordering::Relaxed);
+    } else {
+        OUTSTANDING.fetch_sub(1, Ordering::Release);
+    }
+    S_OK
+}
+
+/// Creates a provider object with a reference count of one.
+pub(crate) fn new_provider_object(provider: Box<dyn Provider>) -> *mut c_void {
+    OUTSTANDING.fetch_add(1, Ordering::Relaxed);
+    Box::into_raw(Box::new(ProviderObject{
+        vtbl: &PROVIDER_VTBL,
+        refs: AtomicU32::new(1),
+        provider,
+    })) as *mut c_void
+}
+
+/// Implements `DllGetClassObject` for a provider DLL, see `export_provider!`.
+///
+/// # Safety
+/// The pointers have to be valid, as guaranteed by COM when it calls `DllGetClassObject`.
+pub unsafe fn dll_get_class_object(clsid: *const Guid, iid: *const Guid, object: *mut *mut c_void, provider_clsid: &Guid, create: fn() -> Box<dyn Provider>) -> HRESULT {
+    if clsid.is_null() || iid.is_null() || object.is_null() {
+        return E_POINTER;
+    }
+    *object = std::ptr::null_mut();
+    if *clsid != *provider_clsid {
+        return CLASS_E_CLASSNOTAVAILABLE;
+    }
+
+    OUTSTANDING.fetch_add(1, Ordering::Relaxed);
+    let factory = Box::into_raw(Box::new(FactoryObject{
+        vtbl: &FACTORY_VTBL,
+        refs: AtomicU32::new(1),
+        create,
+    })) as *mut c_void;
+    let hres = factory_query_interface(factory, iid, object);
+    factory_release(factory);
+    hres
+}
+
+/// Implements `DllCanUnloadNow` for a provider DLL, see `export_provider!`.
+pub fn dll_can_unload_now() -> HRESULT {
+    if OUTSTANDING.load(Ordering::Acquire) == 0 {
+        S_OK
+    } else {
+        S_FALSE
+    }
+}
+
+/// Exports `DllGetClassObject` and `DllCanUnloadNow` for a provider, turning a `cdylib` into a provider DLL.
+///
+/// The first argument is the CLSID of the provider (a `Guid`), the second a function (or closure without captures)
+/// that creates a `Box<dyn Provider>`. See the `provider` module for an example.
+#[macro_export]
+macro_rules! export_provider {
+    ($clsid:expr, $create:expr) => {
+        #[no_mangle]
+        pub unsafe extern "system" fn DllGetClassObject(clsid: *const $crate::provider::Guid, iid: *const $crate::provider::Guid, object: *mut *mut ::std::os::raw::c_void) -> u32 {
+            $crate::provider::dll_get_class_object(clsid, iid, object, &$clsid, $create)
+        }
+
+        #[no_mangle]
+        pub extern "system" fn DllCanUnloadNow() -> u32 {
+            $crate::provider::dll_can_unload_now()
+        }
+    };
+}
+
+/// Registers a provider DLL with COM and AMSI, as an installer would.
+///
+/// This writes the `InprocServer32` entry of the CLSID (with the `Both` threading model) and adds the CLSID to
+/// `HKLM\SOFTWARE\Microsoft\AMSI\Providers`. Both live in `HKLM`, so this fails with `ERROR_ACCESS_DENIED`
+/// unless the process runs elevated. The registry view is the one of the current process, a 32-bit provider has to
+/// be registered from a 32-bit process.
+///
+/// ## Parameters
+/// * **clsid** - CLSID of the provider, as passed to `export_provider!`.
+/// * **dll_path** - absolute path to the provider DLL.
+/// * **name** - name of the provider, shown next to the CLSID in the registry.
+pub fn register<P: AsRef<Path>>(clsid: &Guid, dll_path: P, name: &str) -> Result<(), WinError> {
+    let clsid = clsid.to_string();
+    let class_key = format!("{}\\{}", CLSID_KEY, clsid);
+
+    RegKey::create(HKEY_LOCAL_MACHINE, &class_key)?.set_string_value("", name)?;
+    let server = RegKey::create(HKEY_LOCAL_MACHINE, &format!("{}\\InprocServer32", class_key))?;
+    server.set_string_value("", &dll_path.as_ref().to_string_lossy())?;
+    server.set_string_value("ThreadingModel", "Both")?;
+
+    RegKey::create(HKEY_LOCAL_MACHINE, &format!("{}\\{}", PROVIDERS_KEY, clsid))?.set_string_value("", name)?;
+    Ok(())
+}
+
+/// Removes the registration of a provider, undoing `register`.
+///
+/// Entries that don't exist are skipped, so this succeeds for providers that aren't (fully) registered. Like
+/// `register`, this fails with `ERROR_ACCESS_DENIED` unless the process runs elevated.
+pub fn unregister(clsid: &Guid) -> Result<(), WinError> {
+    let clsid = clsid.to_string();
+    // AMSI first, so a failure doesn't leave AMSI pointing at a class that no longer exists.
+    registry::delete_tree(HKEY_LOCAL_MACHINE, &format!("{}\\{}", PROVIDERS_KEY, clsid))?;
+    registry::delete_tree(HKEY_LOCAL_MACHINE, &format!("{}\\{}", CLSID_KEY, clsid))
+}
+
+/// Calls a provider exactly like `amsi.dll` would, to test it without registering it.
+///
+/// Scans are handed to the provider as `IAmsiStream`s built from the given content and `ScanAttributes`. Buffers are
+/// exposed through `AMSI_ATTRIBUTE_CONTENT_ADDRESS` like `AmsiScanBuffer` does, readers only through `Read`. The
+/// application name reported to the provider is `"ProviderHarness"`, unless the attributes override it.
+pub struct ProviderHarness {
+    provider: ComPtr<IAntimalwareProviderVtbl>,
+    app_name: Vec<u16>,
+}
+
+impl std::fmt::Debug for ProviderHarness {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ProviderHarness")
+            .field("provider", &self.provider.as_raw())
+            .finish()
+    }
+}
+
+impl ProviderHarness {
+    /// Wraps a provider of the current process, going through the same COM object `export_provider!` exports.
+    pub fn new(provider: Box<dyn Provider>) -> ProviderHarness {
+        let object = new_provider_object(provider);
+        ProviderHarness::from_object(object).expect("provider object is not null")
+    }
+
+    /// Loads a provider DLL, and instantiates the provider through its `DllGetClassObject`.
+    ///
+    /// The DLL stays loaded for the rest of the process. COM doesn't need to be initialized, unless the provider
+    /// itself uses COM.
+    ///
+    /// ## Safety
+    /// Loading the DLL runs its `DllMain`, and the provider's code runs in this process with no isolation, so a
+    /// broken DLL corrupts the process just like any other native code would.
+    /// * The DLL must be trusted, and built for the architecture of the process.
+    /// * Its `DllGetClassObject` must follow the COM contract: return an `IClassFactory` for `clsid` whose
+    ///   `CreateInstance` returns an object implementing `IAntimalwareProvider`, with the vtable layout and calling
+    ///   convention declared in `amsi.h`.
+    /// * The provider must be usable from the threads the harness is used on, without a COM apartment unless the
+    ///   caller initializes one.
+    ///
+    /// ## Parameters
+    /// * **dll_path** - path to the provider DLL.
+    /// * **clsid** - CLSID of the provider, as it would be registered.
+    pub unsafe fn load<P: AsRef<Path>>(dll_path: P, clsid: &Guid) -> Result<ProviderHarness, WinError> {
+        let path = dll_path.as_ref();
+        let path = path.to_wide();
+        let module = LoadLibraryW(path.as_ptr());
+        if module.is_null() {
+            return Err(WinError::new());
+        }
+
+        let proc_addr = GetProcAddress(module, b"DllGetClassObject\0".as_ptr());
+        if proc_addr.is_null() {
+            return Err(WinError::from_code(ERROR_PROC_NOT_FOUND));
+        }
+        let get_class_object = std::mem::transmute::<*const u8, DllGetClassObjectFn>(proc_addr);
+
+        let mut factory = std::ptr::null_mut();
+        let hres = get_class_object(clsid, &IID_ICLASSFACTORY, &mut factory);
+        if hres != S_OK {
+            return Err(WinError::from_hresult(hres));
+        }
+        let factory = ComPtr::<IClassFactoryVtbl>::from_raw(factory).ok_or_else(|| WinError::from_hresult(E_POINTER))?;
+
+        let mut object = std::ptr::null_mut();
+        let hres = (factory.vtbl().create_instance)(factory.as_raw(), std::ptr::null_mut(), &IID_IANTIMALWAREPROVIDER, &mut object);
+        if hres != S_OK {
+            return Err(WinError::from_hresult(hres));
+        }
+        ProviderHarness::from_object(object).ok_or_else(|| WinError::from_hresult(E_POINTER))
+    }
+
+    fn from_object(object: *mut c_void) -> Option<ProviderHarness> {
+        Some(ProviderHarness{
+            provider: unsafe { ComPtr::from_raw(object) }?,
+            app_name: registry::to_wide("ProviderHarness"),
+        })
+    }
+
+    /// Returns the display name of the provider.
+    pub fn display_name(&self) -> Result<String, WinError> {
+        let mut name = std::ptr::null_mut();
+        let hres = unsafe {
+            (self.provider.vtbl().display_name)(self.provider.as_raw(), &mut name)
+        };
+        if hres != S_OK {
+            return Err(WinError::from_hresult(hres));
+        }
+        if name.is_null() {
+            return Err(WinError::from_hresult(E_POINTER));
+        }
+        Ok(unsafe { take_co_task_string(name) })
+    }
+
+    /// Scans a buffer, like `AmsiScanBuffer`.
+    pub fn scan_buffer(&self, content_name: &str, data: &[u8]) -> Result<AmsiResult, ScanError> {
+        self.scan_buffer_with(content_name, data, &ScanAttributes::default())
+    }
+
+    /// Scans a buffer with the given attributes.
+    pub fn scan_buffer_with(&self, content_name: &str, data: &[u8], attributes: &ScanAttributes) -> Result<AmsiResult, ScanError> {
+        buffer_length(data)?;
+        self.scan_amsi_stream(content_name, AmsiStream::new(&self.app_name, content_name, attributes, Box::new(BufferSource::new(data))))
+    }
+
+    /// Scans content from a reader, which the provider can only access through `IAmsiStream::Read`.
+    ///
+    /// Like `Antimalware::scan_stream`, this falls back to reading the content into memory and scanning it as a
+    /// buffer if the provider fails with `E_NOTIMPL`.
+    pub fn scan_stream<R: Read + Seek + Send>(&self, content_name: &str, reader: R) -> Result<AmsiResult, ScanError> {
+        self.scan_stream_with(content_name, reader, &ScanAttributes::default())
+    }
+
+    /// Scans content from a reader with the given attributes.
+    pub fn scan_stream_with<R: Read + Seek + Send>(&self, content_name: &str, mut reader: R, attributes: &ScanAttributes) -> Result<AmsiResult, ScanError> {
+        let start = reader.stream_position()?;
+        let source = ReaderSource::new(&mut reader)?;
+        match self.scan_amsi_stream(content_name, AmsiStream::new(&self.app_name, content_name, attributes, Box::new(source))) {
+            Err(ref err) if is_stream_unsupported(err) => {
+                let data = read_all(&mut reader, start)?;
+                self.scan_buffer_with(content_name, &data, attributes)
+            },
+            result => result,
+        }
+    }
+
+    fn scan_amsi_stream(&self, content_name: &str, stream: AmsiStream) -> Result<AmsiResult, ScanError> {
+        let mut result = 0;
+        let hres = unsafe {
+            (self.provider.vtbl().scan)(self.provider.as_raw(), stream.as_raw(), &mut result)
+        };
+        if hres != S_OK {
+            return Err(WinError::from_hresult(hres).during("IAntimalwareProvider::Scan").for_content(content_name).into());
+        }
+        Ok(AmsiResult::new(result))
+    }
+
+    /// Ends a session, as `amsi.dll` does when a session is closed.
+    pub fn close_session(&self, session: u64) {
+        unsafe {
+            (self.provider.vtbl().close_session)(self.provider.as_raw(), session);
+        }
+    }
+
+    /// Notifies the provider of an operation, like `AmsiNotifyOperation`.
+    ///
+    /// Fails with `E_NOINTERFACE` if the provider doesn't implement `IAntimalwareProvider2`.
+    pub fn notify(&self, content_name: &str, data: &[u8]) -> Result<AmsiResult, ScanError> {
+        let length = buffer_length(data)?;
+
+        let mut object = std::ptr::null_mut();
+        let hres = unsafe {
+            (self.provider.vtbl().unknown.query_interface)(self.provider.as_raw(), &IID_IANTIMALWAREPROVIDER2, &mut object)
+        };
+        if hres != S_OK {
+            return Err(WinError::from_hresult(hres).into());
+        }
+        let provider = unsafe { ComPtr::<IAntimalwareProvider2Vtbl>::from_raw(object) }.ok_or_else(|| WinError::from_hresult(E_POINTER))?;
+
+        let content_name = registry::to_wide(content_name);
+        let app_name = &self.app_name;
+        let mut result = 0;
+        let hres = unsafe {
+            (provider.vtbl().notify)(provider.as_raw(), data.as_ptr(), length, content_name.as_ptr(), app_name.as_ptr(), &mut result)
+        };
+        if hres != S_OK {
+            return Err(WinError::from_hresult(hres).into());
+        }
+        Ok(AmsiResult::new(result))
+    }
+}
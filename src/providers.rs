@@ -0,0 +1,74 @@
+use std::path::{Path, PathBuf};
+
+use super::WinError;
+use super::registry::{HKEY_CLASSES_ROOT, HKEY_LOCAL_MACHINE, RegKey};
+
+pub(crate) const PROVIDERS_KEY: &str = r"SOFTWARE\Microsoft\AMSI\Providers";
+
+/// An antimalware provider registered with AMSI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProviderInfo {
+    clsid: String,
+    name: Option<String>,
+    dll_path: Option<PathBuf>,
+}
+
+impl ProviderInfo {
+    /// Returns the CLSID the provider is registered under, e.g. `"{2781761E-28E0-4109-99FE-B9D127C57AFE}"`.
+    pub fn clsid(&self) -> &str {
+        &self.clsid
+    }
+
+    /// Returns the display name of the provider, e.g. `"Microsoft Defender Antivirus"`.
+    ///
+    /// This is the name of the COM class, or the name AMSI lists the provider with when the class has none. `None` if
+    /// neither is set.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns the path of the DLL implementing the provider, from the `InprocServer32` entry of its COM class.
+    ///
+    /// `None` if the class isn't registered, which leaves AMSI with a provider it can't load.
+    pub fn dll_path(&self) -> Option<&Path> {
+        self.dll_path.as_deref()
+    }
+
+    /// Resolves the COM class of a provider.
+    fn resolve(clsid: String) -> ProviderInfo {
+        let name = RegKey::open(HKEY_CLASSES_ROOT, &format!("CLSID\\{}", clsid))
+            .and_then(|class| class.string_value(""))
+            .ok()
+            .or_else(|| RegKey::open(HKEY_LOCAL_MACHINE, &format!("{}\\{}", PROVIDERS_KEY, clsid)).and_then(|key| key.string_value("")).ok())
+            .filter(|name| !name.is_empty());
+        let dll_path = RegKey::open(HKEY_CLASSES_ROOT, &format!("CLSID\\{}\\InprocServer32", clsid))
+            .and_then(|server| server.expanded_string_value(""))
+            .ok()
+            // paths with spaces are sometimes registered in quotes.
+            .map(|path| PathBuf::from(path.trim_matches('"')))
+            .filter(|path| !path.as_os_str().is_empty());
+
+        ProviderInfo{
+            clsid,
+            name,
+            dll_path,
+        }
+    }
+}
+
+/// Lists the antimalware providers registered with AMSI.
+///
+/// AMSI doesn't offer a way to pick a provider: every scan is handed to the registered providers by `amsi.dll`
+/// itself, and a context can't be bound to a specific one. When more than one provider is registered, the verdict
+/// of a scan may come from any of them. This function at least makes that situation visible.
+///
+/// The providers are read from `HKLM\SOFTWARE\Microsoft\AMSI\Providers`, using the registry view that matches the
+/// bitness of the current process, which is also what AMSI uses. Each CLSID is resolved to the name and DLL of its
+/// COM class, entries that can't be resolved are still listed.
+pub fn providers() -> Result<Vec<ProviderInfo>, WinError> {
+    let key = RegKey::open(HKEY_LOCAL_MACHINE, PROVIDERS_KEY)?;
+    Ok(key.subkeys()?
+        .into_iter()
+        .map(ProviderInfo::resolve)
+        .collect())
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+
+use super::{Clock, ScanError};
+
+/// Determines what happens when a scan exceeds the rate limit of an `AmsiContext`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitMode {
+    /// The scan fails immediately with `ScanError::RateLimited`.
+    Error,
+    /// The calling thread sleeps until the scan is allowed to proceed.
+    Block,
+}
+
+const MODE_ERROR: u8 = 0;
+const MODE_BLOCK: u8 = 1;
+
+/// A lock-free token bucket.
+///
+/// The bucket is implemented as a "generic cell rate algorithm": instead of counting tokens, it keeps track of the
+/// theoretical arrival time of the next scan, which fits in a single atomic.
+pub(crate) struct RateLimiter {
+    clock: Arc<dyn Clock>,
+    epoch: Instant,
+    /// Allowed scans per second, `0` means unlimited.
+    rate: AtomicU32,
+    mode: AtomicU8,
+    /// Theoretical arrival time of the next scan, in nanoseconds since `epoch`.
+    tat: AtomicU64,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> RateLimiter {
+        RateLimiter{
+            epoch: clock.now(),
+            clock,
+            rate: AtomicU32::new(0),
+            mode: AtomicU8::new(MODE_ERROR),
+            tat: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns a new, full bucket with the same configuration that uses `clock`.
+    pub(crate) fn with_clock(&self, clock: Arc<dyn Clock>) -> RateLimiter {
+        RateLimiter{
+            rate: AtomicU32::new(self.rate.load(Ordering::Relaxed)),
+            mode: AtomicU8::new(self.mode.load(Ordering::Relaxed)),
+            ..RateLimiter::new(clock)
+        }
+    }
+
+    pub(crate) fn configure(&self, scans_per_sec: u32, mode: RateLimitMode) {
+        let mode = match mode {
+            RateLimitMode::Error => MODE_ERROR,
+            RateLimitMode::Block => MODE_BLOCK,
+        };
+        self.mode.store(mode, Ordering::Relaxed);
+        self.rate.store(scans_per_sec, Ordering::Relaxed);
+    }
+
+    /// Takes a token from the bucket, either failing or blocking when the bucket is empty.
+    pub(crate) fn acquire(&self) -> Result<(), ScanError> {
+        loop {
+            let rate = self.rate.load(Ordering::Relaxed);
+            if rate == 0 {
+                return Ok(());
+            }
+
+            let interval = 1_000_000_000 / u64::from(rate);
+            // allows a burst of up to `rate` scans.
+            let tolerance = interval * (u64::from(rate) - 1);
+
+            let now = self.clock.now().duration_since(self.epoch).as_nanos() as u64;
+            let tat = self.tat.load(Ordering::Acquire);
+            let start = tat.max(now);
+
+            if start - now > tolerance {
+                if self.mode.load(Ordering::Relaxed) == MODE_ERROR {
+                    return Err(ScanError::RateLimited);
+                }
+                self.clock.sleep(Duration::from_nanos(start - now - tolerance));
+                continue;
+            }
+
+            if self.tat.compare_exchange_weak(tat, start + interval, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("rate", &self.rate)
+            .field("mode", &self.mode)
+            .field("tat", &self.tat)
+            .finish()
+    }
+}
@@ -0,0 +1,132 @@
+use std::sync::{Arc, RwLock};
+
+use super::{AmsiContext, AmsiError, AppName, ContextHandle, ScanError, WinError};
+
+/// Called when a context tries to recover, see `AmsiContext::set_recovery_handler`.
+pub(crate) type RecoveryHandler = Arc<dyn Fn(&RecoveryEvent) + Send + Sync>;
+
+/// Reports that a context tried to recover from a fatal failure, passed to the handler set with
+/// `AmsiContext::set_recovery_handler`.
+#[derive(Debug)]
+pub struct RecoveryEvent {
+    error: WinError,
+    reinit_error: Option<WinError>,
+}
+
+impl RecoveryEvent {
+    /// Returns the failure of the scan that triggered the recovery.
+    pub fn error(&self) -> &WinError {
+        &self.error
+    }
+
+    /// Returns `true` if the context was reinitialized, in which case the scan was replayed on the new context.
+    pub fn recovered(&self) -> bool {
+        self.reinit_error.is_none()
+    }
+
+    /// Returns why the context couldn't be reinitialized, if it couldn't. The scan then fails with `error`.
+    pub fn reinit_error(&self) -> Option<&WinError> {
+        self.reinit_error.as_ref()
+    }
+}
+
+impl AmsiError {
+    /// Returns `true` for failures that mean the AMSI context itself is unusable, rather than the call or the payload:
+    /// `NotInitialized`, `RpcServerUnavailable`, and the RPC errors of a service that went away (`RPC_S_CALL_FAILED`
+    /// and `RPC_E_DISCONNECTED`). Only reinitializing the context helps with these, see `AmsiContext::set_recovery`.
+    pub fn is_fatal(&self) -> bool {
+        match *self {
+            AmsiError::NotInitialized | AmsiError::RpcServerUnavailable => true,
+            AmsiError::Other(code) => code == super::RPC_E_CALL_FAILED || code == super::RPC_E_DISCONNECTED,
+            _ => false,
+        }
+    }
+}
+
+/// The current AMSI context of an `AmsiContext` and its clones, replaced when they recover.
+pub(crate) struct HandleSlot {
+    current: RwLock<Arc<ContextHandle>>,
+}
+
+impl HandleSlot {
+    pub(crate) fn new(handle: ContextHandle) -> HandleSlot {
+        HandleSlot{
+            current: RwLock::new(Arc::new(handle)),
+        }
+    }
+
+    pub(crate) fn get(&self) -> Arc<ContextHandle> {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Replaces `stale` with a new context, unless another scan did so already.
+    ///
+    /// Returns the current context, and whether it was created by this call.
+    fn replace(&self, stale: &Arc<ContextHandle>, app_name: &AppName) -> Result<(Arc<ContextHandle>, bool), WinError> {
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        if !Arc::ptr_eq(&current, stale) {
+            return Ok((current.clone(), false));
+        }
+        let handle = Arc::new(ContextHandle::new(app_name, !stale.default_session.is_null())?);
+        *current = handle.clone();
+        Ok((handle, true))
+    }
+}
+
+impl AmsiContext {
+    /// Makes the context reinitialize itself when a scan fails with a fatal error (see `AmsiError::is_fatal`), such as
+    /// after the antimalware service was restarted. By default it doesn't.
+    ///
+    /// With recovery enabled, `scan_string` and `scan_buffer` re-run `AmsiInitialize` (and `AmsiOpenSession` for the
+    /// default session) when they fail with a fatal error, and replay the failed scan once on the new context. If
+    /// reinitializing fails too, the scan fails with its original error. The new context is shared by all clones, and
+    /// by the sessions created afterwards; sessions created before keep failing, since they are bound to the old
+    /// context, and have to be replaced.
+    ///
+    /// Scans that fail at the same time recover only once, the others replay on the context that was created first.
+    pub fn set_recovery(&mut self, recovery: bool) {
+        self.recovery = recovery;
+    }
+
+    /// Sets a function that is called whenever the context tries to recover, see `set_recovery`, e.g. for logging
+    /// or metrics. It is called on the thread of the failed scan, before the scan is replayed.
+    pub fn set_recovery_handler<F>(&mut self, handler: F)
+        where F: Fn(&RecoveryEvent) + Send + Sync + 'static
+    {
+        self.on_recovery = Some(Arc::new(handler));
+    }
+
+    /// Runs `scan` on the current context, and again on a new one if it failed fatally and recovery is enabled.
+    pub(crate) fn recovering<T, F>(&self, scan: F) -> Result<T, ScanError>
+        where F: Fn(&ContextHandle) -> Result<T, ScanError>
+    {
+        let handle = self.handle.get();
+        let error = match scan(&handle) {
+            Err(ScanError::Win(err)) => err,
+            result => return result,
+        };
+        if !self.recovery || !error.kind().is_fatal() {
+            return Err(ScanError::Win(error));
+        }
+
+        match self.handle.replace(&handle, &self.app_name) {
+            Ok((handle, created)) => {
+                if created {
+                    self.notify_recovery(&RecoveryEvent{error, reinit_error: None});
+                }
+                scan(&handle)
+            },
+            Err(reinit_error) => {
+                let event = RecoveryEvent{error, reinit_error: Some(reinit_error)};
+                self.notify_recovery(&event);
+                Err(ScanError::Win(event.error))
+            },
+        }
+    }
+
+    fn notify_recovery(&self, event: &RecoveryEvent) {
+        if let Some(ref handler) = self.on_recovery {
+            handler(event);
+        }
+    }
+}
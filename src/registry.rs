@@ -0,0 +1,275 @@
+use std::os::raw::c_void;
+
+use super::{AmsiResult, AmsiSession, DWORD, LPCWSTR, ScanError, WinError};
+
+pub(crate) type HKEY = *mut c_void;
+type LSTATUS = i32;
+
+pub(crate) const HKEY_CLASSES_ROOT: HKEY = 0x8000_0000usize as HKEY;
+pub(crate) const HKEY_CURRENT_USER: HKEY = 0x8000_0001usize as HKEY;
+pub(crate) const HKEY_LOCAL_MACHINE: HKEY = 0x8000_0002usize as HKEY;
+pub(crate) const HKEY_USERS: HKEY = 0x8000_0003usize as HKEY;
+pub(crate) const HKEY_CURRENT_CONFIG: HKEY = 0x8000_0005usize as HKEY;
+
+pub(crate) const REG_SZ: DWORD = 1;
+pub(crate) const REG_EXPAND_SZ: DWORD = 2;
+pub(crate) const REG_BINARY: DWORD = 3;
+pub(crate) const REG_DWORD: DWORD = 4;
+pub(crate) const REG_MULTI_SZ: DWORD = 7;
+
+const KEY_READ: DWORD = 0x20019;
+const KEY_WRITE: DWORD = 0x20006;
+const REG_OPTION_NON_VOLATILE: DWORD = 0;
+const REG_NOTIFY_CHANGE_LAST_SET: DWORD = 4;
+const ERROR_SUCCESS: LSTATUS = 0;
+const ERROR_FILE_NOT_FOUND: LSTATUS = 2;
+const ERROR_MORE_DATA: LSTATUS = 234;
+const ERROR_NO_MORE_ITEMS: LSTATUS = 259;
+/// Registry key names are limited to 255 characters.
+const MAX_KEY_NAME: usize = 256;
+
+#[link(name="advapi32")]
+extern "system" {
+    fn RegOpenKeyExW(key: HKEY, sub_key: LPCWSTR, options: DWORD, sam_desired: DWORD, result: *mut HKEY) -> LSTATUS;
+    fn RegEnumKeyExW(key: HKEY, index: DWORD, name: *mut u16, name_len: *mut DWORD, reserved: *mut DWORD, class: *mut u16, class_len: *mut DWORD, last_write_time: *mut c_void) -> LSTATUS;
+    fn RegQueryValueExW(key: HKEY, value_name: LPCWSTR, reserved: *mut DWORD, value_type: *mut DWORD, data: *mut u8, data_len: *mut DWORD) -> LSTATUS;
+    fn RegCreateKeyExW(key: HKEY, sub_key: LPCWSTR, reserved: DWORD, class: *mut u16, options: DWORD, sam_desired: DWORD, security_attributes: *mut c_void, result: *mut HKEY, disposition: *mut DWORD) -> LSTATUS;
+    fn RegSetValueExW(key: HKEY, value_name: LPCWSTR, reserved: DWORD, value_type: DWORD, data: *const u8, data_len: DWORD) -> LSTATUS;
+    fn RegDeleteTreeW(key: HKEY, sub_key: LPCWSTR) -> LSTATUS;
+    fn RegNotifyChangeKeyValue(key: HKEY, watch_subtree: i32, notify_filter: DWORD, event: *mut c_void, asynchronous: i32) -> LSTATUS;
+    fn RegCloseKey(key: HKEY) -> LSTATUS;
+}
+
+#[link(name="kernel32")]
+extern "system" {
+    fn ExpandEnvironmentStringsW(src: LPCWSTR, dst: *mut u16, size: DWORD) -> DWORD;
+}
+
+pub(crate) fn to_wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+fn check(status: LSTATUS) -> Result<(), WinError> {
+    if status == ERROR_SUCCESS {
+        Ok(())
+    } else {
+        Err(WinError::from_code(status as DWORD))
+    }
+}
+
+/// An open registry key, closed on drop.
+pub(crate) struct RegKey {
+    key: HKEY,
+}
+
+// registry handles aren't bound to the thread that opened them.
+unsafe impl Send for RegKey {}
+
+impl RegKey {
+    /// Opens a key for reading.
+    pub(crate) fn open(parent: HKEY, path: &str) -> Result<RegKey, WinError> {
+        let path = to_wide(path);
+        let mut key = std::ptr::null_mut();
+        check(unsafe {
+            RegOpenKeyExW(parent, path.as_ptr(), 0, KEY_READ, &mut key)
+        })?;
+        Ok(RegKey{
+            key,
+        })
+    }
+
+    /// Opens a key for writing, creating it (and its missing parents) if needed.
+    pub(crate) fn create(parent: HKEY, path: &str) -> Result<RegKey, WinError> {
+        let path = to_wide(path);
+        let mut key = std::ptr::null_mut();
+        check(unsafe {
+            RegCreateKeyExW(parent, path.as_ptr(), 0, std::ptr::null_mut(), REG_OPTION_NON_VOLATILE, KEY_WRITE, std::ptr::null_mut(), &mut key, std::ptr::null_mut())
+        })?;
+        Ok(RegKey{
+            key,
+        })
+    }
+
+    /// Writes a `REG_SZ` value, an empty name stands for the default value of the key.
+    pub(crate) fn set_string_value(&self, name: &str, value: &str) -> Result<(), WinError> {
+        let value = to_wide(value);
+        self.set_value(name, REG_SZ, &value)
+    }
+
+    /// Writes a `REG_EXPAND_SZ` value, whose environment variables (e.g. `%SystemRoot%`) are expanded by readers.
+    pub(crate) fn set_expand_string_value(&self, name: &str, value: &str) -> Result<(), WinError> {
+        let value = to_wide(value);
+        self.set_value(name, REG_EXPAND_SZ, &value)
+    }
+
+    /// Writes a `REG_DWORD` value.
+    pub(crate) fn set_dword_value(&self, name: &str, value: DWORD) -> Result<(), WinError> {
+        let value = value.to_le_bytes();
+        check(unsafe {
+            RegSetValueExW(self.key, to_wide(name).as_ptr(), 0, REG_DWORD, value.as_ptr(), value.len() as DWORD)
+        })
+    }
+
+    fn set_value(&self, name: &str, value_type: DWORD, value: &[u16]) -> Result<(), WinError> {
+        let name = to_wide(name);
+        check(unsafe {
+            RegSetValueExW(self.key, name.as_ptr(), 0, value_type, value.as_ptr() as *const u8, (value.len() * 2) as DWORD)
+        })
+    }
+
+    /// Reads a value, returning its type and raw data.
+    pub(crate) fn value(&self, name: &str) -> Result<(DWORD, Vec<u8>), WinError> {
+        let name = to_wide(name);
+        let mut value_type = 0;
+        let mut len = 0;
+        check(unsafe {
+            RegQueryValueExW(self.key, name.as_ptr(), std::ptr::null_mut(), &mut value_type, std::ptr::null_mut(), &mut len)
+        })?;
+
+        let mut data = vec![0; len as usize];
+        loop {
+            let status = unsafe {
+                RegQueryValueExW(self.key, name.as_ptr(), std::ptr::null_mut(), &mut value_type, data.as_mut_ptr(), &mut len)
+            };
+            // the value grew since its size was queried.
+            if status != ERROR_MORE_DATA {
+                check(status)?;
+                data.truncate(len as usize);
+                return Ok((value_type, data));
+            }
+            data.resize(len as usize, 0);
+        }
+    }
+
+    /// Reads a string value.
+    pub(crate) fn string_value(&self, name: &str) -> Result<String, WinError> {
+        let (_, data) = self.value(name)?;
+        Ok(decode_strings(&data))
+    }
+
+    /// Reads a string value, expanding the environment variables of a `REG_EXPAND_SZ` value.
+    pub(crate) fn expanded_string_value(&self, name: &str) -> Result<String, WinError> {
+        let (value_type, data) = self.value(name)?;
+        let value = decode_strings(&data);
+        if value_type != REG_EXPAND_SZ {
+            return Ok(value);
+        }
+
+        let src = to_wide(&value);
+        let mut dst = vec![0u16; src.len()];
+        loop {
+            let len = unsafe {
+                ExpandEnvironmentStringsW(src.as_ptr(), dst.as_mut_ptr(), dst.len() as DWORD)
+            } as usize;
+            if len == 0 {
+                return Err(WinError::new());
+            }
+            // `len` includes the terminating null.
+            if len <= dst.len() {
+                return Ok(String::from_utf16_lossy(&dst[..len - 1]));
+            }
+            dst.resize(len, 0);
+        }
+    }
+
+    /// Blocks until a value of this key is written.
+    pub(crate) fn wait_for_change(&self) -> Result<(), WinError> {
+        check(unsafe {
+            RegNotifyChangeKeyValue(self.key, 0, REG_NOTIFY_CHANGE_LAST_SET, std::ptr::null_mut(), 0)
+        })
+    }
+
+    /// Returns the names of the subkeys of this key.
+    pub(crate) fn subkeys(&self) -> Result<Vec<String>, WinError> {
+        let mut names = Vec::new();
+        let mut buf = [0u16; MAX_KEY_NAME];
+
+        loop {
+            let mut len = buf.len() as DWORD;
+            let status = unsafe {
+                RegEnumKeyExW(self.key, names.len() as DWORD, buf.as_mut_ptr(), &mut len, std::ptr::null_mut(), std::ptr::null_mut(), std::ptr::null_mut(), std::ptr::null_mut())
+            };
+            if status == ERROR_NO_MORE_ITEMS {
+                return Ok(names);
+            }
+            check(status)?;
+            names.push(String::from_utf16_lossy(&buf[..len as usize]));
+        }
+    }
+}
+
+/// Deletes a key with all of its subkeys and values. A missing key is not an error.
+pub(crate) fn delete_tree(parent: HKEY, path: &str) -> Result<(), WinError> {
+    let path = to_wide(path);
+    match unsafe { RegDeleteTreeW(parent, path.as_ptr()) } {
+        ERROR_FILE_NOT_FOUND => Ok(()),
+        status => check(status),
+    }
+}
+
+/// Splits a path such as `HKEY_CURRENT_USER\Software\Foo` (or `HKCU\Software\Foo`) into its root key and subkey.
+pub(crate) fn split_root(path: &str) -> Option<(HKEY, &str)> {
+    let (root, sub_key) = match path.find('\\') {
+        Some(idx) => (&path[..idx], &path[idx + 1..]),
+        None => (path, ""),
+    };
+
+    let root = match root.to_ascii_uppercase().as_str() {
+        "HKEY_CLASSES_ROOT" | "HKCR" => HKEY_CLASSES_ROOT,
+        "HKEY_CURRENT_USER" | "HKCU" => HKEY_CURRENT_USER,
+        "HKEY_LOCAL_MACHINE" | "HKLM" => HKEY_LOCAL_MACHINE,
+        "HKEY_USERS" | "HKU" => HKEY_USERS,
+        "HKEY_CURRENT_CONFIG" | "HKCC" => HKEY_CURRENT_CONFIG,
+        _ => return None,
+    };
+    Some((root, sub_key))
+}
+
+/// Decodes the data of a `REG_SZ`, `REG_EXPAND_SZ` or `REG_MULTI_SZ` value, one string per line.
+fn decode_strings(data: &[u8]) -> String {
+    let wide: Vec<u16> = data.chunks(2)
+        .filter(|c| c.len() == 2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+
+    wide.split(|&c| c == 0)
+        .filter(|s| !s.is_empty())
+        .map(String::from_utf16_lossy)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+impl<'a> AmsiSession<'a> {
+    /// Scans a script or macro stored in the registry.
+    ///
+    /// String values (`REG_SZ`, `REG_EXPAND_SZ` and `REG_MULTI_SZ`) are scanned as strings, with the strings of a
+    /// `REG_MULTI_SZ` value joined by newlines. Environment variables of `REG_EXPAND_SZ` values are not expanded.
+    /// `REG_BINARY` values are scanned as a buffer. The content name is `"<key path>\<value name>"`.
+    ///
+    /// A missing key or value fails with the error of the registry (`ERROR_FILE_NOT_FOUND`), an unsupported key path
+    /// or value type fails with an `InvalidInput` I/O error.
+    ///
+    /// ## Parameters
+    /// * **hive_path** - path to the key holding the value, starting with the root key (e.g. `HKCU\Software\App`).
+    /// * **value_name** - name of the value, an empty name stands for the default value of the key.
+    pub fn scan_registry_value(&self, hive_path: &str, value_name: &str) -> Result<AmsiResult, ScanError> {
+        let (root, sub_key) = split_root(hive_path)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "unknown registry root key"))?;
+        let (value_type, data) = RegKey::open(root, sub_key)?.value(value_name)?;
+
+        let content_name = format!("{}\\{}", hive_path, value_name);
+        match value_type {
+            REG_SZ | REG_EXPAND_SZ | REG_MULTI_SZ => self.scan_string(&content_name, &decode_strings(&data)),
+            REG_BINARY => self.scan_buffer(&content_name, &data),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "unsupported registry value type").into()),
+        }
+    }
+}
+
+impl Drop for RegKey {
+    fn drop(&mut self) {
+        unsafe {
+            RegCloseKey(self.key);
+        }
+    }
+}
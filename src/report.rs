@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::AmsiResult;
+
+/// The results of one MIME type in a `GroupedReport`.
+#[derive(Debug)]
+pub struct ReportGroup {
+    mime_type: String,
+    count: usize,
+    detected: usize,
+    worst: AmsiResult,
+}
+
+impl ReportGroup {
+    /// Returns the MIME type of the group.
+    pub fn mime_type(&self) -> &str {
+        &self.mime_type
+    }
+
+    /// Returns the number of results in the group.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the number of results in the group that are malware (see `AmsiResult::is_malware`).
+    pub fn detected(&self) -> usize {
+        self.detected
+    }
+
+    /// Returns the most severe result of the group, as ranked by `AmsiResult::normalized_score`.
+    pub fn worst(&self) -> &AmsiResult {
+        &self.worst
+    }
+}
+
+/// A summary of scan results, grouped by MIME type. Created by `ScanReportBuilder::build`.
+///
+/// The `Display` implementation writes one line per MIME type, such as
+/// `application/pdf: 3 scanned, 1 detected, worst Detected (code 0x8000)`.
+#[derive(Debug)]
+pub struct GroupedReport {
+    groups: Vec<ReportGroup>,
+}
+
+impl GroupedReport {
+    /// Returns the groups of the report, sorted by MIME type.
+    pub fn groups(&self) -> &[ReportGroup] {
+        &self.groups
+    }
+}
+
+impl fmt::Display for GroupedReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for group in &self.groups {
+            writeln!(f, "{}: {} scanned, {} detected, worst {}", group.mime_type, group.count, group.detected, group.worst)?;
+        }
+        Ok(())
+    }
+}
+
+/// Accumulates scan results of heterogeneous content (e.g. email attachments) into a `GroupedReport`.
+#[derive(Debug, Default)]
+pub struct ScanReportBuilder {
+    groups: BTreeMap<String, ReportGroup>,
+}
+
+impl ScanReportBuilder {
+    /// Creates a builder without any results.
+    pub fn new() -> ScanReportBuilder {
+        ScanReportBuilder::default()
+    }
+
+    /// Adds the result of scanning content of the given MIME type.
+    ///
+    /// MIME types are compared case-insensitively, and parameters (such as `; charset=utf-8`) are ignored.
+    pub fn add(&mut self, mime_type: &str, result: AmsiResult) -> &mut Self {
+        let mime_type = mime_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        let detected = result.is_malware() as usize;
+
+        self.groups.entry(mime_type.clone())
+            .and_modify(|group| {
+                group.count += 1;
+                group.detected += detected;
+                group.worst = group.worst.most_severe(result);
+            })
+            .or_insert_with(|| ReportGroup{
+                mime_type,
+                count: 1,
+                detected,
+                worst: result,
+            });
+        self
+    }
+
+    /// Builds the report.
+    pub fn build(self) -> GroupedReport {
+        GroupedReport{
+            groups: self.groups.into_values().collect(),
+        }
+    }
+}
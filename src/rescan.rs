@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+
+use super::{AmsiContext, AmsiResult, DefinitionsToken, ScanError};
+use super::definitions::SIGNATURE_UPDATES_KEY;
+use super::registry::{HKEY_LOCAL_MACHINE, RegKey};
+use super::sha256::Sha256;
+
+type Loader<K> = Box<dyn Fn(&K) -> std::io::Result<Vec<u8>> + Send + Sync>;
+type ChangeHandler<K> = Box<dyn Fn(&K, &AmsiResult) + Send + Sync>;
+
+struct Item {
+    content_name: String,
+    hash: [u8; 32],
+}
+
+struct State<K> {
+    token: DefinitionsToken,
+    items: HashMap<K, Item>,
+}
+
+/// Remembers content that scanned as "not detected", and re-scans it when the antimalware definitions are updated.
+///
+/// A "not detected" result only holds for the definitions it was scanned with, so stored content that was clean
+/// yesterday may be detected today. Items are identified by a key chosen by the application (e.g. a path or a
+/// database ID), the queue keeps their content name and SHA-256 hash, not the content itself. For a re-scan, the
+/// content is fetched again through the loader the queue was created with.
+///
+/// Items whose re-scan comes back with anything but "not detected" are reported to the change handler (see
+/// `set_change_handler`) and forgotten, since their verdict won't change with further updates, or is already bad.
+pub struct RescanQueue<K> {
+    context: AmsiContext,
+    load: Loader<K>,
+    on_change: Option<ChangeHandler<K>>,
+    state: Mutex<State<K>>,
+}
+
+impl<K: Clone + Eq + Hash> RescanQueue<K> {
+    /// Creates an empty queue that scans with `context`.
+    ///
+    /// ## Parameters
+    /// * **context** - context the items are re-scanned with.
+    /// * **load** - function that returns the current content of an item, for re-scanning it.
+    pub fn new<F>(context: &AmsiContext, load: F) -> RescanQueue<K>
+        where F: Fn(&K) -> std::io::Result<Vec<u8>> + Send + Sync + 'static
+    {
+        RescanQueue{
+            context: context.clone(),
+            load: Box::new(load),
+            on_change: None,
+            state: Mutex::new(State{
+                token: context.definitions_token(),
+                items: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Sets a function that is called for every item whose verdict changed on a re-scan, with the new result. It is
+    /// called on the thread that re-scans.
+    pub fn set_change_handler<F>(&mut self, handler: F)
+        where F: Fn(&K, &AmsiResult) + Send + Sync + 'static
+    {
+        self.on_change = Some(Box::new(handler));
+    }
+
+    /// Scans a buffer with the context of the queue, and remembers it if it isn't detected.
+    ///
+    /// ## Parameters
+    /// * **key** - identifies the content, as passed to the loader.
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **data** - payload that should be scanned.
+    pub fn scan(&self, key: K, content_name: &str, data: &[u8]) -> Result<AmsiResult, ScanError> {
+        let result = self.context.scan_buffer(content_name, data)?;
+        self.record(key, content_name, data, &result);
+        Ok(result)
+    }
+
+    /// Records the result of a scan done elsewhere. "Not detected" results are remembered, any other result forgets
+    /// the item.
+    pub fn record(&self, key: K, content_name: &str, data: &[u8], result: &AmsiResult) {
+        let mut state = self.lock();
+        if result.is_not_detected() {
+            state.items.insert(key, Item{
+                content_name: content_name.to_owned(),
+                hash: hash(data),
+            });
+        } else {
+            state.items.remove(&key);
+        }
+    }
+
+    /// Forgets an item, e.g. because its content was deleted. Returns `true` if it was remembered.
+    pub fn remove(&self, key: &K) -> bool {
+        self.lock().items.remove(key).is_some()
+    }
+
+    /// Returns the number of remembered items.
+    pub fn len(&self) -> usize {
+        self.lock().items.len()
+    }
+
+    /// Returns `true` if no items are remembered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Re-scans all items if the definitions were updated since the last re-scan (or since the queue was created),
+    /// see `AmsiContext::definitions_changed_since`. Returns the number of items whose verdict changed.
+    ///
+    /// The definition version can only be discovered for Windows Defender. With other providers this never
+    /// re-scans, call `rescan` on a schedule instead.
+    pub fn rescan_if_updated(&self) -> Result<usize, ScanError> {
+        let token = {
+            let state = self.lock();
+            if !self.context.definitions_changed_since(&state.token) {
+                return Ok(0);
+            }
+            self.context.definitions_token()
+        };
+        self.rescan_with(token)
+    }
+
+    /// Re-scans all items, whether the definitions were updated or not. Returns the number of items whose verdict
+    /// changed.
+    ///
+    /// Items whose content can't be loaded anymore are forgotten. Every item is loaded, but content that is shared by
+    /// several items is only scanned once. If a scan fails, the re-scan stops with its error and the remaining items keep their
+    /// verdict, they are re-scanned the next time.
+    pub fn rescan(&self) -> Result<usize, ScanError> {
+        self.rescan_with(self.context.definitions_token())
+    }
+
+    fn rescan_with(&self, token: DefinitionsToken) -> Result<usize, ScanError> {
+        let old_token = std::mem::replace(&mut self.lock().token, token);
+        // the lock isn't held while scanning, so that scans of new content don't wait for the re-scan.
+        let items: Vec<(K, String, [u8; 32])> = self.lock().items.iter()
+            .map(|(key, item)| (key.clone(), item.content_name.clone(), item.hash))
+            .collect();
+
+        let mut results: HashMap<[u8; 32], AmsiResult> = HashMap::new();
+        let mut changed = 0;
+        for (key, content_name, recorded_hash) in items {
+            let data = match (self.load)(&key) {
+                Ok(data) => data,
+                Err(_) => {
+                    self.remove(&key);
+                    continue;
+                },
+            };
+            // results are keyed by the content as it is now, which may differ from the recorded content.
+            let data_hash = hash(&data);
+            let result = match results.get(&data_hash) {
+                Some(result) => *result,
+                None => match self.context.scan_buffer(content_name.as_str(), &data) {
+                    Ok(result) => *results.entry(data_hash).or_insert(result),
+                    Err(err) => {
+                        self.lock().token = old_token;
+                        return Err(err);
+                    },
+                },
+            };
+
+            if result.is_not_detected() {
+                // the content may have changed since it was recorded.
+                if data_hash != recorded_hash {
+                    if let Some(item) = self.lock().items.get_mut(&key) {
+                        item.hash = data_hash;
+                    }
+                }
+                continue;
+            }
+            if self.remove(&key) {
+                changed += 1;
+                if let Some(ref handler) = self.on_change {
+                    handler(&key, &result);
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<K>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<K: Clone + Eq + Hash + Send + 'static> RescanQueue<K> {
+    /// Watches the Windows Defender definitions in the registry, and calls `rescan_if_updated` whenever they are
+    /// written.
+    ///
+    /// The re-scans run on a thread of its own, which runs until the process exits. Errors of re-scans are ignored,
+    /// the failed items are re-scanned on the next update. Fails with `ERROR_FILE_NOT_FOUND` if Windows Defender
+    /// isn't installed.
+    pub fn watch(self: Arc<Self>) -> std::io::Result<JoinHandle<()>> {
+        let key = RegKey::open(HKEY_LOCAL_MACHINE, SIGNATURE_UPDATES_KEY)?;
+        std::thread::Builder::new().name("amsi-rescan".into()).spawn(move || {
+            while key.wait_for_change().is_ok() {
+                let _ = self.rescan_if_updated();
+            }
+        })
+    }
+}
+
+fn hash(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finish()
+}
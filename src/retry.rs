@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use super::{Clock, HRESULT, WinError};
+
+/// How often scans are retried after transient failures, see `AmsiError::is_transient`.
+///
+/// Transient failures happen while the antimalware service restarts, e.g. after a definition update. Other failures
+/// are returned right away, and so is the last failure once the attempts are used up. The default policy doesn't
+/// retry. Policies are set with `AmsiContext::set_retry_policy` or `AmsiContextBuilder::retry_policy`, and apply to
+/// opening sessions as well.
+///
+/// ```
+/// extern crate amsi;
+///
+/// use std::time::Duration;
+///
+/// // waits 100ms, 200ms and 400ms before the retries.
+/// let policy = amsi::RetryPolicy::exponential(4, Duration::from_millis(100));
+/// assert_eq!(policy.delay(3), Duration::from_millis(400));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RetryPolicy {
+    /// The number of attempts, including the first one. `0` and `1` both disable retries.
+    pub attempts: u32,
+    /// The time to wait before the first retry.
+    pub backoff: Duration,
+    /// The factor the wait grows by with every further retry, `1` (or `0`) waits `backoff` every time.
+    pub multiplier: u32,
+    /// The longest time to wait before a retry.
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// A policy that doesn't retry.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::constant(1, Duration::from_millis(0))
+    }
+
+    /// A policy that waits `backoff` before every retry.
+    pub fn constant(attempts: u32, backoff: Duration) -> RetryPolicy {
+        RetryPolicy{
+            attempts,
+            backoff,
+            multiplier: 1,
+            max_backoff: backoff,
+        }
+    }
+
+    /// A policy that waits `backoff` before the first retry, and twice as long as the time before for every further
+    /// retry, up to 30 seconds.
+    pub fn exponential(attempts: u32, backoff: Duration) -> RetryPolicy {
+        RetryPolicy{
+            attempts,
+            backoff,
+            multiplier: 2,
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+
+    /// Returns the time to wait before retry number `retry`, starting at 1.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = u64::from(std::cmp::max(self.multiplier, 1)).saturating_pow(retry.saturating_sub(1));
+        let nanos = (self.backoff.as_nanos() as u64).saturating_mul(factor);
+        std::cmp::min(Duration::from_nanos(nanos), std::cmp::max(self.max_backoff, self.backoff))
+    }
+
+    /// Calls `f` until it succeeds, fails with a non-transient error, or the attempts are used up, returning the last
+    /// `HRESULT`.
+    pub(crate) fn run<F: FnMut() -> HRESULT>(&self, clock: &dyn Clock, mut f: F) -> HRESULT {
+        let mut attempt = 1;
+        loop {
+            let hres = f();
+            if hres == 0 || attempt >= self.attempts || !WinError::from_hresult(hres).kind().is_transient() {
+                return hres;
+            }
+            clock.sleep(self.delay(attempt));
+            attempt += 1;
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::none()
+    }
+}
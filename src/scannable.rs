@@ -0,0 +1,73 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use super::{AmsiResult, AmsiSession, FILE_SIZE_LIMIT, IntoContentName, ScanError};
+use super::file::read_file;
+
+/// Content that can be scanned with `AmsiSession::scan`.
+///
+/// Strings are scanned with `scan_string`, byte slices with `scan_buffer`, paths are read into memory like with
+/// `scan_file` (up to `FILE_SIZE_LIMIT` bytes) and readers wrapped in `FromReader` are scanned in chunks like with
+/// `scan_reader`, using the chunk size of the context. The trait can be implemented for other kinds of content.
+pub trait Scannable {
+    /// Scans the content in `session`.
+    fn scan_in<N: IntoContentName>(self, session: &AmsiSession, content_name: N) -> Result<AmsiResult, ScanError>;
+}
+
+/// A reader whose content should be scanned, see `Scannable`.
+#[derive(Debug)]
+pub struct FromReader<R>(pub R);
+
+impl Scannable for &str {
+    fn scan_in<N: IntoContentName>(self, session: &AmsiSession, content_name: N) -> Result<AmsiResult, ScanError> {
+        session.scan_string(content_name, self)
+    }
+}
+
+impl Scannable for &String {
+    fn scan_in<N: IntoContentName>(self, session: &AmsiSession, content_name: N) -> Result<AmsiResult, ScanError> {
+        session.scan_string(content_name, self)
+    }
+}
+
+impl Scannable for &[u8] {
+    fn scan_in<N: IntoContentName>(self, session: &AmsiSession, content_name: N) -> Result<AmsiResult, ScanError> {
+        session.scan_buffer(content_name, self)
+    }
+}
+
+impl Scannable for &Vec<u8> {
+    fn scan_in<N: IntoContentName>(self, session: &AmsiSession, content_name: N) -> Result<AmsiResult, ScanError> {
+        session.scan_buffer(content_name, self)
+    }
+}
+
+impl Scannable for &Path {
+    fn scan_in<N: IntoContentName>(self, session: &AmsiSession, content_name: N) -> Result<AmsiResult, ScanError> {
+        let data = read_file(self, FILE_SIZE_LIMIT)?;
+        session.scan_buffer(content_name, &data)
+    }
+}
+
+impl Scannable for &PathBuf {
+    fn scan_in<N: IntoContentName>(self, session: &AmsiSession, content_name: N) -> Result<AmsiResult, ScanError> {
+        self.as_path().scan_in(session, content_name)
+    }
+}
+
+impl<R: Read> Scannable for FromReader<R> {
+    fn scan_in<N: IntoContentName>(self, session: &AmsiSession, content_name: N) -> Result<AmsiResult, ScanError> {
+        session.scan_reader(content_name, self.0, session.ctx.chunk_size())
+    }
+}
+
+impl<'a> AmsiSession<'a> {
+    /// Scans any kind of content, see `Scannable` for how each kind is scanned.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **content** - content that should be scanned, e.g. a `&str`, a `&[u8]`, a `&Path` or a `FromReader`.
+    pub fn scan<N: IntoContentName, S: Scannable>(&self, content_name: N, content: S) -> Result<AmsiResult, ScanError> {
+        content.scan_in(self, content_name)
+    }
+}
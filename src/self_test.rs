@@ -0,0 +1,71 @@
+use std::time::{Duration, Instant};
+
+use super::{AmsiContext, AmsiResult, ScanError, WinError, providers};
+
+/// The EICAR test file, split so that this crate's binaries don't contain it and get flagged themselves.
+const EICAR: [&str; 2] = [r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-", "ANTIVIRUS-TEST-FILE!$H+H*"];
+
+/// The outcome of `self_test`.
+#[derive(Debug)]
+pub enum SelfTest {
+    /// The EICAR test file was detected, after the given time.
+    Healthy(Duration),
+    /// AMSI couldn't be initialized, e.g. because `amsi.dll` is missing.
+    Unavailable(WinError),
+    /// No antimalware provider is registered, so nothing is ever detected.
+    NoProvider,
+    /// The provider didn't return a result within the deadline.
+    Unresponsive(Duration),
+    /// The scan failed.
+    ScanFailed(ScanError),
+    /// The provider returned the given result instead of a detection, e.g. because real-time protection is turned
+    /// off or the content was excluded.
+    NotDetected(AmsiResult),
+}
+
+impl SelfTest {
+    /// Returns `true` if scans were found to be effective.
+    pub fn is_healthy(&self) -> bool {
+        matches!(*self, SelfTest::Healthy(_))
+    }
+}
+
+impl std::fmt::Display for SelfTest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            SelfTest::Healthy(latency) => write!(f, "test file detected after {:?}", latency),
+            SelfTest::Unavailable(ref err) => write!(f, "AMSI is unavailable: {}", err),
+            SelfTest::NoProvider => f.write_str("no antimalware provider is registered"),
+            SelfTest::Unresponsive(deadline) => write!(f, "the provider didn't respond within {:?}", deadline),
+            SelfTest::ScanFailed(ref err) => write!(f, "scanning the test file failed: {}", err),
+            SelfTest::NotDetected(ref result) => write!(f, "the test file wasn't detected: {}", result),
+        }
+    }
+}
+
+/// Checks that scans are effective, by scanning the EICAR test file and expecting a detection within `deadline`.
+///
+/// Calls into AMSI succeed even when scans are pointless, e.g. without a provider every scan is "not detected". This
+/// is meant as a readiness probe for services that depend on scanning. The scan runs on a temporary context, so the
+/// settings and metrics of the contexts of the application aren't involved. A provider that hangs costs a thread,
+/// see `AmsiContext::scan_buffer_with_timeout`.
+///
+/// Each call sends the test file to the provider, which may be reported as a detection by the antimalware product.
+pub fn self_test(deadline: Duration) -> SelfTest {
+    let ctx = match AmsiContext::new("amsi-self-test") {
+        Ok(ctx) => ctx,
+        Err(err) => return SelfTest::Unavailable(err),
+    };
+
+    let eicar = EICAR.concat();
+    let start = Instant::now();
+    match ctx.scan_buffer_with_timeout("eicar.com", eicar.as_bytes(), deadline) {
+        Ok(result) if result.is_malware() => SelfTest::Healthy(start.elapsed()),
+        Ok(result) => match providers() {
+            Ok(ref providers) if providers.is_empty() => SelfTest::NoProvider,
+            _ => SelfTest::NotDetected(result),
+        },
+        Err(ScanError::TimedOut(_)) => SelfTest::Unresponsive(deadline),
+        Err(err) => SelfTest::ScanFailed(err),
+    }
+}
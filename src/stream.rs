@@ -0,0 +1,404 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::os::raw::c_void;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use super::ScanError;
+use super::com::{E_FAIL, E_NOINTERFACE, E_NOT_SUFFICIENT_BUFFER, E_NOTIMPL, E_POINTER, Guid, IID_IAMSISTREAM, IID_IUNKNOWN, IUnknownVtbl, S_OK};
+use super::sys::{HRESULT, ULONG};
+
+pub(crate) const AMSI_ATTRIBUTE_APP_NAME: u32 = 0;
+pub(crate) const AMSI_ATTRIBUTE_CONTENT_NAME: u32 = 1;
+pub(crate) const AMSI_ATTRIBUTE_CONTENT_SIZE: u32 = 2;
+pub(crate) const AMSI_ATTRIBUTE_CONTENT_ADDRESS: u32 = 3;
+pub(crate) const AMSI_ATTRIBUTE_SESSION: u32 = 4;
+pub(crate) const AMSI_ATTRIBUTE_REDIRECT_CHAIN_SIZE: u32 = 5;
+pub(crate) const AMSI_ATTRIBUTE_REDIRECT_CHAIN_ADDRESS: u32 = 6;
+
+/// Additional context about scanned content, answered to providers that query the attributes of the stream.
+///
+/// Only scans that go through an `IAmsiStream` (see `Antimalware`) can carry attributes, the flat API of
+/// `AmsiContext` only passes the application name, the content name and the content.
+///
+/// The place the content came from (e.g. its URL) is best passed as the content name, which every provider looks at.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanAttributes {
+    /// Reported as `AMSI_ATTRIBUTE_APP_NAME` instead of the application name of the client, e.g. for content that is
+    /// scanned on behalf of a hosted application.
+    pub app_name: Option<String>,
+    /// Reported as `AMSI_ATTRIBUTE_SESSION`, allowing the provider to correlate scans that share the same session.
+    /// `0` stands for no session.
+    pub session: u64,
+    /// The URLs the content was redirected through before it was obtained, starting with the original URL. Reported as
+    /// `AMSI_ATTRIBUTE_REDIRECT_CHAIN_ADDRESS`, as a sequence of nul-terminated UTF-16 strings that ends with an empty
+    /// string, and `AMSI_ATTRIBUTE_REDIRECT_CHAIN_SIZE`, the size of that sequence in bytes.
+    pub redirect_chain: Vec<String>,
+}
+
+/// Content that is exposed to providers through an `IAmsiStream`.
+///
+/// Providers may read from other threads than the one that started the scan, hence `Send`.
+pub(crate) trait StreamSource: Send {
+    /// Returns the size of the content, in bytes.
+    fn size(&self) -> u64;
+
+    /// Reads content at `position`, returning the number of bytes read (`0` at the end of the content).
+    fn read_at(&mut self, position: u64, buf: &mut [u8]) -> std::io::Result<usize>;
+
+    /// Returns the address of the content, if all of it is in memory.
+    fn address(&self) -> Option<*const u8> {
+        None
+    }
+}
+
+/// A `StreamSource` over a buffer.
+pub(crate) struct BufferSource<'d> {
+    data: &'d [u8],
+}
+
+impl<'d> BufferSource<'d> {
+    pub(crate) fn new(data: &'d [u8]) -> BufferSource<'d> {
+        BufferSource{
+            data,
+        }
+    }
+}
+
+impl<'d> StreamSource for BufferSource<'d> {
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn read_at(&mut self, position: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = std::cmp::min(position, self.data.len() as u64) as usize;
+        let n = std::cmp::min(buf.len(), self.data.len() - start);
+        buf[..n].copy_from_slice(&self.data[start..start + n]);
+        Ok(n)
+    }
+
+    fn address(&self) -> Option<*const u8> {
+        Some(self.data.as_ptr())
+    }
+}
+
+/// A `StreamSource` over a reader, only reading the parts the provider asks for.
+pub(crate) struct ReaderSource<R> {
+    reader: R,
+    /// Offset of the content within the reader.
+    start: u64,
+    size: u64,
+    /// Current offset of the reader.
+    position: u64,
+}
+
+impl<R: Read + Seek> ReaderSource<R> {
+    /// Wraps a reader, the content reaches from its current position up to its end.
+    pub(crate) fn new(mut reader: R) -> std::io::Result<ReaderSource<R>> {
+        let start = reader.stream_position()?;
+        let end = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(start))?;
+
+        Ok(ReaderSource{
+            reader,
+            start,
+            size: end.saturating_sub(start),
+            position: start,
+        })
+    }
+}
+
+impl<R: Read + Seek + Send> StreamSource for ReaderSource<R> {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, position: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        if position >= self.size {
+            return Ok(0);
+        }
+        let max = std::cmp::min(buf.len() as u64, self.size - position) as usize;
+
+        let target = self.start + position;
+        if self.position != target {
+            self.position = self.reader.seek(SeekFrom::Start(target))?;
+        }
+        match self.reader.read(&mut buf[..max]) {
+            Ok(n) => {
+                self.position += n as u64;
+                Ok(n)
+            },
+            Err(err) => {
+                // the position of the reader is unknown now, seek before the next read.
+                self.position = u64::MAX;
+                Err(err)
+            },
+        }
+    }
+}
+
+/// Returns `true` if a scan failed because the provider doesn't read content through `IAmsiStream::Read`, and only
+/// scans content it can access in memory. Such providers fail the scan with `E_NOTIMPL`.
+pub(crate) fn is_stream_unsupported(err: &ScanError) -> bool {
+    match *err {
+        ScanError::Win(ref err) => err.hresult() == E_NOTIMPL,
+        _ => false,
+    }
+}
+
+/// Reads the content of a reader, from `start` up to its end, for scanning it as a buffer.
+pub(crate) fn read_all<R: Read + Seek>(reader: &mut R, start: u64) -> std::io::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(start))?;
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Content of a reader without `Seek`, buffered in memory or spilled to a temporary file, see `spill`.
+pub(crate) enum Spilled {
+    Memory(Vec<u8>),
+    File(SpillFile),
+}
+
+/// A temporary file that is deleted when dropped.
+pub(crate) struct SpillFile {
+    pub(crate) path: PathBuf,
+    pub(crate) file: File,
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Reads a reader to its end, into memory if it holds up to `memory_limit` bytes and into a temporary file otherwise.
+///
+/// The file is created in `std::env::temp_dir()`, and deleted when the returned `SpillFile` is dropped or reading
+/// fails. Its position is at the start of the content.
+pub(crate) fn spill<R: Read>(mut reader: R, memory_limit: usize) -> std::io::Result<Spilled> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    let mut data = Vec::new();
+    (&mut reader).take(memory_limit as u64 + 1).read_to_end(&mut data)?;
+    if data.len() <= memory_limit {
+        return Ok(Spilled::Memory(data));
+    }
+
+    let path = std::env::temp_dir()
+        .join(format!("amsi-spill-{}-{}.tmp", std::process::id(), NEXT_ID.fetch_add(1, Ordering::Relaxed)));
+    let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+    let mut spilled = SpillFile{
+        path,
+        file,
+    };
+    spilled.file.write_all(&data)?;
+    drop(data);
+    std::io::copy(&mut reader, &mut spilled.file)?;
+    spilled.file.seek(SeekFrom::Start(0))?;
+    Ok(Spilled::File(spilled))
+}
+
+#[repr(C)]
+pub(crate) struct IAmsiStreamVtbl {
+    pub(crate) unknown: IUnknownVtbl,
+    pub(crate) get_attribute: unsafe extern "system" fn(this: *mut c_void, attribute: u32, data_size: ULONG, data: *mut u8, ret_data: *mut ULONG) -> HRESULT,
+    pub(crate) read: unsafe extern "system" fn(this: *mut c_void, position: u64, size: ULONG, buffer: *mut u8, read_size: *mut ULONG) -> HRESULT,
+}
+
+static STREAM_VTBL: IAmsiStreamVtbl = IAmsiStreamVtbl{
+    unknown: IUnknownVtbl{
+        query_interface,
+        add_ref,
+        release,
+    },
+    get_attribute,
+    read,
+};
+
+/// The COM object behind an `AmsiStream`. The vtable pointer has to be the first field.
+#[repr(C)]
+struct StreamObject {
+    vtbl: *const IAmsiStreamVtbl,
+    refs: AtomicU32,
+    app_name: Vec<u16>,
+    content_name: Vec<u16>,
+    session: u64,
+    redirect_chain: Vec<u8>,
+    // The lifetime of the source is enforced by `AmsiStream`, which detaches it (leaving `None`) when it is dropped.
+    source: Mutex<Option<Box<dyn StreamSource>>>,
+}
+
+/// An `IAmsiStream` implementation, handed to `IAntimalware::Scan` and `IAntimalwareProvider::Scan`.
+///
+/// Like the streams `amsi.dll` creates for `AmsiScanBuffer`, the object borrows its content for the duration of the
+/// scan it is passed to. The object is reference counted, so a provider may keep it past the scan: dropping the
+/// `AmsiStream` detaches the content, and later calls to `Read` and `GetAttribute` fail with `E_FAIL` instead of
+/// reaching borrowed data.
+pub(crate) struct AmsiStream<'s> {
+    object: *mut StreamObject,
+    _source: PhantomData<Box<dyn StreamSource + 's>>,
+}
+
+impl<'s> AmsiStream<'s> {
+    /// Creates a stream. `app_name` is expected to be nul-terminated, as kept by the contexts.
+    pub(crate) fn new(app_name: &[u16], content_name: &str, attributes: &ScanAttributes, source: Box<dyn StreamSource + 's>) -> AmsiStream<'s> {
+        // Safety: the source is detached from the object when the `AmsiStream` is dropped, see `Drop`.
+        let source: Box<dyn StreamSource> = unsafe { std::mem::transmute::<Box<dyn StreamSource + 's>, Box<dyn StreamSource>>(source) };
+
+        let app_name = match attributes.app_name {
+            Some(ref app_name) => app_name.encode_utf16().chain(std::iter::once(0)).collect(),
+            None => app_name.to_vec(),
+        };
+        let redirect_chain: Vec<u16> = attributes.redirect_chain.iter()
+            .flat_map(|url| url.encode_utf16().chain(std::iter::once(0)))
+            .chain(std::iter::once(0))
+            .collect();
+
+        let object = Box::new(StreamObject{
+            vtbl: &STREAM_VTBL,
+            refs: AtomicU32::new(1),
+            app_name,
+            content_name: content_name.encode_utf16().chain(std::iter::once(0)).collect(),
+            session: attributes.session,
+            redirect_chain: wide_bytes(&redirect_chain),
+            source: Mutex::new(Some(source)),
+        });
+        AmsiStream{
+            object: Box::into_raw(object),
+            _source: PhantomData,
+        }
+    }
+
+    /// Returns the `IAmsiStream` pointer of the stream.
+    pub(crate) fn as_raw(&self) -> *mut c_void {
+        self.object as *mut c_void
+    }
+}
+
+impl<'s> Drop for AmsiStream<'s> {
+    fn drop(&mut self) {
+        unsafe {
+            // a provider may still hold a reference, the source must not be reachable through it after `'s`.
+            let source = (*self.object).source.lock().unwrap_or_else(|e| e.into_inner()).take();
+            drop(source);
+            release(self.as_raw());
+        }
+    }
+}
+
+unsafe extern "system" fn query_interface(this: *mut c_void, iid: *const Guid, object: *mut *mut c_void) -> HRESULT {
+    if object.is_null() || iid.is_null() {
+        return E_POINTER;
+    }
+    if *iid == IID_IUNKNOWN || *iid == IID_IAMSISTREAM {
+        add_ref(this);
+        *object = this;
+        S_OK
+    } else {
+        *object = std::ptr::null_mut();
+        E_NOINTERFACE
+    }
+}
+
+unsafe extern "system" fn add_ref(this: *mut c_void) -> ULONG {
+    let object = &*(this as *const StreamObject);
+    object.refs.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+unsafe extern "system" fn release(this: *mut c_void) -> ULONG {
+    let refs = (*(this as *const StreamObject)).refs.fetch_sub(1, Ordering::Release) - 1;
+    if refs == 0 {
+        std::sync::atomic::fence(Ordering::Acquire);
+        drop(Box::from_raw(this as *mut StreamObject));
+    }
+    refs
+}
+
+/// Copies an attribute value into the caller's buffer, following the `IAmsiStream::GetAttribute` protocol.
+unsafe fn copy_attribute(value: &[u8], data_size: ULONG, data: *mut u8, ret_data: *mut ULONG) -> HRESULT {
+    *ret_data = value.len() as ULONG;
+    if (data_size as usize) < value.len() {
+        return E_NOT_SUFFICIENT_BUFFER;
+    }
+    if data.is_null() {
+        return E_POINTER;
+    }
+    std::ptr::copy_nonoverlapping(value.as_ptr(), data, value.len());
+    S_OK
+}
+
+fn wide_bytes(s: &[u16]) -> Vec<u8> {
+    s.iter().flat_map(|c| c.to_le_bytes().to_vec()).collect()
+}
+
+unsafe extern "system" fn get_attribute(this: *mut c_void, attribute: u32, data_size: ULONG, data: *mut u8, ret_data: *mut ULONG) -> HRESULT {
+    if ret_data.is_null() {
+        return E_POINTER;
+    }
+    let object = &*(this as *const StreamObject);
+    let source = object.source.lock().unwrap_or_else(|e| e.into_inner());
+    let source = match *source {
+        Some(ref source) => source,
+        // the scan is over.
+        None => return E_FAIL,
+    };
+
+    let value = match attribute {
+        AMSI_ATTRIBUTE_APP_NAME => wide_bytes(&object.app_name),
+        AMSI_ATTRIBUTE_CONTENT_NAME => wide_bytes(&object.content_name),
+        AMSI_ATTRIBUTE_CONTENT_SIZE => source.size().to_le_bytes().to_vec(),
+        AMSI_ATTRIBUTE_CONTENT_ADDRESS => match source.address() {
+            Some(address) => (address as usize).to_le_bytes().to_vec(),
+            None => return E_NOTIMPL,
+        },
+        AMSI_ATTRIBUTE_SESSION => object.session.to_le_bytes().to_vec(),
+        AMSI_ATTRIBUTE_REDIRECT_CHAIN_SIZE => (object.redirect_chain.len() as ULONG).to_le_bytes().to_vec(),
+        AMSI_ATTRIBUTE_REDIRECT_CHAIN_ADDRESS => (object.redirect_chain.as_ptr() as usize).to_le_bytes().to_vec(),
+        _ => return E_NOTIMPL,
+    };
+    copy_attribute(&value, data_size, data, ret_data)
+}
+
+unsafe extern "system" fn read(this: *mut c_void, position: u64, size: ULONG, buffer: *mut u8, read_size: *mut ULONG) -> HRESULT {
+    if read_size.is_null() || (buffer.is_null() && size != 0) {
+        return E_POINTER;
+    }
+    *read_size = 0;
+    if size == 0 {
+        return S_OK;
+    }
+
+    let object = &*(this as *const StreamObject);
+    let buf = std::slice::from_raw_parts_mut(buffer, size as usize);
+    let mut source = object.source.lock().unwrap_or_else(|e| e.into_inner());
+    let source = match *source {
+        Some(ref mut source) => source,
+        // the scan is over.
+        None => return E_FAIL,
+    };
+
+    // Unwinding into the provider would abort the process, a panic fails the read instead.
+    let filled = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match source.read_at(position + filled as u64, &mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => {},
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(filled)
+    }));
+
+    match filled {
+        Ok(Ok(filled)) => {
+            *read_size = filled as ULONG;
+            S_OK
+        },
+        _ => E_FAIL,
+    }
+}
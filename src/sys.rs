@@ -0,0 +1,84 @@
+//! Raw bindings of the native AMSI API.
+//!
+//! These are the declarations the safe wrappers of this crate are built on, for the cases they don't cover. Handles
+//! obtained here can't be turned into an `AmsiContext` or `AmsiSession`, and have to be released with
+//! `AmsiUninitialize` and `AmsiCloseSession`.
+//!
+//! With the `dynamic` feature, the functions are wrappers that call into `amsi.dll` once it was loaded, rather than
+//! imports.
+
+pub type HRESULT = u32;
+pub type LPCWSTR = *const u16;
+pub type HAMSICONTEXT = *const u8;
+pub type HAMSISESSION = *const u8;
+pub type DWORD = u32;
+pub type ULONG = u32;
+pub type AMSI_RESULT = u32;
+
+/// The `HRESULT` of a successful call.
+pub const S_OK: HRESULT = 0;
+
+/// Known good. No detection found, and the result is likely not going to change after a future definition update.
+pub const AMSI_RESULT_CLEAN: AMSI_RESULT = 0;
+/// No detection found, but the result might change after a future definition update.
+pub const AMSI_RESULT_NOT_DETECTED: AMSI_RESULT = 1;
+/// Administrator policy blocked this content on this machine (beginning of range).
+pub const AMSI_RESULT_BLOCKED_BY_ADMIN_START: AMSI_RESULT = 0x4000;
+/// Administrator policy blocked this content on this machine (end of range).
+pub const AMSI_RESULT_BLOCKED_BY_ADMIN_END: AMSI_RESULT = 0x4fff;
+/// Detected as malware. Any result equal or larger than this is considered malware.
+pub const AMSI_RESULT_DETECTED: AMSI_RESULT = 32768;
+
+pub(crate) type HMODULE = *const u8;
+pub(crate) type HANDLE = *mut std::os::raw::c_void;
+pub(crate) type BOOL = i32;
+
+pub(crate) const INVALID_HANDLE_VALUE: HANDLE = !0 as HANDLE;
+pub(crate) const ERROR_PROC_NOT_FOUND: DWORD = 127;
+
+/// Restricts `LoadLibraryExW` to the system directory, so a planted DLL next to the executable isn't picked up.
+pub(crate) const LOAD_LIBRARY_SEARCH_SYSTEM32: DWORD = 0x0000_0800;
+
+// The loader functions that several modules resolve optional or dynamically loaded functions with, and the handle
+// functions that the file scanners share.
+#[link(name="kernel32")]
+extern "system" {
+    pub(crate) fn GetLastError() -> DWORD;
+    pub(crate) fn LoadLibraryW(file_name: LPCWSTR) -> HMODULE;
+    pub(crate) fn LoadLibraryExW(file_name: LPCWSTR, file: *const u8, flags: DWORD) -> HMODULE;
+    pub(crate) fn FreeLibrary(module: HMODULE) -> BOOL;
+    pub(crate) fn GetModuleHandleW(module_name: LPCWSTR) -> HMODULE;
+    pub(crate) fn GetProcAddress(module: HMODULE, proc_name: *const u8) -> *const u8;
+    pub(crate) fn GetFileSizeEx(file: HANDLE, file_size: *mut i64) -> BOOL;
+    pub(crate) fn CloseHandle(handle: HANDLE) -> BOOL;
+}
+
+/// Returns the name `amsi.dll` is loaded and looked up under, nul-terminated.
+pub(crate) fn amsi_dll() -> Vec<u16> {
+    "amsi.dll".encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+/// Converts a Windows error code to an `HRESULT`, like the `HRESULT_FROM_WIN32` macro.
+pub(crate) fn hresult_from_win32(code: DWORD) -> HRESULT {
+    if code == 0 || code & 0x8000_0000 != 0 {
+        code
+    } else {
+        0x8007_0000 | (code & 0xffff)
+    }
+}
+
+#[cfg(not(feature = "dynamic"))]
+// `raw-dylib` is rejected for other targets, which would break documentation builds with all features.
+#[cfg_attr(not(all(windows, feature = "raw-dylib")), link(name="amsi"))]
+#[cfg_attr(all(windows, feature = "raw-dylib"), link(name="amsi", kind="raw-dylib"))]
+extern "system" {
+    pub fn AmsiInitialize(name: LPCWSTR, context: &mut HAMSICONTEXT) -> HRESULT;
+    pub fn AmsiUninitialize(context: HAMSICONTEXT);
+    pub fn AmsiScanString(context: HAMSICONTEXT, string: LPCWSTR, content_name: LPCWSTR, session: HAMSISESSION, result: &mut AMSI_RESULT) -> HRESULT;
+    pub fn AmsiScanBuffer(context: HAMSICONTEXT, buffer: *const u8, length: ULONG, content_name: LPCWSTR, session: HAMSISESSION, result: &mut AMSI_RESULT) -> HRESULT;
+    pub fn AmsiOpenSession(context: HAMSICONTEXT, session: &mut HAMSISESSION) -> HRESULT;
+    pub fn AmsiCloseSession(context: HAMSICONTEXT, session: HAMSISESSION);
+}
+
+#[cfg(feature = "dynamic")]
+pub use super::dynamic::{AmsiCloseSession, AmsiInitialize, AmsiOpenSession, AmsiScanBuffer, AmsiScanString, AmsiUninitialize};
@@ -19,4 +19,1408 @@ fn clean_test() {
     let s = ctx.create_session().unwrap();
     let res = s.scan_string("test.txt", "Nothing wrong with this.").unwrap();
     assert!(res.is_not_detected() || res.is_clean());
-}
\ No newline at end of file
+}
+
+#[test]
+fn notify_operation_test() {
+    let ctx = AmsiContext::new("mytest").unwrap();
+    let res = ctx.notify_operation(b"Set-ItemProperty HKCU:\\Software\\Test -Name Value -Value 1", "registry-write").unwrap();
+    assert!(!res.is_malware());
+}
+
+#[test]
+fn rate_limit_test() {
+    let limiter = ratelimit::RateLimiter::new(Arc::new(SystemClock));
+    limiter.configure(2, RateLimitMode::Error);
+    assert!(limiter.acquire().is_ok());
+    assert!(limiter.acquire().is_ok());
+    match limiter.acquire() {
+        Err(ScanError::RateLimited) => {},
+        other => panic!("expected rate limit, got {:?}", other),
+    }
+
+    limiter.configure(0, RateLimitMode::Error);
+    assert!(limiter.acquire().is_ok());
+}
+
+#[test]
+fn rate_limit_block_test() {
+    let clock = Arc::new(MockClock::new());
+    let limiter = ratelimit::RateLimiter::new(clock.clone());
+    limiter.configure(2, RateLimitMode::Block);
+
+    limiter.acquire().unwrap();
+    limiter.acquire().unwrap();
+    assert_eq!(clock.elapsed(), std::time::Duration::from_secs(0));
+
+    // the bucket is empty, the next token becomes available after half a second.
+    limiter.acquire().unwrap();
+    assert_eq!(clock.elapsed(), std::time::Duration::from_millis(500));
+}
+
+#[test]
+fn rate_limit_clone_test() {
+    let mut ctx = AmsiContext::new("Test").unwrap();
+    let clone = ctx.clone();
+    ctx.set_rate_limit(1, RateLimitMode::Error);
+
+    assert!(ctx.scan_string("test.txt", "Nothing wrong with this.").is_ok());
+    assert!(matches!(ctx.scan_string("test.txt", "Nothing wrong with this."), Err(ScanError::RateLimited)));
+    // the clone was made before the limit was set.
+    assert!(clone.scan_string("test.txt", "Nothing wrong with this.").is_ok());
+}
+
+#[test]
+fn scan_lines_test() {
+    let script = "Write-Host 'hello'\nX5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*\nWrite-Host 'bye'\n";
+
+    let ctx = AmsiContext::new("Test").unwrap();
+    let s = ctx.create_session().unwrap();
+    let results: Vec<LineVerdict> = s.scan_lines("test.ps1", script.as_bytes())
+        .map(|r| r.unwrap())
+        .collect();
+
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].line, 1);
+    assert!(!results[0].result.is_malware());
+    assert!(!results[0].worst.is_malware());
+    assert_eq!(results[1].line, 2);
+    assert!(results[1].result.is_malware());
+    assert!(results[1].worst.is_malware());
+    // the detection carries over to the lines after it.
+    assert_eq!(results[2].line, 3);
+    assert!(results[2].worst.is_malware());
+}
+
+/// Scans the same payloads through the raw API and the wrapper, and expects identical result codes.
+#[cfg(windows)]
+#[test]
+fn raw_api_differential_test() {
+    let payloads: &[(&str, &[u8])] = &[
+        ("eicar-test.txt", br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*"),
+        ("clean.txt", b"Nothing wrong with this."),
+        ("binary.bin", &[0x4d, 0x5a, 0x90, 0x00, 0x03, 0x00, 0x00, 0x00, 0xff, 0xfe, 0x00, 0x01]),
+    ];
+
+    let ctx = AmsiContext::new("differential-test").unwrap();
+    let session = ctx.create_session().unwrap();
+
+    for &(name, data) in payloads {
+        let name_utf16: Vec<u16> = name.encode_utf16().chain(std::iter::once(0)).collect();
+
+        let mut raw_result = 0;
+        let hres = unsafe {
+            AmsiScanBuffer(session.handle.ctx, data.as_ptr(), data.len() as u32, name_utf16.as_ptr(), session.session, &mut raw_result)
+        };
+        assert_eq!(hres, 0, "AmsiScanBuffer failed for {}", name);
+        let wrapped = session.scan_buffer(name, data).unwrap();
+        assert_eq!(wrapped.get_code(), raw_result, "scan_buffer diverged for {}", name);
+
+        if let Ok(text) = std::str::from_utf8(data) {
+            let text_utf16: Vec<u16> = text.encode_utf16().chain(std::iter::once(0)).collect();
+
+            let mut raw_result = 0;
+            let hres = unsafe {
+                AmsiScanString(session.handle.ctx, text_utf16.as_ptr(), name_utf16.as_ptr(), session.session, &mut raw_result)
+            };
+            assert_eq!(hres, 0, "AmsiScanString failed for {}", name);
+            let wrapped = session.scan_string(name, text).unwrap();
+            assert_eq!(wrapped.get_code(), raw_result, "scan_string diverged for {}", name);
+        }
+    }
+}
+
+#[test]
+fn result_kind_test() {
+    assert_eq!(AmsiResult::new(0).kind(), AmsiResultKind::Clean);
+    assert_eq!(AmsiResult::new(1).kind(), AmsiResultKind::NotDetected);
+    assert_eq!(AmsiResult::new(0x4000).kind(), AmsiResultKind::BlockedByAdmin(0));
+    assert_eq!(AmsiResult::new(0x4fff).kind(), AmsiResultKind::BlockedByAdmin(0xfff));
+    assert_eq!(AmsiResult::new(0x8000).kind(), AmsiResultKind::Detected(0));
+    assert_eq!(AmsiResult::new(0x8123).kind(), AmsiResultKind::Detected(0x123));
+    assert_eq!(AmsiResult::new(0xffff_ffff).kind(), AmsiResultKind::Detected(u16::MAX));
+    assert_eq!(AmsiResult::new(2).kind(), AmsiResultKind::Unknown(2));
+    assert_eq!(AmsiResult::new(0x5000).kind(), AmsiResultKind::Unknown(0x5000));
+}
+
+#[test]
+fn risk_level_test() {
+    assert_eq!(AmsiResult::new(1).risk_level(), None);
+    assert_eq!(AmsiResult::new(0x4000).risk_level(), None);
+    assert_eq!(AmsiResult::new(0x8000).risk_level(), Some(RiskLevel::Low));
+    assert_eq!(AmsiResult::new(0xa000).risk_level(), Some(RiskLevel::Medium));
+    assert_eq!(AmsiResult::new(0xdfff).risk_level(), Some(RiskLevel::High));
+    assert_eq!(AmsiResult::new(0x1_0000).risk_level(), Some(RiskLevel::Critical));
+    assert!(RiskLevel::Critical > RiskLevel::Low);
+}
+
+#[test]
+fn empty_string_test() {
+    let ctx = AmsiContext::new("mytest").unwrap();
+    let s = ctx.create_session().unwrap();
+    assert!(s.scan_string("empty.txt", "").unwrap().is_clean());
+    assert!(s.scan_string("blank.txt", "   ").unwrap().is_clean());
+}
+
+#[test]
+fn filter_chain_test() {
+    let mut chain = FilterChain::new();
+    chain.push(|req| if req.content_name().ends_with(".exe") { FilterDecision::Block } else { FilterDecision::Continue })
+        .push(|req| if req.data().is_empty() { FilterDecision::Allow } else { FilterDecision::Continue });
+
+    assert_eq!(chain.evaluate(&ScanRequest::new("a.exe", b"")), FilterDecision::Block);
+    assert_eq!(chain.evaluate(&ScanRequest::new("a.txt", b"")), FilterDecision::Allow);
+    assert_eq!(chain.evaluate(&ScanRequest::new("a.txt", b"data")), FilterDecision::Continue);
+}
+
+#[test]
+fn scan_confidence_test() {
+    let eicar = br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    let mut ctx = AmsiContext::builder().app_name("Test").max_payload_size(1024).build().unwrap();
+    let mut filters = FilterChain::new();
+    filters.push(|req| if req.content_name().ends_with(".exe") { FilterDecision::Block } else { FilterDecision::Continue });
+    ctx.set_filter_chain(filters);
+
+    let session = ctx.create_session().unwrap();
+    match session.scan_confident("eicar-test.txt", eicar).unwrap() {
+        ScanConfidence::Scanned(result) => assert!(result.is_malware()),
+        other => panic!("unexpected outcome {:?}", other),
+    }
+    let blocked = session.scan_confident("tool.exe", b"MZ").unwrap();
+    assert!(matches!(blocked, ScanConfidence::Skipped(SkipReason::Filtered(FilterDecision::Block))));
+    assert!(blocked.result().is_malware());
+
+    // unlike regular scans, oversize payloads are reported as skipped.
+    let large = vec![b'a'; 2048];
+    assert!(session.scan_buffer("large.txt", &large).is_err());
+    let skipped = session.scan_confident("large.txt", &large).unwrap();
+    assert!(matches!(skipped, ScanConfidence::Skipped(SkipReason::TooLarge)));
+    assert!(!skipped.is_scanned());
+
+    let cached = ctx.create_cached_session().unwrap();
+    assert!(cached.scan_confident("eicar-test.txt", eicar).unwrap().is_scanned());
+    match cached.scan_confident("copy.txt", eicar).unwrap() {
+        ScanConfidence::Skipped(SkipReason::CacheHit(result)) => assert!(result.is_malware()),
+        other => panic!("unexpected outcome {:?}", other),
+    }
+}
+
+#[test]
+fn verdict_policy_test() {
+    let policy = VerdictPolicy::default();
+    assert_eq!(policy.verdict(&AmsiResult::new(0)), Verdict::Allow);
+    assert_eq!(policy.verdict(&AmsiResult::new(1)), Verdict::Allow);
+    assert_eq!(policy.verdict(&AmsiResult::new(0x4000)), Verdict::Block);
+    assert_eq!(policy.verdict(&AmsiResult::new(0x8000)), Verdict::Block);
+    assert_eq!(policy.verdict(&AmsiResult::new(0x5000)), Verdict::Review);
+
+    let lenient = VerdictPolicy{
+        blocked_by_admin: Verdict::Allow,
+        ..VerdictPolicy::default()
+    };
+    assert_eq!(lenient.verdict(&AmsiResult::new(0x4000)), Verdict::Allow);
+}
+
+#[test]
+fn result_debug_test() {
+    assert_eq!(format!("{:?}", AmsiResult::new(0x8000)), "AmsiResult { code: 0x8000, kind: Detected(0) }");
+    assert_eq!(format!("{:?}", AmsiResult::new(2)), "AmsiResult { code: 0x2, kind: Unknown(2) }");
+}
+
+#[test]
+fn result_display_test() {
+    assert_eq!(AmsiResult::new(0).to_string(), "Clean");
+    assert_eq!(AmsiResult::new(1).to_string(), "Not detected");
+    assert_eq!(AmsiResult::new(0x4001).to_string(), "Blocked by administrator policy");
+    assert_eq!(AmsiResult::new(0x8000).to_string(), "Detected (code 0x8000)");
+    assert_eq!(AmsiResult::new(2).to_string(), "Unknown result (code 0x2)");
+}
+
+#[test]
+fn result_eq_test() {
+    let a = AmsiResult::scanned(0x8000);
+    let b = AmsiResult::scanned(0x8000);
+    assert_ne!(a.correlation_id(), b.correlation_id());
+    assert_eq!(a, b);
+    assert!(AmsiResult::new(1) < AmsiResult::new(0x8000));
+
+    let unique: std::collections::HashSet<AmsiResult> = vec![a, b, AmsiResult::new(1)].into_iter().collect();
+    assert_eq!(unique.len(), 2);
+}
+
+#[test]
+fn win_error_display_test() {
+    let err = WinError::from_code(5);
+    assert!(err.message().is_some());
+    assert!(err.to_string().ends_with(" (0x5)"));
+    assert_eq!(WinError::from_code(0xdead_beef).to_string(), "Windows error 0xdeadbeef");
+}
+
+#[test]
+fn error_kind_test() {
+    assert_eq!(WinError::from_hresult(0x8007_0057).kind(), AmsiError::InvalidArg);
+    assert_eq!(WinError::from_hresult(0x8007_0005).kind(), AmsiError::AccessDenied);
+    assert_eq!(WinError::from_code(1722).kind(), AmsiError::RpcServerUnavailable);
+    assert!(WinError::from_code(1722).kind().is_transient());
+    assert_eq!(WinError::from_code(2).kind(), AmsiError::Other(0x8007_0002));
+}
+
+#[test]
+fn hresult_test() {
+    let err = WinError::from_hresult(0x8004_0154);
+    assert_eq!(err.hresult(), 0x8004_0154);
+    assert_eq!(err.facility(), 4);
+    assert!(err.is_failure());
+
+    let err = WinError::from_code(5);
+    assert_eq!(err.hresult(), 0x8007_0005);
+    assert_eq!(err.facility(), 7);
+    assert_ne!(WinError::from_hresult(0x8004_0005).hresult(), err.hresult());
+}
+
+#[test]
+fn io_error_test() {
+    let err: std::io::Error = WinError::from_code(5).into();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    assert_eq!(err.get_ref().and_then(|e| e.downcast_ref::<WinError>()).map(WinError::hresult), Some(0x8007_0005));
+
+    let err: std::io::Error = ScanError::Io(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "eof")).into();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+    fn scan(ctx: &AmsiContext) -> std::io::Result<AmsiResult> {
+        Ok(ctx.create_session()?.scan_string("io-test.txt", "Write-Host 'hello'")?)
+    }
+    assert!(!scan(&AmsiContext::new("Test").unwrap()).unwrap().is_malware());
+}
+
+#[test]
+fn error_operation_test() {
+    let err = WinError::from_code(5).during("AmsiScanBuffer").for_content("script.ps1");
+    assert_eq!(err.operation(), Some("AmsiScanBuffer"));
+    assert_eq!(err.content_name(), Some("script.ps1"));
+    assert!(err.to_string().starts_with("AmsiScanBuffer failed for \"script.ps1\": "));
+    assert!(WinError::from_code(5).during("AmsiOpenSession").to_string().starts_with("AmsiOpenSession failed: "));
+}
+
+#[test]
+fn scan_file_test() {
+    let path = std::env::temp_dir().join("amsi-scan-file-test.ps1");
+    std::fs::write(&path, b"Write-Host 'hello'").unwrap();
+
+    let ctx = AmsiContext::new("Test").unwrap();
+    let session = ctx.create_session().unwrap();
+    let too_large = session.scan_file_with_limit(&path, 16);
+    let res = session.scan_file(&path);
+    let _ = std::fs::remove_file(&path);
+
+    match too_large {
+        Err(ScanError::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::InvalidData),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert!(!res.unwrap().is_malware());
+}
+
+#[cfg(windows)]
+#[test]
+fn scan_file_handle_test() {
+    use std::io::{Seek, SeekFrom, Write};
+    use std::os::windows::io::AsRawHandle;
+
+    let path = std::env::temp_dir().join("amsi-scan-file-handle-test.txt");
+    let mut file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+    file.write_all(br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*").unwrap();
+    file.seek(SeekFrom::Start(3)).unwrap();
+
+    let ctx = AmsiContext::new("Test").unwrap();
+    let session = ctx.create_session().unwrap();
+    let res = unsafe { session.scan_file_handle("eicar-test.txt", file.as_raw_handle()) };
+    let position = file.stream_position().unwrap();
+    let too_large = unsafe { file::read_handle(file.as_raw_handle(), 16) };
+    drop(file);
+    let _ = std::fs::remove_file(&path);
+
+    assert!(res.unwrap().is_malware());
+    // the scan reads through a handle of its own.
+    assert_eq!(position, 3);
+    assert_eq!(too_large.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn scan_file_with_ads_test() {
+    let path = std::env::temp_dir().join("amsi-scan-file-ads-test.txt");
+    let mut stream = path.clone().into_os_string();
+    stream.push(":hidden");
+    let stream = std::path::PathBuf::from(stream);
+    std::fs::write(&path, b"Write-Host 'hello'").unwrap();
+    std::fs::write(&stream, br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*").unwrap();
+
+    let ctx = AmsiContext::new("Test").unwrap();
+    let session = ctx.create_session().unwrap();
+    let results = session.scan_file_with_ads(&path);
+    let _ = std::fs::remove_file(&path);
+
+    assert_eq!(results.len(), 2);
+    for (name, result) in results {
+        assert_eq!(result.unwrap().is_malware(), name == stream, "unexpected result for {}", name.display());
+        assert!(name == path || name == stream);
+    }
+
+    let missing = session.scan_file_with_ads(std::env::temp_dir().join("amsi-missing-file.txt"));
+    assert_eq!(missing.len(), 1);
+    assert!(missing[0].1.is_err());
+}
+
+#[test]
+fn streams_or_main_test() {
+    // volumes without streams support only get their main stream scanned.
+    let unsupported = file::streams_or_main(Err(WinError::from_code(50)));
+    assert_eq!(unsupported.unwrap(), vec![std::ffi::OsString::new()]);
+
+    let missing = file::streams_or_main(Err(WinError::from_code(2)));
+    assert_eq!(missing.unwrap_err().hresult(), sys::hresult_from_win32(2));
+    let missing = file::streams_or_main(Err(WinError::from_code(3)));
+    assert_eq!(missing.unwrap_err().hresult(), sys::hresult_from_win32(3));
+
+    let streams = vec![std::ffi::OsString::new(), "hidden".into()];
+    assert_eq!(file::streams_or_main(Ok(streams.clone())).unwrap(), streams);
+}
+
+#[test]
+fn clipboard_files_test() {
+    let path = std::env::temp_dir().join("amsi-clipboard-test.txt");
+    let missing = std::env::temp_dir().join("amsi-clipboard-missing.txt");
+    std::fs::write(&path, br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*").unwrap();
+
+    let ctx = AmsiContext::new("Test").unwrap();
+    let session = ctx.create_session().unwrap();
+    let scan = session.scan_copied_files(vec![missing.clone(), path.clone()]);
+    let _ = std::fs::remove_file(&path);
+
+    // the missing file doesn't keep the other one from being scanned.
+    assert!(scan.worst().unwrap().is_malware());
+    match scan {
+        ClipboardScan::Files(files) => {
+            assert_eq!(files.len(), 2);
+            assert_eq!(files[0].0, missing);
+            assert!(files[0].1.is_err());
+            assert_eq!(files[1].0, path);
+            assert!(files[1].1.as_ref().unwrap().is_malware());
+        },
+        ClipboardScan::Text(_) => panic!("files were scanned"),
+    }
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn scan_file_mmap_test() {
+    let path = std::env::temp_dir().join("amsi-scan-file-mmap-test.ps1");
+    let mut content = vec![b' '; 200 * 1024];
+    content.extend_from_slice(b"Write-Host 'hello'");
+    std::fs::write(&path, &content).unwrap();
+
+    let ctx = AmsiContext::new("Test").unwrap();
+    let session = ctx.create_session().unwrap();
+    let whole = session.scan_file_mmap(&path, 1024 * 1024);
+    let chunked = session.scan_file_mmap(&path, 64 * 1024);
+    let _ = std::fs::remove_file(&path);
+
+    assert!(!whole.unwrap().is_malware());
+    assert!(!chunked.unwrap().is_malware());
+}
+
+#[test]
+fn sha256_test() {
+    assert_eq!(sha256::to_hex(&sha256::sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    assert_eq!(sha256::to_hex(&sha256::sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+    let mut hasher = sha256::Sha256::new();
+    for _ in 0..1000 {
+        hasher.update(b"a");
+    }
+    let long: Vec<u8> = vec![b'a'; 1000];
+    assert_eq!(hasher.finish(), sha256::sha256(&long));
+}
+
+#[test]
+fn scan_chunks_test() {
+    let chunks: [&[u8]; 3] = [b"Write-Host 'hello'\n", br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*", b"\n"];
+
+    let ctx = AmsiContext::new("Test").unwrap();
+    let s = ctx.create_session().unwrap();
+    assert!(s.scan_chunks("test.ps1", chunks.iter().cloned()).unwrap().is_malware());
+    assert!(s.scan_chunks("empty.ps1", std::iter::empty()).unwrap().is_clean());
+}
+
+#[test]
+fn scan_reader_test() {
+    let ctx = AmsiContext::new("Test").unwrap();
+    let session = ctx.create_session().unwrap();
+    let mut content = b"Write-Host 'hello'\n".to_vec();
+    content.extend_from_slice(br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*");
+
+    assert!(session.scan_reader("eicar-test.txt", &content[..], 4096).unwrap().is_malware());
+    assert!(!session.scan_reader("hello.ps1", &b"Write-Host 'hello'"[..], 4).unwrap().is_malware());
+    assert!(session.scan_reader("empty.txt", std::io::empty(), 4096).unwrap().is_clean());
+}
+
+#[test]
+fn scan_slices_test() {
+    let ctx = AmsiContext::new("Test").unwrap();
+    let session = ctx.create_session().unwrap();
+    let eicar = br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    let body = vec![b' '; SMALL_SCAN_THRESHOLD * 2];
+
+    assert!(session.scan_slices("mail.eml", &[b"Subject: hi\r\n", b"\r\n", &body, eicar]).unwrap().is_malware());
+    assert!(!session.scan_slices("mail.eml", &[b"Subject: hi\r\n", b"\r\n", &body]).unwrap().is_malware());
+    assert!(session.scan_slices("empty.eml", &[]).unwrap().is_clean());
+}
+
+#[test]
+fn name_cache_test() {
+    let cache = name::NameCache::default();
+    let first = cache.encode(&"script.ps1", &"script.ps1".to_str_lossy());
+    let second = cache.encode(&"script.ps1", &"script.ps1".to_str_lossy());
+    assert_eq!(first.as_ptr(), second.as_ptr());
+    assert_eq!(&*first, &*"script.ps1".to_wide());
+    assert_eq!(cache.len(), 1);
+
+    let ctx = AmsiContext::new("Test").unwrap();
+    let session = ctx.create_session().unwrap();
+    for _ in 0..3 {
+        session.scan_string("snippet.ps1", "Write-Host 'hello'").unwrap();
+    }
+    assert_eq!(ctx.names.len(), 1);
+}
+
+#[test]
+fn encoded_name_test() {
+    let name = ContentName::new(std::path::Path::new("script.ps1"));
+    assert_eq!(name.as_str(), "script.ps1");
+    assert_eq!(&*(&name).to_wide(), &*"script.ps1".to_wide());
+    assert_eq!(AppName::new("Test").as_wide(), &[b'T' as u16, b'e' as u16, b's' as u16, b't' as u16, 0][..]);
+
+    let ctx = AmsiContext::builder().app_name(AppName::new("Test")).build().unwrap();
+    assert_eq!(ctx.app_name().as_str(), "Test");
+    let session = ctx.create_session().unwrap();
+    let eicar = r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    let name = ContentName::new("eicar-test.txt");
+    assert!(session.scan_string(&name, eicar).unwrap().is_malware());
+    assert!(session.scan_buffer(&name, eicar.as_bytes()).unwrap().is_malware());
+    // pre-encoded names bypass the cache.
+    assert_eq!(ctx.names.len(), 0);
+}
+
+#[test]
+fn scan_wide_test() {
+    let ctx = AmsiContext::new("Test").unwrap();
+    let session = ctx.create_session().unwrap();
+    let wide = |s: &str| -> Vec<u16> { s.encode_utf16().collect() };
+    let eicar = wide(r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*");
+
+    assert!(session.scan_wide(&wide("eicar-test.txt"), &eicar).unwrap().is_malware());
+    let mut terminated = eicar.clone();
+    terminated.push(0);
+    assert!(session.scan_wide(&wide("eicar-test.txt\0"), &terminated).unwrap().is_malware());
+    assert!(session.scan_wide(&wide("blank.txt"), &wide("  ")).unwrap().is_clean());
+
+    let mut hidden = wide("Write-Host 'hello'\0");
+    hidden.extend_from_slice(&eicar);
+    match session.scan_wide(&wide("hidden.ps1"), &hidden) {
+        Err(ScanError::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput),
+        other => panic!("expected the interior nul to be rejected, got {:?}", other),
+    }
+}
+
+#[test]
+fn scannable_test() {
+    let ctx = AmsiContext::new("Test").unwrap();
+    let session = ctx.create_session().unwrap();
+    let eicar = r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+    assert!(session.scan("eicar-test.txt", eicar).unwrap().is_malware());
+    assert!(session.scan("eicar-test.txt", &eicar.to_owned()).unwrap().is_malware());
+    assert!(session.scan("eicar-test.txt", eicar.as_bytes()).unwrap().is_malware());
+    assert!(session.scan("eicar-test.txt", FromReader(eicar.as_bytes())).unwrap().is_malware());
+
+    let path = std::env::temp_dir().join("amsi-scannable-test.txt");
+    std::fs::write(&path, "Nothing wrong with this.").unwrap();
+    let result = session.scan("clean.txt", &path);
+    std::fs::remove_file(&path).unwrap();
+    assert!(!result.unwrap().is_malware());
+}
+
+#[test]
+fn sessionless_scan_test() {
+    let ctx = AmsiContext::new("Test").unwrap();
+    let eicar = r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    assert!(ctx.scan_string("eicar-test.txt", eicar).unwrap().is_malware());
+    assert!(ctx.scan_buffer("eicar-test.txt", eicar.as_bytes()).unwrap().is_malware());
+    assert!(!ctx.scan_string("hello.ps1", "Write-Host 'hello'").unwrap().is_malware());
+}
+
+#[test]
+fn content_name_test() {
+    let path = std::path::Path::new(r"C:\scripts\eicar-test.txt");
+    assert_eq!(path.to_str_lossy(), r"C:\scripts\eicar-test.txt");
+    assert_eq!(&*"a.ps1".to_wide(), &[b'a' as u16, b'.' as u16, b'p' as u16, b's' as u16, b'1' as u16, 0][..]);
+
+    let ctx = AmsiContext::new("Test").unwrap();
+    let session = ctx.create_session().unwrap();
+    let eicar = r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    assert!(session.scan_string(path, eicar).unwrap().is_malware());
+    assert!(session.scan_buffer(std::ffi::OsStr::new("eicar-test.txt"), eicar.as_bytes()).unwrap().is_malware());
+}
+
+#[test]
+fn context_builder_test() {
+    assert_eq!(AmsiContext::builder().build().unwrap_err().hresult(), 0x8007_0057);
+
+    let ctx = AmsiContext::builder()
+        .app_guid(&Guid::from_u128(0x2781761e_28e0_4109_99fe_b9d127c57afe))
+        .chunk_size(4096)
+        .max_payload_size(1024)
+        .retry_policy(RetryPolicy::constant(3, std::time::Duration::from_millis(10)))
+        .default_session(true)
+        .build()
+        .unwrap();
+    assert_eq!(ctx.chunk_size(), 4096);
+
+    let eicar = r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    assert!(ctx.scan_string("eicar-test.txt", eicar).unwrap().is_malware());
+    match ctx.scan_buffer("large.bin", &[0; 2048]) {
+        Err(ScanError::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput),
+        other => panic!("expected the payload to be rejected, got {:?}", other),
+    }
+}
+
+#[test]
+fn owned_session_test() {
+    struct Scanner {
+        session: OwnedAmsiSession,
+    }
+
+    let ctx = Arc::new(AmsiContext::new("Test").unwrap());
+    let scanner = Scanner{ session: ctx.create_owned_session().unwrap() };
+    drop(ctx);
+
+    let eicar = r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    assert!(scanner.session.scan_string("eicar-test.txt", eicar).unwrap().is_malware());
+    assert_eq!(Arc::strong_count(scanner.session.context()), 1);
+}
+
+#[test]
+fn context_clone_test() {
+    let ctx = AmsiContext::new("Test").unwrap();
+    let mut clone = ctx.clone();
+    clone.set_verdict_policy(VerdictPolicy{
+        not_detected: Verdict::Block,
+        ..VerdictPolicy::default()
+    });
+    assert!(!ctx.should_block(&AmsiResult::new(1)));
+    assert!(clone.should_block(&AmsiResult::new(1)));
+
+    drop(ctx);
+    let eicar = r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    assert!(clone.create_session().unwrap().scan_string("eicar-test.txt", eicar).unwrap().is_malware());
+}
+
+#[test]
+fn concurrent_scan_test() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<AmsiContext>();
+    assert_send_sync::<AmsiSession>();
+    assert_send_sync::<OwnedAmsiSession>();
+
+    let eicar = r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    let ctx = Arc::new(AmsiContext::new("Test").unwrap());
+    let threads: Vec<_> = (0..4).map(|i| {
+        let ctx = ctx.clone();
+        std::thread::spawn(move || {
+            let session = ctx.create_session().unwrap();
+            for _ in 0..16 {
+                assert!(session.scan_string(format!("eicar-{}.txt", i).as_str(), eicar).unwrap().is_malware());
+                assert!(!ctx.scan_buffer("clean.txt", b"Nothing wrong with this.").unwrap().is_malware());
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    // a single session, shared by several threads.
+    let session = ctx.create_session().unwrap();
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| assert!(session.scan_buffer("eicar-test.txt", eicar.as_bytes()).unwrap().is_malware()));
+        }
+    });
+
+    let owned = ctx.create_owned_session().unwrap();
+    std::thread::spawn(move || assert!(owned.scan_string("eicar-test.txt", eicar).unwrap().is_malware())).join().unwrap();
+}
+
+#[test]
+fn session_pool_test() {
+    let ctx = Arc::new(AmsiContext::new("Test").unwrap());
+    let mut pool = AmsiSessionPool::new(ctx, 2);
+    pool.set_max_uses(2);
+    let eicar = r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+    {
+        let first = pool.get().unwrap();
+        let _second = pool.get().unwrap();
+        assert!(pool.try_get().is_none());
+        assert!(first.scan_string("eicar-test.txt", eicar).unwrap().is_malware());
+    }
+    assert_eq!(pool.idle(), 2);
+
+    // both sessions were used twice now, and are retired.
+    drop((pool.get().unwrap(), pool.get().unwrap()));
+    assert_eq!(pool.idle(), 0);
+
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..8 {
+                    assert!(pool.get().unwrap().scan_buffer("eicar-test.txt", eicar.as_bytes()).unwrap().is_malware());
+                }
+            });
+        }
+    });
+    assert!(pool.idle() <= pool.size());
+}
+
+#[test]
+fn session_pool_shutdown_test() {
+    use std::time::Duration;
+
+    let ctx = Arc::new(AmsiContext::new("Test").unwrap());
+    let pool = AmsiSessionPool::new(ctx, 2);
+    drop(pool.get().unwrap());
+    assert_eq!(pool.session_count(), 1);
+
+    std::thread::scope(|scope| {
+        let in_flight = pool.get().unwrap();
+        let idle = pool.get().unwrap();
+        drop(idle);
+        scope.spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            drop(in_flight);
+        });
+        assert!(pool.shutdown(Duration::from_secs(10)));
+    });
+    assert_eq!(pool.session_count(), 0);
+    assert_eq!(pool.idle(), 0);
+    assert!(pool.get().is_err());
+    assert!(pool.try_get().unwrap().is_err());
+
+    // a session that isn't returned in time is closed when its guard is dropped.
+    let pool = AmsiSessionPool::new(Arc::new(AmsiContext::new("Test").unwrap()), 2);
+    let held = pool.get().unwrap();
+    assert!(!pool.shutdown(Duration::from_millis(10)));
+    assert_eq!(pool.session_count(), 1);
+    drop(held);
+    assert_eq!(pool.session_count(), 0);
+}
+
+#[test]
+fn scan_stream_channel_test() {
+    use std::sync::mpsc::channel;
+
+    let pool = AmsiSessionPool::new(Arc::new(AmsiContext::new("Test").unwrap()), 4);
+    let eicar = r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    let items: Vec<(String, &[u8])> = (0..16)
+        .map(|i| (format!("item-{}.txt", i), if i % 2 == 0 { eicar.as_bytes() } else { &b"Hello"[..] }))
+        .collect();
+
+    let (sender, receiver) = channel();
+    pool.scan_stream_channel(items.clone(), sender).unwrap();
+    let mut results: Vec<_> = receiver.iter().collect();
+    results.sort_by_key(|(name, _)| name[5..name.len() - 4].parse::<usize>().unwrap());
+    assert_eq!(results.len(), 16);
+    for (i, (name, result)) in results.into_iter().enumerate() {
+        assert_eq!(name, format!("item-{}.txt", i));
+        assert_eq!(result.unwrap().is_malware(), i % 2 == 0);
+    }
+    assert_eq!(pool.idle(), pool.session_count());
+
+    // a dropped receiver aborts the scan.
+    let (sender, receiver) = channel();
+    drop(receiver);
+    let token = CancellationToken::new();
+    pool.scan_stream_channel_cancellable(items, sender, &token).unwrap();
+    assert!(token.is_cancelled());
+}
+
+#[test]
+fn batch_test() {
+    let ctx = AmsiContext::new("Test").unwrap();
+    let eicar = br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    let items: Vec<(String, &[u8])> = (0..32)
+        .map(|i| if i % 4 == 0 { (format!("eicar-{}.txt", i), &eicar[..]) } else { (format!("clean-{}.txt", i), &b"Nothing wrong with this."[..]) })
+        .collect();
+
+    let results = batch::scan_all_with(&ctx, items.iter().map(|&(ref name, data)| (name.as_str(), data)), 4).unwrap();
+    assert_eq!(results.len(), items.len());
+    for (i, (name, result)) in results.into_iter().enumerate() {
+        assert_eq!(name, items[i].0);
+        assert_eq!(result.unwrap().is_malware(), i % 4 == 0);
+    }
+    assert!(batch::scan_all(&ctx, Vec::<(&str, &[u8])>::new()).unwrap().is_empty());
+}
+
+/// Polls a future to completion on the calling thread.
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    struct ThreadWaker(std::thread::Thread);
+
+    impl std::task::Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = std::task::Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = std::task::Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        std::thread::park();
+    }
+}
+
+#[test]
+fn async_scan_test() {
+    let ctx = AmsiContext::new("Test").unwrap();
+    let eicar = r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+    let string = ctx.scan_string_async("eicar-test.txt", eicar);
+    let buffer = ctx.scan_buffer_async("eicar-test.txt", eicar.as_bytes());
+    assert!(block_on(string).unwrap().is_malware());
+    assert!(block_on(buffer).unwrap().is_malware());
+
+    let path = std::env::temp_dir().join("amsi-async-test.txt");
+    std::fs::write(&path, "Nothing wrong with this.").unwrap();
+    let result = block_on(ctx.scan_file_async(&path));
+    std::fs::remove_file(&path).unwrap();
+    assert!(!result.unwrap().is_malware());
+}
+
+#[test]
+fn scan_timeout_test() {
+    let ctx = AmsiContext::new("Test").unwrap();
+    let eicar = br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    let timeout = std::time::Duration::from_secs(30);
+    assert!(ctx.scan_buffer_with_timeout("eicar-test.txt", eicar, timeout).unwrap().is_malware());
+    assert!(ctx.create_session().unwrap().scan_buffer_with_timeout("eicar-test.txt", eicar, timeout).unwrap().is_malware());
+
+    let err = ScanError::TimedOut(std::time::Duration::from_millis(250));
+    assert_eq!(err.to_string(), "scan timed out after 250ms");
+    assert_eq!(std::io::Error::from(err).kind(), std::io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn cancellation_test() {
+    let ctx = AmsiContext::new("Test").unwrap();
+    let token = CancellationToken::new();
+    let mut session = ctx.create_session().unwrap();
+    session.set_cancellation_token(token.clone());
+    assert!(!session.scan_string("hello.ps1", "Write-Host 'hello'").unwrap().is_malware());
+
+    token.cancel();
+    assert!(token.is_cancelled());
+    assert!(matches!(session.scan_string("hello.ps1", "Write-Host 'hello'"), Err(ScanError::Cancelled)));
+    assert!(matches!(session.scan_reader("upload.bin", &[0u8; 1024][..], 256), Err(ScanError::Cancelled)));
+
+    let items = vec![("a.txt", &b"a"[..]), ("b.txt", &b"b"[..])];
+    for (_, result) in batch::scan_all_cancellable(&ctx, items, 2, &token).unwrap() {
+        assert!(matches!(result, Err(ScanError::Cancelled)));
+    }
+}
+
+#[test]
+fn retry_policy_test() {
+    use std::time::Duration;
+
+    let clock = MockClock::new();
+    let policy = RetryPolicy::exponential(4, Duration::from_millis(100));
+    let mut calls = 0;
+    // fails twice with RPC_S_SERVER_UNAVAILABLE, then succeeds.
+    let hres = policy.run(&clock, || { calls += 1; if calls < 3 { 0x8007_06ba } else { 0 } });
+    assert_eq!((hres, calls), (0, 3));
+    assert_eq!(clock.elapsed(), Duration::from_millis(300));
+
+    // E_INVALIDARG isn't transient.
+    calls = 0;
+    assert_eq!(policy.run(&clock, || { calls += 1; 0x8007_0057 }), 0x8007_0057);
+    assert_eq!(calls, 1);
+
+    calls = 0;
+    assert_eq!(policy.run(&clock, || { calls += 1; 0x8007_06be }), 0x8007_06be);
+    assert_eq!(calls, 4);
+
+    assert_eq!(RetryPolicy::constant(3, Duration::from_millis(50)).delay(2), Duration::from_millis(50));
+    assert_eq!(RetryPolicy::exponential(20, Duration::from_secs(1)).delay(10), Duration::from_secs(30));
+
+    let mut ctx = AmsiContext::new("Test").unwrap();
+    ctx.set_retry_policy(policy);
+    assert!(!ctx.create_session().unwrap().scan_string("hello.ps1", "Write-Host 'hello'").unwrap().is_malware());
+}
+
+#[test]
+fn recovery_test() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    assert!(WinError::from_code(1722).kind().is_fatal());
+    assert!(WinError::from_hresult(0x8001_0108).kind().is_fatal());
+    assert!(!WinError::from_hresult(0x8007_06bb).kind().is_fatal());
+    assert!(!WinError::from_hresult(0x8007_0057).kind().is_fatal());
+
+    let events = Arc::new(AtomicUsize::new(0));
+    let handler_events = events.clone();
+    let mut ctx = AmsiContext::builder().app_name("Test").recovery(true).build().unwrap();
+    ctx.set_recovery_handler(move |event| {
+        assert!(event.recovered());
+        assert_eq!(event.error().kind(), AmsiError::RpcServerUnavailable);
+        handler_events.fetch_add(1, Ordering::SeqCst);
+    });
+    let clone = ctx.clone();
+    let stale = ctx.handle.get();
+
+    // the first attempt fails like a scan against a restarted service, the replay runs on a new context.
+    let calls = AtomicUsize::new(0);
+    let result = ctx.recovering(|handle| {
+        if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+            Err(ScanError::Win(WinError::from_code(1722)))
+        } else {
+            Ok(handle.ctx)
+        }
+    });
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    assert_eq!(events.load(Ordering::SeqCst), 1);
+    assert_ne!(result.unwrap(), stale.ctx);
+    assert!(!Arc::ptr_eq(&clone.handle.get(), &stale));
+
+    // other failures are returned as they are.
+    assert!(ctx.recovering(|_| Err::<(), _>(ScanError::Win(WinError::from_hresult(0x8007_0057)))).is_err());
+    assert_eq!(events.load(Ordering::SeqCst), 1);
+    assert!(!clone.scan_string("hello.ps1", "Write-Host 'hello'").unwrap().is_malware());
+}
+
+#[test]
+fn cached_session_test() {
+    let malicious = r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    let ctx = AmsiContext::new("Test").unwrap();
+    let cached = ctx.create_cached_session().unwrap();
+
+    let first = cached.scan_string("eicar.txt", malicious).unwrap();
+    assert!(first.is_malware());
+    // a hit returns the result of the first scan, under any name.
+    assert_eq!(cached.scan_string("copy.txt", malicious).unwrap().correlation_id(), first.correlation_id());
+    assert_eq!(cached.len(), 1);
+
+    let buffer = cached.scan_buffer("eicar.bin", malicious.as_bytes()).unwrap();
+    assert_ne!(buffer.correlation_id(), first.correlation_id());
+    assert_eq!(cached.len(), 2);
+
+    // empty content is decided without the provider, and isn't cached.
+    cached.scan_string("empty.txt", "  ").unwrap();
+    assert_eq!(cached.len(), 2);
+
+    assert!(cached.invalidate_payload(malicious.as_bytes()));
+    assert!(cached.is_empty());
+    assert!(!cached.refresh());
+    cached.scan_string("eicar.txt", malicious).unwrap();
+    cached.invalidate();
+    assert!(cached.is_empty());
+}
+
+#[test]
+fn etw_scan_event_test() {
+    let wide = |s: &str| -> Vec<u8> { s.encode_utf16().chain(std::iter::once(0)).flat_map(|c| c.to_le_bytes().to_vec()).collect() };
+
+    let mut data = Vec::new();
+    data.extend_from_slice(&0x1234u64.to_le_bytes());
+    data.push(1);
+    data.extend_from_slice(&0x8000u32.to_le_bytes());
+    data.extend(wide("PowerShell_C:\\Windows\\System32\\powershell.exe_10.0"));
+    data.extend(wide("script.ps1"));
+    data.extend_from_slice(&3u32.to_le_bytes());
+    data.extend_from_slice(&100u32.to_le_bytes());
+    data.extend_from_slice(b"abc");
+    data.extend_from_slice(&[0x5a; 32]);
+    data.extend_from_slice(&1u32.to_le_bytes());
+
+    let scan = etw::TraceScan::parse(&data, 8).unwrap();
+    assert_eq!(scan.session(), 0x1234);
+    assert!(scan.scanned());
+    assert!(scan.result().is_malware());
+    assert!(scan.app_name().starts_with("PowerShell_"));
+    assert_eq!(scan.content_name(), "script.ps1");
+    assert_eq!((scan.content_size(), scan.original_size()), (3, 100));
+    assert_eq!(scan.content(), b"abc");
+    assert_eq!(scan.hash(), &[0x5a; 32][..]);
+    assert!(scan.content_filtered());
+
+    // 32-bit processes write 32-bit session pointers.
+    assert_eq!(etw::TraceScan::parse(&data[4..], 4).unwrap().content_name(), "script.ps1");
+    assert!(etw::TraceScan::parse(&data[..data.len() - 1], 8).is_none());
+}
+
+#[test]
+fn event_log_message_test() {
+    use std::sync::Mutex;
+
+    struct Capture(Arc<Mutex<Vec<AuditRecord>>>);
+
+    impl AuditStore for Capture {
+        fn record(&self, record: &AuditRecord) -> std::io::Result<()> {
+            self.0.lock().unwrap().push(record.clone());
+            Ok(())
+        }
+    }
+
+    let malicious = br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let mut ctx = AmsiContext::new("Test").unwrap();
+    ctx.set_audit_store(Box::new(Capture(records.clone())));
+    ctx.create_session().unwrap().scan_buffer_audited("eicar.txt", malicious).unwrap();
+
+    let message = eventlog::detection_message(&records.lock().unwrap()[0]);
+    assert!(message.contains("\"eicar.txt\""));
+    assert!(message.contains(&sha256::to_hex(&sha256::sha256(malicious))));
+    assert!(message.contains("Result code: 0x8000"));
+}
+
+#[test]
+fn detection_store_test() {
+    use std::sync::Mutex;
+
+    struct Capture(Arc<Mutex<Vec<AuditRecord>>>);
+
+    impl AuditStore for Capture {
+        fn record(&self, record: &AuditRecord) -> std::io::Result<()> {
+            self.0.lock().unwrap().push(record.clone());
+            Ok(())
+        }
+    }
+
+    let malicious = r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let mut ctx = AmsiContext::new("Test").unwrap();
+    ctx.set_detection_store(Box::new(Capture(records.clone())));
+
+    // every scan function reaches the store, not only the audited ones.
+    let session = ctx.create_session().unwrap();
+    let buffer = session.scan_buffer("eicar.bin", malicious.as_bytes()).unwrap();
+    ctx.scan_string("eicar.txt", malicious).unwrap();
+    session.scan_string("clean.txt", "Write-Host 'hello'").unwrap();
+
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].content_name(), "eicar.bin");
+    assert_eq!(records[0].sha256(), &sha256::sha256(malicious.as_bytes()));
+    assert_eq!(records[0].correlation_id(), buffer.correlation_id());
+    assert_eq!(records[1].content_name(), "eicar.txt");
+    assert_eq!(records[1].size(), malicious.len() * 2);
+}
+
+#[test]
+fn scan_metrics_test() {
+    use std::time::Duration;
+
+    let metrics = ScanMetrics::new();
+    assert_eq!(metrics.snapshot(), MetricsSnapshot::default());
+
+    for ms in 1..=100 {
+        metrics.record(0, 0, Duration::from_millis(ms));
+    }
+    metrics.record(0, 0x8000, Duration::from_millis(1));
+    metrics.record(0, 0x4001, Duration::from_millis(1));
+    metrics.record(0x8007_06ba, 0, Duration::from_millis(1));
+
+    let snapshot = metrics.snapshot();
+    assert_eq!((snapshot.scans, snapshot.detections, snapshot.blocked_by_admin, snapshot.failures), (103, 1, 1, 1));
+    assert_eq!(snapshot.max_latency, Duration::from_millis(100));
+    // percentiles are rounded up to the next power of two microseconds.
+    assert!(snapshot.p50_latency >= Duration::from_millis(48) && snapshot.p50_latency <= Duration::from_micros(65536));
+    assert_eq!(snapshot.p99_latency, Duration::from_millis(100));
+
+    let metrics = Arc::new(metrics);
+    metrics.reset();
+    let mut ctx = AmsiContext::new("Test").unwrap();
+    ctx.set_metrics(metrics.clone());
+    ctx.scan_string("hello.ps1", "Write-Host 'hello'").unwrap();
+    assert_eq!(metrics.snapshot().scans, 1);
+}
+
+#[test]
+#[cfg(feature = "prometheus")]
+fn prometheus_format_test() {
+    use std::time::Duration;
+
+    let metrics = ScanMetrics::new();
+    metrics.record(0, 0x8000, Duration::from_millis(2));
+    metrics.record(0x8007_06ba, 0, Duration::from_millis(3));
+
+    let mut text = Vec::new();
+    metrics.snapshot().write_prometheus(&mut text, "mail\"scanner").unwrap();
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.contains("# TYPE amsi_scans_total counter\namsi_scans_total{app=\"mail\\\"scanner\"} 2\n"));
+    assert!(text.contains("amsi_detections_total{app=\"mail\\\"scanner\"} 1\n"));
+    assert!(text.contains("amsi_scan_duration_seconds_sum{app=\"mail\\\"scanner\"} 0.005\n"));
+    assert!(text.ends_with("amsi_scan_duration_seconds_count{app=\"mail\\\"scanner\"} 2\n"));
+}
+
+#[test]
+fn providers_test() {
+    for provider in providers().unwrap() {
+        assert!(provider.clsid().starts_with('{'));
+        if let Some(path) = provider.dll_path() {
+            assert!(!path.to_string_lossy().contains('%'));
+        }
+    }
+}
+
+#[test]
+fn availability_test() {
+    let availability = is_available();
+    assert!(availability.is_dll_loadable());
+    assert!(availability.initialize_error().is_none());
+    assert_eq!(availability.is_usable(), !availability.providers().is_empty());
+}
+
+#[test]
+fn integrity_test() {
+    let _ctx = AmsiContext::new("Test").unwrap();
+    let report = integrity::check().unwrap();
+    assert!(report.is_loaded());
+    assert_eq!(report.findings(), &[]);
+}
+
+#[test]
+#[cfg(feature = "defender")]
+fn defender_versions_test() {
+    let versions = defender_versions().unwrap();
+    assert!(versions.engine().is_some());
+    assert!(versions.antivirus_signatures().is_some());
+}
+
+#[test]
+fn rescan_queue_test() {
+    use std::sync::atomic::AtomicUsize;
+
+    let eicar = r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    let ctx = AmsiContext::new("Test").unwrap();
+    // the stored content turned malicious after it was scanned.
+    let mut queue = RescanQueue::new(&ctx, move |_: &u32| Ok(eicar.as_bytes().to_vec()));
+    let changes = Arc::new(AtomicUsize::new(0));
+    let counter = changes.clone();
+    queue.set_change_handler(move |key, result| {
+        assert_eq!(*key, 1);
+        assert!(result.is_malware());
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+
+    // recorded directly, the installed provider may report the content as clean rather than not detected.
+    let result = AmsiResult::new(sys::AMSI_RESULT_NOT_DETECTED);
+    queue.record(1, "stored.txt", b"Nothing wrong with this.", &result);
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue.rescan().unwrap(), 1);
+    assert_eq!(changes.load(Ordering::SeqCst), 1);
+    assert!(queue.is_empty());
+
+    // items recorded with the same content are told apart by their current content.
+    let clean = b"Nothing wrong with this.";
+    let queue = RescanQueue::new(&ctx, move |key: &u32| Ok(if *key == 2 { eicar.as_bytes().to_vec() } else { clean.to_vec() }));
+    for key in 0..4 {
+        queue.record(key, "stored.txt", clean, &result);
+    }
+    assert_eq!(queue.rescan().unwrap(), 1);
+    assert_eq!(queue.len(), 3);
+    assert!(!queue.remove(&2));
+}
+
+#[test]
+fn self_test_test() {
+    let outcome = self_test(std::time::Duration::from_secs(10));
+    assert!(outcome.is_healthy(), "{}", outcome);
+}
+
+#[test]
+fn detached_stream_test() {
+    use stream::{AmsiStream, BufferSource, IAmsiStreamVtbl};
+
+    let data = b"borrowed content".to_vec();
+    let stream = AmsiStream::new(&[0], "test.txt", &ScanAttributes::default(), Box::new(BufferSource::new(&data)));
+    let raw = stream.as_raw();
+    unsafe {
+        // a provider that keeps the stream past the scan.
+        let vtbl = &**(raw as *const *const IAmsiStreamVtbl);
+        (vtbl.unknown.add_ref)(raw);
+        drop(stream);
+        drop(data);
+
+        let mut buf = [0u8; 4];
+        let mut read = 0;
+        assert_eq!((vtbl.read)(raw, 0, buf.len() as u32, buf.as_mut_ptr(), &mut read), com::E_FAIL);
+        assert_eq!(read, 0);
+        let mut size = 0;
+        assert_eq!((vtbl.get_attribute)(raw, stream::AMSI_ATTRIBUTE_CONTENT_SIZE, 0, std::ptr::null_mut(), &mut size), com::E_FAIL);
+        assert_eq!((vtbl.unknown.release)(raw), 0);
+    }
+}
+
+#[test]
+fn stream_fallback_test() {
+    // a provider that doesn't implement reading through `IAmsiStream::Read`.
+    struct BufferOnlyProvider;
+
+    impl provider::Provider for BufferOnlyProvider {
+        fn display_name(&self) -> String {
+            "Buffer Only Provider".to_owned()
+        }
+
+        fn supports_streams(&self) -> bool {
+            false
+        }
+
+        fn scan(&self, stream: &provider::ProviderStream) -> provider::ProviderVerdict {
+            match stream.content() {
+                Some(content) if content.starts_with(b"X5O!") => provider::ProviderVerdict::Detected,
+                Some(_) => provider::ProviderVerdict::NotDetected,
+                None => panic!("content must be in memory"),
+            }
+        }
+    }
+
+    let harness = provider::ProviderHarness::new(Box::new(BufferOnlyProvider));
+    let mut reader = std::io::Cursor::new(br"skipX5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*".to_vec());
+    reader.set_position(4);
+    assert!(harness.scan_stream("eicar-test.txt", reader).unwrap().is_malware());
+    assert!(!harness.scan_stream("clean.txt", std::io::Cursor::new(b"Write-Host 'hello'".to_vec())).unwrap().is_malware());
+}
+
+#[test]
+fn scan_or_defer_test() {
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    let ctx = AmsiContext::new("Test").unwrap();
+    let session = ctx.create_session().unwrap();
+    let eicar = br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+    match session.scan_or_defer("eicar-test.txt", eicar, Duration::from_secs(30), |_| panic!("the scan wasn't deferred")) {
+        LatencyScan::Completed(result) => assert!(result.unwrap().is_malware()),
+        LatencyScan::Deferred => panic!("the scan didn't complete within the budget"),
+    }
+
+    // without a budget, the result is usually passed to the callback.
+    let (sender, receiver) = channel();
+    match session.scan_or_defer(std::path::Path::new("eicar-test.txt"), eicar, Duration::from_secs(0), move |result| sender.send(result).unwrap()) {
+        LatencyScan::Completed(result) => assert!(result.unwrap().is_malware()),
+        LatencyScan::Deferred => assert!(receiver.recv_timeout(Duration::from_secs(30)).unwrap().unwrap().is_malware()),
+    }
+}
+
+#[test]
+fn scan_registry_value_test() {
+    use registry::{HKEY_CURRENT_USER, RegKey};
+
+    let path = r"Software\amsi-rs-test\scan-registry-value";
+    let key = RegKey::create(HKEY_CURRENT_USER, path).unwrap();
+    key.set_string_value("Script", r"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*").unwrap();
+    key.set_string_value("", "Write-Host 'hello'").unwrap();
+    key.set_dword_value("Count", 1).unwrap();
+    drop(key);
+
+    let ctx = AmsiContext::new("Test").unwrap();
+    let session = ctx.create_session().unwrap();
+    let hive_path = format!(r"HKCU\{}", path);
+    let script = session.scan_registry_value(&hive_path, "Script");
+    let default = session.scan_registry_value(&hive_path, "");
+    let dword = session.scan_registry_value(&hive_path, "Count");
+    let missing = session.scan_registry_value(&hive_path, "Missing");
+    let unknown_root = session.scan_registry_value(r"HKXX\Software", "Script");
+    let _ = registry::delete_tree(HKEY_CURRENT_USER, r"Software\amsi-rs-test");
+
+    assert!(script.unwrap().is_malware());
+    assert!(!default.unwrap().is_malware());
+    match dword {
+        Err(ScanError::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert!(missing.is_err());
+    assert!(unknown_root.is_err());
+}
+
+#[test]
+fn config_fingerprint_test() {
+    let mut ctx = AmsiContext::new("Test").unwrap();
+    let fingerprint = ctx.config_fingerprint();
+    assert_eq!(fingerprint, ctx.config_fingerprint());
+
+    ctx.set_verdict_policy(VerdictPolicy{
+        not_detected: Verdict::Block,
+        ..VerdictPolicy::default()
+    });
+    assert_ne!(fingerprint, ctx.config_fingerprint());
+}
+
+#[test]
+fn scan_events_test() {
+    let ctx = AmsiContext::new("Test").unwrap();
+    let s = ctx.create_session().unwrap();
+    let events: Vec<ScanEvent> = s.scan_events("test.txt", b"hello").collect();
+
+    assert_eq!(events.len(), 3);
+    assert!(matches!(events[0], ScanEvent::Started));
+    assert!(matches!(events[1], ScanEvent::Progress(5)));
+    assert!(matches!(events[2], ScanEvent::Completed(ref result) if !result.is_malware()));
+}
+
+#[test]
+fn correlation_id_test() {
+    let ctx = AmsiContext::new("Test").unwrap();
+    let s = ctx.create_session().unwrap();
+    let first = s.scan_string("a.txt", "hello").unwrap().correlation_id();
+    let second = s.scan_buffer("b.txt", b"hello").unwrap().correlation_id();
+
+    assert!(first.is_some());
+    assert!(second.is_some());
+    assert_ne!(first, second);
+    assert_eq!(s.scan_string("empty.txt", " ").unwrap().correlation_id(), None);
+}
+
+#[test]
+fn grouped_report_test() {
+    let mut builder = ScanReportBuilder::new();
+    builder.add("application/pdf", AmsiResult::new(0))
+        .add("text/plain; charset=utf-8", AmsiResult::new(1))
+        .add("Application/PDF", AmsiResult::new(0x8000))
+        .add("application/pdf", AmsiResult::new(1));
+    let report = builder.build();
+
+    assert_eq!(report.groups().len(), 2);
+    assert_eq!(report.groups()[0].mime_type(), "application/pdf");
+    assert_eq!(report.groups()[0].count(), 3);
+    assert_eq!(report.groups()[0].detected(), 1);
+    assert_eq!(report.to_string(), "application/pdf: 3 scanned, 1 detected, worst Detected (code 0x8000)\ntext/plain: 1 scanned, 0 detected, worst Not detected\n");
+}
+
+#[test]
+fn antimalware_test() {
+    let _com = ComApartment::multithreaded().unwrap();
+    let antimalware = Antimalware::new("Test").unwrap();
+    let scan = antimalware.scan_buffer("eicar-test.txt", br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*").unwrap();
+    assert!(scan.result().is_malware());
+    assert!(!antimalware.scan_buffer("clean.txt", b"hello").unwrap().result().is_malware());
+}
+
+#[test]
+fn antimalware_stream_test() {
+    let _com = ComApartment::multithreaded().unwrap();
+    let antimalware = Antimalware::new("Test").unwrap();
+    let mut content = b"Write-Host 'hello'\n".to_vec();
+    content.extend_from_slice(br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*");
+
+    let scan = antimalware.scan_stream("eicar-test.txt", std::io::Cursor::new(content)).unwrap();
+    assert!(scan.result().is_malware());
+}
+
+#[test]
+fn antimalware_reader_test() {
+    use stream::Spilled;
+
+    let _com = ComApartment::multithreaded().unwrap();
+    let antimalware = Antimalware::new("Test").unwrap();
+    let mut content = b"Write-Host 'hello'\n".to_vec();
+    content.extend_from_slice(br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*");
+
+    assert!(antimalware.scan_reader("eicar-test.txt", &content[..], content.len()).unwrap().result().is_malware());
+    assert!(antimalware.scan_reader("eicar-test.txt", &content[..], 8).unwrap().result().is_malware());
+
+    assert!(matches!(stream::spill(&content[..], content.len()).unwrap(), Spilled::Memory(ref data) if *data == content));
+    let path = match stream::spill(&content[..], 8).unwrap() {
+        Spilled::File(mut spilled) => {
+            let mut data = Vec::new();
+            spilled.file.read_to_end(&mut data).unwrap();
+            assert_eq!(data, content);
+            spilled.path.clone()
+        },
+        Spilled::Memory(_) => panic!("content must be spilled"),
+    };
+    assert!(!path.exists());
+}
+
+#[test]
+fn scan_attributes_test() {
+    let _com = ComApartment::multithreaded().unwrap();
+    let antimalware = Antimalware::new("Test").unwrap();
+    let attributes = ScanAttributes{
+        app_name: Some("HostedApp".to_owned()),
+        session: 42,
+        redirect_chain: vec!["http://example.com/a".to_owned(), "http://example.com/eicar.txt".to_owned()],
+    };
+
+    let scan = antimalware.scan_buffer_with("http://example.com/eicar.txt", br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*", &attributes).unwrap();
+    assert!(scan.result().is_malware());
+}
+
+#[test]
+fn provider_test() {
+    struct EicarProvider;
+
+    impl provider::Provider for EicarProvider {
+        fn display_name(&self) -> String {
+            "EICAR Provider".to_owned()
+        }
+
+        fn scan(&self, stream: &provider::ProviderStream) -> provider::ProviderVerdict {
+            assert_eq!(stream.content_name().as_deref(), Some("eicar-test.txt"));
+            assert_eq!(stream.session(), 7);
+            match stream.content() {
+                Some(content) if content.starts_with(b"X5O!") => provider::ProviderVerdict::Detected,
+                _ => provider::ProviderVerdict::NotDetected,
+            }
+        }
+    }
+
+    let harness = provider::ProviderHarness::new(Box::new(EicarProvider));
+    assert_eq!(harness.display_name().unwrap(), "EICAR Provider");
+
+    let attributes = ScanAttributes{ session: 7, ..Default::default() };
+    let data = br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+    assert!(harness.scan_buffer_with("eicar-test.txt", data, &attributes).unwrap().is_malware());
+    assert!(!harness.scan_buffer_with("eicar-test.txt", b"Write-Host 'hello'", &attributes).unwrap().is_malware());
+    // streams don't expose their content in memory.
+    assert!(!harness.scan_stream_with("eicar-test.txt", std::io::Cursor::new(data.to_vec()), &attributes).unwrap().is_malware());
+}
+
+#[test]
+fn provider_session_test() {
+    use std::sync::Mutex;
+
+    struct SessionProvider(Arc<Mutex<Vec<u64>>>);
+
+    impl provider::Provider for SessionProvider {
+        fn display_name(&self) -> String {
+            "Session Provider".to_owned()
+        }
+
+        fn scan(&self, stream: &provider::ProviderStream) -> provider::ProviderVerdict {
+            self.0.lock().unwrap().push(stream.session());
+            provider::ProviderVerdict::NotDetected
+        }
+    }
+
+    let sessions = Arc::new(Mutex::new(Vec::new()));
+    let harness = provider::ProviderHarness::new(Box::new(SessionProvider(sessions.clone())));
+    // sessions are 64-bit, on every target.
+    let attributes = ScanAttributes{ session: 0x1_0000_0007, ..Default::default() };
+    harness.scan_buffer_with("a.txt", b"hello", &attributes).unwrap();
+    harness.scan_stream_with("b.txt", std::io::Cursor::new(b"hello".to_vec()), &attributes).unwrap();
+    harness.scan_buffer("c.txt", b"hello").unwrap();
+    assert_eq!(*sessions.lock().unwrap(), [0x1_0000_0007, 0x1_0000_0007, 0]);
+}
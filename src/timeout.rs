@@ -0,0 +1,47 @@
+use std::sync::mpsc;
+use std::time::Duration;
+
+use super::{AmsiContext, AmsiResult, AmsiSession, ContentName, IntoContentName, ScanError};
+
+impl AmsiContext {
+    /// Scans a buffer without a session, waiting at most `timeout` for the provider, see `scan_buffer`.
+    ///
+    /// The scan runs on a helper thread with a clone of this context. If it doesn't finish in time, this fails with
+    /// `ScanError::TimedOut` and the helper thread is left to finish (or hang) on its own, so a provider that never
+    /// responds costs a thread per timed out scan, but doesn't block the caller. The context remains usable either way.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **data** - payload that should be scanned.
+    /// * **timeout** - how long to wait for the result.
+    pub fn scan_buffer_with_timeout<N: IntoContentName>(&self, content_name: N, data: &[u8], timeout: Duration) -> Result<AmsiResult, ScanError> {
+        let ctx = self.clone();
+        let content_name = ContentName::new(content_name);
+        let data = data.to_vec();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::Builder::new().name("amsi-timed-scan".into()).spawn(move || {
+            // the receiver is gone if the scan timed out.
+            let _ = sender.send(ctx.scan_buffer(&content_name, &data));
+        })?;
+
+        match receiver.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(ScanError::TimedOut(timeout)),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(std::io::Error::other("the scan thread panicked").into()),
+        }
+    }
+}
+
+impl<'a> AmsiSession<'a> {
+    /// Scans a buffer, waiting at most `timeout` for the provider, see `AmsiContext::scan_buffer_with_timeout`.
+    ///
+    /// The scan runs outside of this session, since the session may be closed while a hung scan still uses it.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID.
+    /// * **data** - payload that should be scanned.
+    /// * **timeout** - how long to wait for the result.
+    pub fn scan_buffer_with_timeout<N: IntoContentName>(&self, content_name: N, data: &[u8], timeout: Duration) -> Result<AmsiResult, ScanError> {
+        self.ctx.scan_buffer_with_timeout(content_name, data, timeout)
+    }
+}
@@ -0,0 +1,160 @@
+use std::os::raw::c_void;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use super::com::Guid;
+use super::etw::EVENT_DESCRIPTOR;
+use super::sys::{HRESULT, ULONG};
+use super::AmsiResultKind;
+
+/// `{D20615AD-572F-5EE7-1AE6-CB196C7F41A2}`, the ID of the TraceLogging provider of the `tracelogging` feature.
+///
+/// The provider is named `amsi-rs`, from which the ID is derived, so TraceLogging tools (e.g. `wpr` or `tracelog`)
+/// can enable it by name as well. It writes a `Scan` event for every call into `AmsiScanBuffer` and
+/// `AmsiScanString` through this crate, with the fields `AppName`, `ContentName`, `Size`, `Result`, `Verdict`,
+/// `DurationUs` and `HResult` (`0` unless the scan failed). While no trace session listens to the provider, events
+/// cost no more than a check.
+pub const TRACELOGGING_PROVIDER: Guid = Guid::from_u128(0xd20615ad_572f_5ee7_1ae6_cb196c7f41a2);
+
+const PROVIDER_NAME: &str = "amsi-rs";
+const EVENT_PROVIDER_SET_TRAITS: i32 = 2;
+const WINEVENT_CHANNEL_TRACELOGGING: u8 = 11;
+const WINEVENT_LEVEL_INFO: u8 = 4;
+const EVENT_DATA_DESCRIPTOR_TYPE_EVENT_METADATA: u8 = 1;
+const EVENT_DATA_DESCRIPTOR_TYPE_PROVIDER_METADATA: u8 = 2;
+
+// TraceLogging field types.
+const TLG_IN_UNICODESTRING: u8 = 1;
+const TLG_IN_ANSISTRING: u8 = 2;
+const TLG_IN_UINT64: u8 = 10;
+const TLG_IN_HEXINT32: u8 = 20;
+
+const SCAN_EVENT: EVENT_DESCRIPTOR = EVENT_DESCRIPTOR{
+    id: 0,
+    version: 0,
+    channel: WINEVENT_CHANNEL_TRACELOGGING,
+    level: WINEVENT_LEVEL_INFO,
+    opcode: 0,
+    task: 0,
+    keyword: 0,
+};
+
+#[repr(C)]
+struct EVENT_DATA_DESCRIPTOR {
+    ptr: u64,
+    size: ULONG,
+    /// The type of the descriptor, followed by three reserved bytes.
+    kind: ULONG,
+}
+
+impl EVENT_DATA_DESCRIPTOR {
+    fn new(data: &[u8], kind: u8) -> EVENT_DATA_DESCRIPTOR {
+        EVENT_DATA_DESCRIPTOR{
+            ptr: data.as_ptr() as usize as u64,
+            size: data.len() as ULONG,
+            kind: kind as ULONG,
+        }
+    }
+
+    fn field(data: &[u8]) -> EVENT_DATA_DESCRIPTOR {
+        EVENT_DATA_DESCRIPTOR::new(data, 0)
+    }
+}
+
+#[link(name="advapi32")]
+extern "system" {
+    fn EventRegister(provider_id: *const Guid, enable_callback: *const c_void, callback_context: *mut c_void, handle: *mut u64) -> ULONG;
+    fn EventSetInformation(handle: u64, information_class: i32, information: *const c_void, information_length: ULONG) -> ULONG;
+    fn EventEnabled(handle: u64, descriptor: *const EVENT_DESCRIPTOR) -> u8;
+    fn EventWriteTransfer(handle: u64, descriptor: *const EVENT_DESCRIPTOR, activity_id: *const Guid, related_activity_id: *const Guid, data_count: ULONG, data: *const EVENT_DATA_DESCRIPTOR) -> ULONG;
+}
+
+/// The registered provider, which stays registered until the process exits.
+struct Provider {
+    handle: u64,
+    traits: Vec<u8>,
+    metadata: Vec<u8>,
+}
+
+/// Prefixes `data` with its size (including the prefix) as a little-endian `u16`, as TraceLogging expects.
+fn with_size(data: Vec<u8>) -> Vec<u8> {
+    let size = (data.len() + 2) as u16;
+    size.to_le_bytes().iter().cloned().chain(data).collect()
+}
+
+fn provider() -> Option<&'static Provider> {
+    static PROVIDER: OnceLock<Option<Provider>> = OnceLock::new();
+
+    PROVIDER.get_or_init(|| {
+        let mut handle = 0;
+        if unsafe { EventRegister(&TRACELOGGING_PROVIDER, std::ptr::null(), std::ptr::null_mut(), &mut handle) } != 0 {
+            return None;
+        }
+
+        let traits = with_size(PROVIDER_NAME.bytes().chain(std::iter::once(0)).collect());
+        unsafe {
+            EventSetInformation(handle, EVENT_PROVIDER_SET_TRAITS, traits.as_ptr() as *const c_void, traits.len() as ULONG);
+        }
+
+        // no tags, followed by the event name and the name and type of every field.
+        let mut metadata = vec![0];
+        for &(name, kind) in &[("Scan", None), ("AppName", Some(TLG_IN_UNICODESTRING)), ("ContentName", Some(TLG_IN_UNICODESTRING)), ("Size", Some(TLG_IN_UINT64)), ("Result", Some(TLG_IN_HEXINT32)), ("Verdict", Some(TLG_IN_ANSISTRING)), ("DurationUs", Some(TLG_IN_UINT64)), ("HResult", Some(TLG_IN_HEXINT32))] {
+            metadata.extend(name.bytes().chain(std::iter::once(0)));
+            metadata.extend(kind);
+        }
+
+        Some(Provider{
+            handle,
+            traits,
+            metadata: with_size(metadata),
+        })
+    }).as_ref()
+}
+
+fn wide_bytes(data: &[u16]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, data.len() * 2) }
+}
+
+/// Writes a `Scan` event, if a trace session listens to the provider.
+///
+/// `app_name` and `content_name` are nul-terminated UTF-16.
+pub(crate) fn log_scan(app_name: &[u16], content_name: &[u16], size: usize, hres: HRESULT, result: u32, duration: Duration) {
+    let provider = match provider() {
+        Some(provider) => provider,
+        None => return,
+    };
+    if unsafe { EventEnabled(provider.handle, &SCAN_EVENT) } == 0 {
+        return;
+    }
+
+    let verdict: &[u8] = if hres != 0 {
+        b"Failed\0"
+    } else {
+        match AmsiResultKind::classify(result) {
+            AmsiResultKind::Clean => b"Clean\0",
+            AmsiResultKind::NotDetected => b"NotDetected\0",
+            AmsiResultKind::BlockedByAdmin(_) => b"BlockedByAdmin\0",
+            AmsiResultKind::Detected(_) => b"Detected\0",
+            AmsiResultKind::Unknown(_) => b"Unknown\0",
+        }
+    };
+    let size = (size as u64).to_le_bytes();
+    let result = result.to_le_bytes();
+    let duration = (duration.as_micros() as u64).to_le_bytes();
+    let hres = hres.to_le_bytes();
+
+    let data = [
+        EVENT_DATA_DESCRIPTOR::new(&provider.traits, EVENT_DATA_DESCRIPTOR_TYPE_PROVIDER_METADATA),
+        EVENT_DATA_DESCRIPTOR::new(&provider.metadata, EVENT_DATA_DESCRIPTOR_TYPE_EVENT_METADATA),
+        EVENT_DATA_DESCRIPTOR::field(wide_bytes(app_name)),
+        EVENT_DATA_DESCRIPTOR::field(wide_bytes(content_name)),
+        EVENT_DATA_DESCRIPTOR::field(&size),
+        EVENT_DATA_DESCRIPTOR::field(&result),
+        EVENT_DATA_DESCRIPTOR::field(verdict),
+        EVENT_DATA_DESCRIPTOR::field(&duration),
+        EVENT_DATA_DESCRIPTOR::field(&hres),
+    ];
+    unsafe {
+        EventWriteTransfer(provider.handle, &SCAN_EVENT, std::ptr::null(), std::ptr::null(), data.len() as ULONG, data.as_ptr());
+    }
+}
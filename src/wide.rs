@@ -0,0 +1,75 @@
+use std::borrow::Cow;
+
+use super::{AmsiContext, AmsiResult, AmsiSession, IntoContentName, ScanError, ScanTarget, SkipReason};
+
+/// A UTF-16 content name, which may or may not be nul-terminated.
+struct WideName<'a>(&'a [u16]);
+
+impl<'a> IntoContentName for WideName<'a> {
+    fn to_str_lossy(&self) -> Cow<'_, str> {
+        Cow::Owned(String::from_utf16_lossy(trim_nul(self.0)))
+    }
+
+    fn to_wide(&self) -> Cow<'_, [u16]> {
+        nul_terminated(self.0)
+    }
+}
+
+/// Returns `data` up to (not including) its terminator, if any.
+fn trim_nul(data: &[u16]) -> &[u16] {
+    match data.split_last() {
+        Some((&0, rest)) => rest,
+        _ => data,
+    }
+}
+
+/// Returns `data` with a terminator, copying it only if it isn't terminated already.
+fn nul_terminated(data: &[u16]) -> Cow<'_, [u16]> {
+    if data.last() == Some(&0) {
+        Cow::Borrowed(data)
+    } else {
+        Cow::Owned(data.iter().cloned().chain(std::iter::once(0)).collect())
+    }
+}
+
+impl AmsiContext {
+    fn scan_wide_in(&self, target: ScanTarget, content_name: &[u16], data: &[u16]) -> Result<AmsiResult, ScanError> {
+        let text = trim_nul(data);
+        // `AmsiScanString` stops at the first nul, the provider wouldn't see anything after it.
+        if text.contains(&0) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "wide strings can't contain nul characters").into());
+        }
+        if std::char::decode_utf16(text.iter().cloned()).all(|c| c.map(char::is_whitespace).unwrap_or(false)) {
+            return Ok(SkipReason::Empty.result());
+        }
+
+        let name = WideName(content_name);
+        let display_name = name.to_str_lossy();
+        let bytes = unsafe {
+            std::slice::from_raw_parts(text.as_ptr() as *const u8, text.len() * 2)
+        };
+        if let Some(reason) = self.before_scan(&display_name, bytes)? {
+            return Ok(reason.result());
+        }
+
+        self.scan_wide_unchecked(target, &name.to_wide(), &nul_terminated(text), &display_name)
+    }
+}
+
+impl<'a> AmsiSession<'a> {
+    /// Scans a UTF-16 string, without converting it to UTF-8 and back
+    ///
+    /// This is useful for strings from Win32 APIs or script hosts, which are UTF-16 already and may not be valid
+    /// Unicode. The string is passed to the provider as it is, with a nul terminator appended unless it has one. Strings
+    /// that contain a nul before their end fail with an `InvalidInput` I/O error, since the provider would only see
+    /// the content up to the nul. Like with `scan_string`, empty and whitespace-only strings are reported as clean
+    /// without calling the provider. Filters see the content as UTF-16LE bytes.
+    ///
+    /// ## Parameters
+    /// * **content_name** - File name, URL or unique script ID, optionally nul-terminated.
+    /// * **data** - Content that should be scanned, optionally nul-terminated.
+    pub fn scan_wide(&self, content_name: &[u16], data: &[u16]) -> Result<AmsiResult, ScanError> {
+        self.check_cancelled()?;
+        self.ctx.scan_wide_in(self.target(), content_name, data)
+    }
+}
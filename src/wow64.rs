@@ -0,0 +1,55 @@
+use std::sync::OnceLock;
+
+use super::{ScanError, WinError};
+use super::sys::{BOOL, GetModuleHandleW, GetProcAddress, HANDLE};
+
+const IMAGE_FILE_MACHINE_UNKNOWN: u16 = 0;
+
+/// The errors that a scan fails with when the provider isn't available for the bitness of the process:
+/// `REGDB_E_CLASSNOTREG`, `ERROR_MOD_NOT_FOUND` and `ERROR_BAD_EXE_FORMAT`.
+const MISMATCH_CODES: [u32; 3] = [0x8004_0154, 0x8007_007e, 0x8007_00c1];
+
+#[link(name="kernel32")]
+extern "system" {
+    fn GetCurrentProcess() -> HANDLE;
+}
+
+type IsWow64Process2Fn = unsafe extern "system" fn(process: HANDLE, process_machine: *mut u16, native_machine: *mut u16) -> BOOL;
+
+/// Returns `true` if the current process is a 32-bit process running on a 64-bit Windows.
+///
+/// The check is done with `IsWow64Process2`, which is available starting with Windows 10 1511. On older builds the
+/// process is assumed not to run under WOW64.
+pub fn is_wow64() -> bool {
+    static IS_WOW64: OnceLock<bool> = OnceLock::new();
+
+    *IS_WOW64.get_or_init(|| unsafe {
+        let kernel32: Vec<u16> = "kernel32.dll".encode_utf16().chain(std::iter::once(0)).collect();
+        let module = GetModuleHandleW(kernel32.as_ptr());
+        if module.is_null() {
+            return false;
+        }
+
+        let proc_addr = GetProcAddress(module, b"IsWow64Process2\0".as_ptr());
+        if proc_addr.is_null() {
+            return false;
+        }
+        let is_wow64_process2: IsWow64Process2Fn = std::mem::transmute(proc_addr);
+
+        let mut process_machine = IMAGE_FILE_MACHINE_UNKNOWN;
+        let mut native_machine = IMAGE_FILE_MACHINE_UNKNOWN;
+        if is_wow64_process2(GetCurrentProcess(), &mut process_machine, &mut native_machine) == 0 {
+            return false;
+        }
+        process_machine != IMAGE_FILE_MACHINE_UNKNOWN
+    })
+}
+
+/// Turns the error of a failed scan into a `ScanError`, recognizing failures caused by a WOW64 bitness mismatch.
+pub(crate) fn scan_error(err: WinError) -> ScanError {
+    if MISMATCH_CODES.contains(&err.hresult()) && is_wow64() {
+        ScanError::Wow64Mismatch(err)
+    } else {
+        ScanError::Win(err)
+    }
+}